@@ -1,7 +1,10 @@
 use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
 use crate::miner::Handle as MinerHandle;
 use crate::network::message::Message;
+use crate::network::metrics::NetworkMetrics;
 use crate::network::server::Handle as NetworkServerHandle;
+use crate::network::sync::SyncTracker;
 use serde::Serialize;
 
 use log::info;
@@ -18,6 +21,9 @@ pub struct Server {
     miner: MinerHandle, // handle for sending signal to miner thread
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    sync_tracker: Arc<Mutex<SyncTracker>>,
+    network_metrics: Arc<NetworkMetrics>,
 }
 
 #[derive(Serialize)]
@@ -53,6 +59,9 @@ impl Server {
         miner: &MinerHandle,
         network: &NetworkServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        sync_tracker: &Arc<Mutex<SyncTracker>>,
+        network_metrics: &Arc<NetworkMetrics>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
@@ -60,12 +69,18 @@ impl Server {
             miner: miner.clone(),
             network: network.clone(),
             blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            sync_tracker: Arc::clone(sync_tracker),
+            network_metrics: Arc::clone(network_metrics),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
                 let blockchain = Arc::clone(&server.blockchain);
+                let mempool = Arc::clone(&server.mempool);
+                let sync_tracker = Arc::clone(&server.sync_tracker);
+                let network_metrics = Arc::clone(&server.network_metrics);
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -109,9 +124,24 @@ impl Server {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/sync-status" => {
+                            let current_height = blockchain.lock().unwrap().tip_height();
+                            let status = sync_tracker.lock().unwrap().status(current_height);
+                            respond_json!(req, status);
+                        }
+                        "/network/metrics" => {
+                            respond_json!(req, network_metrics.snapshot());
+                        }
+                        "/mempool/transactions" => {
+                            let snapshot = mempool.lock().unwrap().snapshot();
+                            let v_string: Vec<String> =
+                                snapshot.iter().map(|tx| tx.txid().to_string()).collect();
+                            respond_json!(req, v_string);
+                        }
                         "/blockchain/longest-chain" => {
-                            let blockchain = blockchain.lock().unwrap();
-                            let v = blockchain.all_blocks_in_longest_chain();
+                            // the lock is released before walking the chain
+                            let view = blockchain.lock().unwrap().view();
+                            let v = view.longest_chain();
                             let v_string: Vec<String> =
                                 v.into_iter().map(|h| h.to_string()).collect();
                             respond_json!(req, v_string);
@@ -0,0 +1,253 @@
+use std::ops::Index;
+use std::sync::Arc;
+
+use crate::types::block::Block;
+use crate::types::hash::H256;
+
+/// Number of children of a trie node, one per 4-bit digit of a hash
+const FANOUT: usize = 16;
+
+#[derive(Clone)]
+enum Entry {
+    Leaf(H256, Arc<Block>),
+    Node(Arc<Node>),
+}
+
+#[derive(Clone, Default)]
+struct Node {
+    children: [Option<Entry>; FANOUT],
+}
+
+/// Blocks by hash, in a trie on the digits of their hashes. A copy shares all its nodes, and a
+/// change copies only the nodes on the path to the block, so that views of the blockchain are
+/// not copied in full on the next insert. Blocks are immutable, so copies share them too.
+#[derive(Clone, Default)]
+pub struct BlockStore {
+    root: Arc<Node>,
+    len: usize,
+}
+
+// 4-bit digit of the hash at the given depth in the trie, from the most significant one
+fn digit(hash: &H256, depth: usize) -> usize {
+    let byte = hash.as_ref()[depth / 2];
+    if depth.is_multiple_of(2) {
+        (byte >> 4) as usize
+    } else {
+        (byte & 0x0f) as usize
+    }
+}
+
+impl BlockStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&Arc<Block>> {
+        let mut node = &self.root;
+        let mut depth = 0;
+        loop {
+            match &node.children[digit(hash, depth)] {
+                Some(Entry::Leaf(leaf_hash, block)) if leaf_hash == hash => return Some(block),
+                Some(Entry::Node(child)) => node = child,
+                _ => return None,
+            }
+            depth += 1;
+        }
+    }
+
+    pub fn contains_key(&self, hash: &H256) -> bool {
+        self.get(hash).is_some()
+    }
+
+    /// Insert a block, return the one of the same hash it replaces
+    pub fn insert(&mut self, hash: H256, block: Arc<Block>) -> Option<Arc<Block>> {
+        let replaced = Self::insert_at(&mut self.root, 0, hash, block);
+        if replaced.is_none() {
+            self.len += 1;
+        }
+        replaced
+    }
+
+    pub fn remove(&mut self, hash: &H256) -> Option<Arc<Block>> {
+        // the path is only copied if the block is there
+        if !self.contains_key(hash) {
+            return None;
+        }
+        let removed = Self::remove_at(&mut self.root, 0, hash);
+        self.len -= 1;
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterate over the blocks, in the order of their hashes
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            stack: vec![self.root.children.iter()],
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &H256> {
+        self.iter().map(|(hash, _)| hash)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Arc<Block>> {
+        self.iter().map(|(_, block)| block)
+    }
+
+    fn insert_at(
+        node: &mut Arc<Node>,
+        depth: usize,
+        hash: H256,
+        block: Arc<Block>,
+    ) -> Option<Arc<Block>> {
+        // copies the node only if another store shares it
+        let slot = &mut Arc::make_mut(node).children[digit(&hash, depth)];
+        match slot.take() {
+            None => {
+                *slot = Some(Entry::Leaf(hash, block));
+                None
+            }
+            Some(Entry::Leaf(leaf_hash, leaf_block)) if leaf_hash == hash => {
+                *slot = Some(Entry::Leaf(hash, block));
+                Some(leaf_block)
+            }
+            Some(Entry::Leaf(leaf_hash, leaf_block)) => {
+                // two hashes sharing the digits so far, both go one level down
+                let mut child = Arc::new(Node::default());
+                Self::insert_at(&mut child, depth + 1, leaf_hash, leaf_block);
+                Self::insert_at(&mut child, depth + 1, hash, block);
+                *slot = Some(Entry::Node(child));
+                None
+            }
+            Some(Entry::Node(mut child)) => {
+                let replaced = Self::insert_at(&mut child, depth + 1, hash, block);
+                *slot = Some(Entry::Node(child));
+                replaced
+            }
+        }
+    }
+
+    fn remove_at(node: &mut Arc<Node>, depth: usize, hash: &H256) -> Option<Arc<Block>> {
+        let slot = &mut Arc::make_mut(node).children[digit(hash, depth)];
+        match slot.take() {
+            Some(Entry::Leaf(leaf_hash, leaf_block)) if leaf_hash == *hash => Some(leaf_block),
+            Some(Entry::Node(mut child)) => {
+                let removed = Self::remove_at(&mut child, depth + 1, hash);
+                // a node left with a single block is replaced by it, and an empty one dropped
+                let mut entries = child.children.iter().flatten();
+                let collapsed = match (entries.next(), entries.next()) {
+                    (None, _) => Some(None),
+                    (Some(Entry::Leaf(leaf_hash, leaf_block)), None) => {
+                        Some(Some(Entry::Leaf(*leaf_hash, Arc::clone(leaf_block))))
+                    }
+                    _ => None,
+                };
+                *slot = collapsed.unwrap_or(Some(Entry::Node(child)));
+                removed
+            }
+            entry => {
+                *slot = entry;
+                None
+            }
+        }
+    }
+}
+
+impl Index<&H256> for BlockStore {
+    type Output = Arc<Block>;
+
+    fn index(&self, hash: &H256) -> &Arc<Block> {
+        self.get(hash).expect("block not in store")
+    }
+}
+
+/// Iterator over the blocks of a `BlockStore`, with their hashes
+pub struct Iter<'a> {
+    stack: Vec<std::slice::Iter<'a, Option<Entry>>>, // children left to visit at each depth
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a H256, &'a Arc<Block>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.stack.last_mut()?.next() {
+                None => {
+                    self.stack.pop();
+                }
+                Some(None) => {}
+                Some(Some(Entry::Leaf(hash, block))) => return Some((hash, block)),
+                Some(Some(Entry::Node(child))) => self.stack.push(child.children.iter()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockStore;
+    use crate::types::block::{generate_random_block, Block};
+    use crate::types::hash::{Hashable, H256};
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    // a block keyed by its hash with the first bytes replaced, to control where it is in the trie
+    fn block_with_hash_prefix(prefix: &[u8]) -> (H256, Arc<Block>) {
+        let block = generate_random_block(&H256::default());
+        let mut bytes: [u8; 32] = block.hash().into();
+        bytes[..prefix.len()].copy_from_slice(prefix);
+        (bytes.into(), Arc::new(block))
+    }
+
+    #[test]
+    fn insert_get_and_remove_blocks() {
+        let mut store = BlockStore::new();
+        // the first two share 3 digits, so they are split deeper down the trie
+        let entries = [
+            block_with_hash_prefix(&[0x12, 0x30]),
+            block_with_hash_prefix(&[0x12, 0x3f]),
+            block_with_hash_prefix(&[0x80]),
+        ];
+        for (hash, block) in entries.iter() {
+            assert!(store.insert(*hash, Arc::clone(block)).is_none());
+        }
+        assert_eq!(store.len(), 3);
+        for (hash, block) in entries.iter() {
+            assert_eq!(store[hash].hash(), block.hash());
+        }
+        let keys: HashSet<H256> = store.keys().cloned().collect();
+        assert_eq!(keys, entries.iter().map(|(hash, _)| *hash).collect());
+        assert!(!store.contains_key(&[0x12; 32].into()));
+
+        assert!(store.remove(&entries[0].0).is_some());
+        assert!(store.remove(&entries[0].0).is_none());
+        assert_eq!(store.len(), 2);
+        assert!(!store.contains_key(&entries[0].0));
+        assert!(store.contains_key(&entries[1].0));
+        assert_eq!(store.iter().count(), 2);
+    }
+
+    #[test]
+    fn copy_keeps_blocks_at_copy_time() {
+        let mut store = BlockStore::new();
+        let (old_hash, old_block) = block_with_hash_prefix(&[0x10]);
+        store.insert(old_hash, old_block);
+        let copy = store.clone();
+
+        let (new_hash, new_block) = block_with_hash_prefix(&[0x20]);
+        store.insert(new_hash, new_block);
+        store.remove(&old_hash);
+        assert!(copy.contains_key(&old_hash));
+        assert!(!copy.contains_key(&new_hash));
+        assert_eq!(copy.len(), 1);
+        assert!(!store.contains_key(&old_hash));
+        assert!(store.contains_key(&new_hash));
+    }
+}
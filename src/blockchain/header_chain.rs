@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+use super::{block_work, genesis_block, BlockValidationError, Blockchain, GenesisParams};
+use crate::types::block::Header;
+use crate::types::hash::{Hashable, H256};
+
+/// Headers only, without the contents or states of the blocks, for light clients verifying the
+/// PoW of the longest chain
+pub struct HeaderChain {
+    tip: H256,
+    genesis_hash: H256,
+    hash_to_header: HashMap<H256, Header>,
+    hash_to_len: HashMap<H256, u128>, // length of the chain ending at the header, genesis included
+    hash_to_work: HashMap<H256, u128>, // cumulative work of the chain ending at the header
+}
+
+impl Default for HeaderChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeaderChain {
+    /// Create a new header chain, only containing the genesis header
+    pub fn new() -> Self {
+        Self::with_params(&GenesisParams::default())
+    }
+
+    /// Create a new header chain with the genesis header of the given parameters
+    pub fn with_params(params: &GenesisParams) -> Self {
+        let genesis_header = genesis_block(params).header;
+        let genesis_hash = genesis_header.hash();
+        let mut hash_to_work = HashMap::new();
+        hash_to_work.insert(genesis_hash, block_work(&genesis_header.difficulty));
+        let mut hash_to_len = HashMap::new();
+        hash_to_len.insert(genesis_hash, 1);
+        let mut hash_to_header = HashMap::new();
+        hash_to_header.insert(genesis_hash, genesis_header);
+
+        HeaderChain {
+            tip: genesis_hash,
+            genesis_hash,
+            hash_to_header,
+            hash_to_len,
+            hash_to_work,
+        }
+    }
+
+    /// Insert a header extending a known one, with the same PoW and difficulty checks as full
+    /// blocks. The tip moves to it if its chain has the most work, the smaller hash winning on
+    /// equal work, as in `Blockchain::insert`.
+    pub fn insert_header(&mut self, header: &Header) -> Result<(), BlockValidationError> {
+        let hash = header.hash();
+        if self.hash_to_header.contains_key(&hash) {
+            return Ok(());
+        }
+        let parent_header = self
+            .hash_to_header
+            .get(&header.parent)
+            .ok_or(BlockValidationError::MissingParent)?;
+        Blockchain::validate_header(header, parent_header)?;
+
+        let len = self.hash_to_len[&header.parent] + 1;
+        let work = self.hash_to_work[&header.parent].saturating_add(block_work(&header.difficulty));
+        self.hash_to_header.insert(hash, header.clone());
+        self.hash_to_len.insert(hash, len);
+        self.hash_to_work.insert(hash, work);
+
+        let tip_work = self.hash_to_work[&self.tip];
+        if work > tip_work || (work == tip_work && hash < self.tip) {
+            self.tip = hash;
+        }
+        Ok(())
+    }
+
+    /// Get the last header's hash of the chain with the most work
+    pub fn tip(&self) -> H256 {
+        self.tip
+    }
+
+    /// Get the height of the tip, genesis being at height 0
+    pub fn tip_height(&self) -> u128 {
+        self.hash_to_len[&self.tip] - 1
+    }
+
+    pub fn genesis_hash(&self) -> H256 {
+        self.genesis_hash
+    }
+
+    pub fn get_header(&self, hash: &H256) -> Option<&Header> {
+        self.hash_to_header.get(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HeaderChain;
+    use crate::blockchain::{BlockValidationError, Blockchain};
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::{Hashable, H256};
+
+    #[test]
+    fn tip_follows_most_work_headers() {
+        let mut header_chain = HeaderChain::new();
+        let genesis_hash = header_chain.genesis_hash();
+        // the same genesis as full nodes
+        assert_eq!(genesis_hash, Blockchain::new().genesis_hash());
+
+        let a1 = generate_random_block(&genesis_hash).header;
+        let a2 = generate_random_block(&a1.hash()).header;
+        assert_eq!(header_chain.insert_header(&a1), Ok(()));
+        assert_eq!(header_chain.insert_header(&a2), Ok(()));
+        assert_eq!(header_chain.tip(), a2.hash());
+
+        // a longer fork of the same difficulty has more work
+        let b1 = generate_random_block(&genesis_hash).header;
+        let b2 = generate_random_block(&b1.hash()).header;
+        let b3 = generate_random_block(&b2.hash()).header;
+        for header in [&b1, &b2, &b3] {
+            assert_eq!(header_chain.insert_header(header), Ok(()));
+        }
+        assert_eq!(header_chain.tip(), b3.hash());
+        assert_eq!(header_chain.tip_height(), 3);
+
+        let mut harder = generate_random_block(&b3.hash()).header;
+        harder.difficulty = H256::from([0u8; 32]);
+        assert_eq!(
+            header_chain.insert_header(&harder),
+            Err(BlockValidationError::DifficultyMismatch)
+        );
+        let unknown_parent = generate_random_block(&[7u8; 32].into()).header;
+        assert_eq!(
+            header_chain.insert_header(&unknown_parent),
+            Err(BlockValidationError::MissingParent)
+        );
+        assert_eq!(header_chain.tip(), b3.hash());
+    }
+}
@@ -1,43 +1,169 @@
-use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+pub mod block_store;
+pub mod header_chain;
 
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use self::block_store::BlockStore;
+use crate::types::address::Address;
 use crate::types::block::{Block, Content, Header};
+use crate::types::bloom::BloomFilter;
+use crate::types::clock::{Clock, SystemClock};
 use crate::types::hash::{Hashable, H256};
 use crate::types::merkle::MerkleTree;
-use crate::types::transaction::SignedTransaction;
+use crate::types::state::State;
+use crate::types::transaction::{SignedTransaction, Transaction, TxValidationError};
+
+/// Mining difficulty of the genesis block, which is kept by all following blocks
+pub const GENESIS_DIFFICULTY: [u8; 32] = [255u8; 32];
+/// Number of latest blocks whose median timestamp a new block has to exceed
+const MEDIAN_TIME_SPAN: usize = 11;
+/// How far in the future a block's timestamp may be, in millis
+pub const MAX_FUTURE_BLOCK_TIME_MS: u128 = 2 * 60 * 60 * 1000;
+/// Max serialized size of a block
+pub const MAX_BLOCK_BYTES: usize = 1_000_000;
+/// Max number of transactions in a block, including the coinbase and those in batches, which bounds
+/// the cost of its Merkle tree
+pub const MAX_TXS_PER_BLOCK: usize = 4096;
+/// Max number of blocks of the longest chain that a fork may replace, older blocks are final
+pub const MAX_REORG_DEPTH: u128 = 100;
+/// Value paid to the miner of a block by its coinbase transaction
+pub const BLOCK_REWARD: u64 = 50;
+
+/// Parameters of a blockchain fixed at its creation
+#[derive(Debug, Clone)]
+pub struct GenesisParams {
+    pub difficulty: H256, // target of every block, high enough for miners to meet
+    pub timestamp: u128,  // unix time in millis, as all block timestamps
+    pub max_reorg_depth: u128,
+    pub premine: Vec<(Address, u64)>, // balances credited in the genesis state
+}
+
+impl Default for GenesisParams {
+    fn default() -> Self {
+        GenesisParams {
+            difficulty: H256::from(GENESIS_DIFFICULTY),
+            // fixed so that every node derives the same genesis block, and any mined block is later
+            timestamp: 0,
+            max_reorg_depth: MAX_REORG_DEPTH,
+            premine: vec![],
+        }
+    }
+}
 
 pub struct Blockchain {
     pub tip: H256,
-    pub max_len: u128,
-    pub hash_to_block: HashMap<H256, Block>, // in-memory storage
+    pub max_len: u128, // length of the chain ending at the tip, which has the most work
+    pub hash_to_block: BlockStore, // in-memory storage, sharing its unchanged nodes with views
     pub hash_to_len: HashMap<H256, u128>,
+    pub hash_to_work: HashMap<H256, u128>, // cumulative work of the chain ending at each block
+    pub hash_to_state: HashMap<H256, State>, // state after applying each block
+    genesis_hash: H256,
+    params: GenesisParams,
+    address_to_txids: HashMap<Address, Vec<H256>>, // over the longest chain, from old to new
+    txid_to_block: HashMap<H256, H256>,            // over the longest chain
+    clock: Arc<dyn Clock>,                         // for checking timestamps
+    last_tip_change_ms: u128,
+}
+
+/// Overview of a blockchain, e.g. for dashboards
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainSummary {
+    pub tip: H256,
+    pub height: u128,
+    pub total_blocks: usize,
+    pub fork_count: usize, // number of tips other than the longest chain's
+    pub total_txs: usize,  // over the longest chain
+}
+
+/// Read-only snapshot of a blockchain, e.g. to serve an API response without holding its lock
+#[derive(Clone)]
+pub struct ChainView {
+    pub tip: H256,
+    pub height: u128,
+    blocks: BlockStore, // sharing its unchanged nodes with the blockchain
+}
+
+impl ChainView {
+    pub fn get_block(&self, hash: &H256) -> Option<&Block> {
+        self.blocks.get(hash).map(|block| block.as_ref())
+    }
+
+    /// Get all blocks' hashes of the longest chain at the snapshot, ordered from genesis to the tip
+    pub fn longest_chain(&self) -> Vec<H256> {
+        let mut hash_vec = vec![];
+        let mut cur_hash = self.tip;
+        while let Some(block) = self.blocks.get(&cur_hash) {
+            hash_vec.push(cur_hash);
+            cur_hash = block.get_parent();
+        }
+        hash_vec.reverse();
+        hash_vec
+    }
+}
+
+/// Blocks leaving and joining the longest chain when the tip switches to another fork
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReorgInfo {
+    pub disconnected: Vec<H256>, // from the old tip down to the common ancestor, excluded
+    pub connected: Vec<H256>,    // from after the common ancestor up to the new tip
+}
+
+/// Reasons for rejecting a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockValidationError {
+    BadPow,
+    DifficultyMismatch,
+    BadMerkleRoot,
+    MissingParent,
+    StaleTimestamp, // not later than the median-time-past
+    FutureTimestamp,
+    Oversized,
+    TooManyTransactions,  // more than `MAX_TXS_PER_BLOCK`
+    DuplicateTransaction, // the same txid more than once
+    BadCoinbase,          // not the first transaction, or not paying exactly the block reward
+    InvalidTransaction(TxValidationError),
+}
+
+/// Reasons for rejecting a snapshot from `Blockchain::import_snapshot`
+#[derive(Debug)]
+pub enum SnapshotError {
+    Malformed(bincode::Error),
+    GenesisMismatch(H256), // hash of the first block in the snapshot
+    InvalidBlock(H256, BlockValidationError),
 }
 
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
-        let genesis_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-        let genesis_data: Vec<SignedTransaction> = Vec::new();
-        let genesis_header = Header {
-            parent: [0u8; 32].into(),
-            nonce: 0u32,
-            difficulty: H256::from([255u8; 32]),
-            timestamp: genesis_time,
-            merkle_root: MerkleTree::new(&genesis_data).root(),
-        };
-        let genesis_block = Block {
-            header: genesis_header,
-            content: Content { data: genesis_data },
-        };
+        Self::with_params(GenesisParams::default())
+    }
+
+    /// Create a new blockchain with the given parameters, only containing the genesis block
+    pub fn with_params(params: GenesisParams) -> Self {
+        Self::with_params_and_clock(params, Arc::new(SystemClock))
+    }
+
+    /// Create a new blockchain with the given parameters and time source, only containing the
+    /// genesis block
+    pub fn with_params_and_clock(params: GenesisParams, clock: Arc<dyn Clock>) -> Self {
+        let genesis_block = genesis_block(&params);
 
         let tip = genesis_block.hash();
         let max_len = 1u128;
-        let mut hash_to_block: HashMap<H256, Block> = HashMap::new();
+        let mut hash_to_block = BlockStore::new();
         let mut hash_to_len: HashMap<H256, u128> = HashMap::new();
-        hash_to_block.insert(tip, genesis_block);
+        let mut hash_to_work: HashMap<H256, u128> = HashMap::new();
+        hash_to_work.insert(tip, block_work(&genesis_block.header.difficulty));
+        let mut genesis_state = State::new();
+        for (addr, balance) in &params.premine {
+            genesis_state.credit(addr, *balance);
+        }
+        let mut hash_to_state: HashMap<H256, State> = HashMap::new();
+        hash_to_state.insert(tip, genesis_state);
+        hash_to_block.insert(tip, Arc::new(genesis_block));
         hash_to_len.insert(tip, max_len);
 
         Blockchain {
@@ -45,26 +171,408 @@ impl Blockchain {
             max_len,
             hash_to_block,
             hash_to_len,
+            hash_to_work,
+            hash_to_state,
+            genesis_hash: tip,
+            params,
+            address_to_txids: HashMap::new(),
+            txid_to_block: HashMap::new(),
+            last_tip_change_ms: clock.now_ms(),
+            clock,
         }
     }
 
-    /// Insert a block into blockchain
+    /// Insert a block into blockchain, return the blocks disconnected and connected if the tip
+    /// switches to another fork
     // Assumption: the block is already validated
-    pub fn insert(&mut self, block: &Block) {
+    pub fn insert(&mut self, block: &Block) -> Option<ReorgInfo> {
+        // everything is computed before the first change, so that an insert failing half-way
+        // leaves the blockchain as it was
         let block_hash = block.hash();
         let parent_hash = block.get_parent();
 
         let mut parent_len = 1u128;
+        let mut parent_work = 0u128;
         if self.hash_to_block.contains_key(&parent_hash) {
             parent_len = *self.hash_to_len.get(&parent_hash).unwrap();
+            parent_work = *self.hash_to_work.get(&parent_hash).unwrap();
+        }
+        let work = parent_work.saturating_add(block_work(&block.header.difficulty));
+        let mut state = self
+            .hash_to_state
+            .get(&parent_hash)
+            .cloned()
+            .unwrap_or_default();
+        state.set_height(parent_len);
+        for (i, signed_tx) in block.content().data.iter().enumerate() {
+            // only a first coinbase of the exact reward pays, any other has no effect
+            if signed_tx.is_coinbase() {
+                if i == 0 && signed_tx.transaction().value() == BLOCK_REWARD as i64 {
+                    state.credit_coinbase(&signed_tx.transaction().receiver(), BLOCK_REWARD);
+                }
+                continue;
+            }
+            // blocks are not validated against the state yet, a failing transaction has no effect
+            let _ = state.apply_transaction(signed_tx.transaction());
+        }
+        for batch in block.content().batches.iter() {
+            let _ = state.apply_batch(batch);
         }
 
-        self.hash_to_block.insert(block_hash, block.clone());
+        // the chain with the most work wins, rather than the longest one, so that many easy blocks
+        // cannot outweigh fewer hard ones. On equal work, the smaller hash wins so that all nodes
+        // agree regardless of arrival order.
+        let tip_work = self.hash_to_work[&self.tip];
+        let mut reorg = None;
+        if work > tip_work || (work == tip_work && block_hash < self.tip) {
+            // the block shares its ancestors with the tip through its parent
+            if parent_hash != self.tip
+                && self.reorg_depth(&parent_hash) > self.params.max_reorg_depth
+            {
+                warn!(
+                    "Refusing to reorg to block {} deeper than {} blocks",
+                    block_hash, self.params.max_reorg_depth
+                );
+            } else {
+                reorg = Some(self.tip_switch(&parent_hash, block_hash));
+            }
+        }
+
+        self.hash_to_block
+            .insert(block_hash, Arc::new(block.clone()));
         self.hash_to_len.insert(block_hash, parent_len + 1);
-        if parent_len + 1 > self.max_len {
-            self.tip = block_hash;
-            self.max_len = parent_len + 1;
+        self.hash_to_work.insert(block_hash, work);
+        self.hash_to_state.insert(block_hash, state);
+        let reorg = reorg?;
+        self.switch_tip(&reorg);
+        self.max_len = parent_len + 1;
+        // scanning every block is costly, so stale forks are pruned once every `max_reorg_depth`
+        // blocks of the longest chain, and kept at most that long after becoming stale
+        if self
+            .max_len
+            .is_multiple_of(self.params.max_reorg_depth.max(1))
+        {
+            self.prune_stale_forks();
+        }
+        if reorg.disconnected.is_empty() {
+            None
+        } else {
+            Some(reorg)
+        }
+    }
+
+    // Get the blocks leaving and joining the longest chain if the tip moved to a new block of the
+    // given parent, without changing anything
+    fn tip_switch(&self, parent: &H256, new_tip: H256) -> ReorgInfo {
+        let ancestor = self.common_ancestor(parent, &self.tip);
+        let mut disconnected = vec![];
+        let mut cur_hash = self.tip;
+        while Some(cur_hash) != ancestor {
+            match self.hash_to_block.get(&cur_hash) {
+                Some(block) => {
+                    disconnected.push(cur_hash);
+                    cur_hash = block.get_parent();
+                }
+                None => break,
+            }
+        }
+
+        let mut connected = vec![new_tip];
+        let mut cur_hash = *parent;
+        while Some(cur_hash) != ancestor {
+            match self.hash_to_block.get(&cur_hash) {
+                Some(block) => {
+                    connected.push(cur_hash);
+                    cur_hash = block.get_parent();
+                }
+                None => break,
+            }
+        }
+        connected.reverse();
+        ReorgInfo {
+            disconnected,
+            connected,
+        }
+    }
+
+    // Move the tip to the last connected block, and update the address index with the blocks
+    // leaving and joining the longest chain, which are all in blockchain
+    fn switch_tip(&mut self, reorg: &ReorgInfo) {
+        for hash in reorg.disconnected.iter() {
+            self.unindex_block(hash);
+        }
+        for hash in reorg.connected.iter() {
+            self.index_block(hash);
+        }
+        self.tip = *reorg.connected.last().unwrap();
+        self.last_tip_change_ms = self.clock.now_ms();
+    }
+
+    /// Get the unix time in millis when the tip last changed, or when the blockchain was created
+    pub fn last_tip_change_ms(&self) -> u128 {
+        self.last_tip_change_ms
+    }
+
+    /// Get the blockchain's time source
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock)
+    }
+
+    fn index_block(&mut self, block_hash: &H256) {
+        let block = Arc::clone(&self.hash_to_block[block_hash]);
+        for tx in block.content().transactions() {
+            let txid = tx.hash();
+            // a transaction included twice is found in its earliest block
+            self.txid_to_block.entry(txid).or_insert(*block_hash);
+            self.address_to_txids
+                .entry(tx.sender())
+                .or_default()
+                .push(txid);
+            if tx.receiver() != tx.sender() {
+                self.address_to_txids
+                    .entry(tx.receiver())
+                    .or_default()
+                    .push(txid);
+            }
+        }
+    }
+
+    fn unindex_block(&mut self, block_hash: &H256) {
+        let block = Arc::clone(&self.hash_to_block[block_hash]);
+        for tx in block.content().transactions() {
+            let txid = tx.hash();
+            if self.txid_to_block.get(&txid) == Some(block_hash) {
+                self.txid_to_block.remove(&txid);
+            }
+            for addr in [tx.sender(), tx.receiver()].iter() {
+                if let Some(txids) = self.address_to_txids.get_mut(addr) {
+                    txids.retain(|hash| *hash != txid);
+                    if txids.is_empty() {
+                        self.address_to_txids.remove(addr);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Get the txids on the longest chain sent or received by an address, ordered from genesis to
+    /// the tip, from an index kept up to date on insertion
+    pub fn txids_for_address(&self, addr: &Address) -> Vec<H256> {
+        self.address_to_txids.get(addr).cloned().unwrap_or_default()
+    }
+
+    /// Get the hash of the longest chain's block including a transaction, from an index kept up to
+    /// date on insertion
+    pub fn block_containing_tx(&self, txid: &H256) -> Option<H256> {
+        self.txid_to_block.get(txid).cloned()
+    }
+
+    /// Get the number of blocks confirming a transaction, its block included, None if the
+    /// transaction is not in the longest chain
+    pub fn tx_confirmations(&self, txid: &H256) -> Option<u128> {
+        let block = self.block_containing_tx(txid)?;
+        self.confirmations(&block).map(|depth| depth + 1)
+    }
+
+    /// Get the latest block which both blocks descend from (or are), None if either is unknown
+    pub fn common_ancestor(&self, a: &H256, b: &H256) -> Option<H256> {
+        let (mut a, mut b) = (*a, *b);
+        let mut a_len = *self.hash_to_len.get(&a)?;
+        let mut b_len = *self.hash_to_len.get(&b)?;
+        while a != b {
+            // walk up the higher one, or both at the same height
+            if a_len >= b_len {
+                a = self.hash_to_block.get(&a)?.get_parent();
+                a_len -= 1;
+            }
+            if b_len > a_len {
+                b = self.hash_to_block.get(&b)?.get_parent();
+                b_len -= 1;
+            }
+        }
+        Some(a)
+    }
+
+    // Number of blocks of the longest chain that switching the tip to `block` would replace
+    fn reorg_depth(&self, block: &H256) -> u128 {
+        match self.common_ancestor(block, &self.tip) {
+            Some(ancestor) => self.max_len - self.hash_to_len[&ancestor],
+            None => self.max_len,
+        }
+    }
+
+    /// Check a block against its parent in blockchain: the parent exists, the PoW is valid, the
+    /// difficulty is the parent's one, the timestamp is later than the median-time-past and not too
+    /// far in the future, the size and the number of transactions are bounded, no transaction is
+    /// repeated, and the Merkle root matches the content
+    pub fn validate_block(&self, block: &Block) -> Result<(), BlockValidationError> {
+        let parent_hash = block.get_parent();
+        let parent_header = self
+            .get_header(&parent_hash)
+            .ok_or(BlockValidationError::MissingParent)?;
+        Self::validate_header(&block.header, &parent_header)?;
+
+        if block.header.timestamp <= self.median_time_past(&parent_hash) {
+            return Err(BlockValidationError::StaleTimestamp);
+        }
+        if block.header.timestamp > self.clock.now_ms() + MAX_FUTURE_BLOCK_TIME_MS {
+            return Err(BlockValidationError::FutureTimestamp);
+        }
+        if bincode::serialized_size(block).unwrap() as usize > MAX_BLOCK_BYTES {
+            return Err(BlockValidationError::Oversized);
+        }
+        if block.content().tx_count() > MAX_TXS_PER_BLOCK {
+            return Err(BlockValidationError::TooManyTransactions);
+        }
+        let mut txids = HashSet::new();
+        if !block
+            .content()
+            .transactions()
+            .all(|tx| txids.insert(tx.hash()))
+        {
+            return Err(BlockValidationError::DuplicateTransaction);
+        }
+        if block.merkle_root() != block.header.merkle_root {
+            return Err(BlockValidationError::BadMerkleRoot);
+        }
+        Ok(())
+    }
+
+    /// Validate a block, including its transactions against the parent's state, and insert it if
+    /// valid. Same as `insert`, return the blocks disconnected and connected on a reorg.
+    pub fn submit_block(
+        &mut self,
+        block: Block,
+    ) -> Result<Option<ReorgInfo>, BlockValidationError> {
+        self.validate_block(&block)?;
+        self.validate_transactions(&block)?;
+        Ok(self.insert(&block))
+    }
+
+    // Check that a coinbase is only the first transaction and pays the block reward, and that the
+    // other transactions, then the batches, are signed by their senders and apply in order on the
+    // parent's state
+    fn validate_transactions(&self, block: &Block) -> Result<(), BlockValidationError> {
+        let mut state = self
+            .child_state(&block.get_parent())
+            .ok_or(BlockValidationError::MissingParent)?;
+        for (i, signed_tx) in block.content().data.iter().enumerate() {
+            if signed_tx.is_coinbase() {
+                if i != 0 || signed_tx.transaction().value() != BLOCK_REWARD as i64 {
+                    return Err(BlockValidationError::BadCoinbase);
+                }
+                continue;
+            }
+            signed_tx
+                .validate()
+                .and_then(|_| state.apply_transaction(signed_tx.transaction()))
+                .map_err(BlockValidationError::InvalidTransaction)?;
+        }
+        for batch in block.content().batches.iter() {
+            batch
+                .validate()
+                .and_then(|_| state.apply_batch(batch))
+                .map_err(BlockValidationError::InvalidTransaction)?;
+        }
+        Ok(())
+    }
+
+    /// Check if a transaction is signed by its sender and applies on the state at a block, as if
+    /// included in a child of it, without changing the blockchain
+    pub fn would_accept_tx(
+        &self,
+        signed_tx: &SignedTransaction,
+        at: &H256,
+    ) -> Result<(), TxValidationError> {
+        self.would_accept_tx_after(signed_tx, at, &[])
+    }
+
+    /// Same as `would_accept_tx`, after the given transactions are applied on the state at the
+    /// block, e.g. the pending ones of the same sender
+    pub fn would_accept_tx_after(
+        &self,
+        signed_tx: &SignedTransaction,
+        at: &H256,
+        pending: &[&SignedTransaction],
+    ) -> Result<(), TxValidationError> {
+        let mut state = self
+            .child_state(at)
+            .ok_or(TxValidationError::UnknownBlock)?;
+        for pending_tx in pending {
+            state.apply_transaction(pending_tx.transaction())?;
+        }
+        signed_tx.validate()?;
+        state.apply_transaction(signed_tx.transaction())
+    }
+
+    // Copy of the state at a block, to apply the transactions of a child block onto
+    fn child_state(&self, parent: &H256) -> Option<State> {
+        let mut state = self.hash_to_state.get(parent).cloned()?;
+        state.set_height(self.hash_to_len[parent]);
+        Some(state)
+    }
+
+    /// Check a chain of headers ordered from old to new: the first one extends a block in
+    /// blockchain, each following one extends the previous one, and each has a valid PoW with its
+    /// parent's difficulty
+    pub fn validate_header_chain(&self, headers: &[Header]) -> Result<(), BlockValidationError> {
+        let first_header = match headers.first() {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+        let mut parent_header = match self.hash_to_block.get(&first_header.parent) {
+            Some(parent) => &parent.header,
+            None => return Err(BlockValidationError::MissingParent),
+        };
+
+        for header in headers {
+            if header.parent != parent_header.hash() {
+                return Err(BlockValidationError::MissingParent);
+            }
+            Self::validate_header(header, parent_header)?;
+            parent_header = header;
+        }
+        Ok(())
+    }
+
+    // PoW and difficulty checks, which only need the headers
+    fn validate_header(
+        header: &Header,
+        parent_header: &Header,
+    ) -> Result<(), BlockValidationError> {
+        if header.difficulty != parent_header.difficulty {
+            return Err(BlockValidationError::DifficultyMismatch);
+        }
+        if header.hash() > header.difficulty {
+            return Err(BlockValidationError::BadPow);
+        }
+        Ok(())
+    }
+
+    /// Raise a timestamp in millis, e.g. the current time, to the earliest one valid for a child of
+    /// `parent`: right after the median-time-past
+    pub fn normalize_timestamp(&self, parent: &H256, timestamp: u128) -> u128 {
+        timestamp.max(self.median_time_past(parent) + 1)
+    }
+
+    /// Get the median timestamp of the last `MEDIAN_TIME_SPAN` blocks ending at `parent`, or of all
+    /// of them if the chain is shorter. For an even count, the upper median is taken.
+    pub fn median_time_past(&self, parent: &H256) -> u128 {
+        let mut timestamps: Vec<u128> = vec![];
+        let mut cur_hash = *parent;
+
+        while let Some(block) = self.hash_to_block.get(&cur_hash) {
+            timestamps.push(block.header.timestamp);
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            cur_hash = block.get_parent();
+        }
+        if timestamps.is_empty() {
+            return 0;
         }
+        timestamps.sort_unstable();
+        timestamps[timestamps.len() / 2]
     }
 
     /// Get the last block's hash of the longest chain
@@ -72,19 +580,463 @@ impl Blockchain {
         self.tip
     }
 
+    /// Get the header of a block, which is cheaper to clone than the block, None if the block is
+    /// unknown
+    pub fn get_header(&self, hash: &H256) -> Option<Header> {
+        self.hash_to_block
+            .get(hash)
+            .map(|block| block.header.clone())
+    }
+
+    /// Get the state after applying a block and its ancestors, None if the block is unknown
+    pub fn state(&self, hash: &H256) -> Option<&State> {
+        self.hash_to_state.get(hash)
+    }
+
+    /// Get the state at the tip of the longest chain
+    pub fn tip_state(&self) -> &State {
+        &self.hash_to_state[&self.tip]
+    }
+
+    /// Get the number of accounts with a nonzero balance at the tip, which grows with the state
+    pub fn account_count(&self) -> usize {
+        self.tip_state().funded_account_count()
+    }
+
+    /// Get the money supply at the tip: the premine plus the block rewards, minus the fees which
+    /// are burned
+    pub fn total_supply(&self) -> u128 {
+        self.tip_state().total_balance()
+    }
+
+    /// Get the hash of the genesis block
+    pub fn genesis_hash(&self) -> H256 {
+        self.genesis_hash
+    }
+
+    /// Check whether a hash is the genesis block's
+    pub fn is_genesis(&self, hash: &H256) -> bool {
+        *hash == self.genesis_hash
+    }
+
+    /// Get the cumulative work of the chain ending at the tip
+    pub fn tip_work(&self) -> u128 {
+        self.hash_to_work[&self.tip]
+    }
+
+    /// Get the height of the longest chain's tip, genesis being at height 0
+    pub fn tip_height(&self) -> u128 {
+        self.max_len - 1
+    }
+
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-        let mut res: Vec<H256> = vec![];
-        let mut cur_hash = self.tip;
+        let mut res: Vec<H256> = self.iter_longest_chain().collect();
+        res.reverse();
+        res
+    }
+
+    /// Get the height and difficulty target of each block of the longest chain, ordered from
+    /// genesis to the tip
+    pub fn difficulty_history(&self) -> Vec<(u128, H256)> {
+        self.all_blocks_in_longest_chain()
+            .into_iter()
+            .map(|hash| {
+                let difficulty = self.hash_to_block[&hash].header.difficulty;
+                (self.hash_to_len[&hash] - 1, difficulty)
+            })
+            .collect()
+    }
 
-        while cur_hash != H256::from([0u8; 32]) {
-            let parent_hash = self.hash_to_block[&cur_hash].get_parent();
+    /// Get all blocks' hashes of the chain ending at any block, e.g. of a losing fork, ordered from
+    /// genesis to it. Return None if the block is unknown or does not descend from genesis.
+    pub fn chain_from(&self, tip: H256) -> Option<Vec<H256>> {
+        let mut res = vec![tip];
+        let mut cur_hash = tip;
+        while !self.is_genesis(&cur_hash) {
+            cur_hash = self.hash_to_block.get(&cur_hash)?.get_parent();
             res.push(cur_hash);
-            cur_hash = parent_hash;
         }
         res.reverse();
-        res
+        Some(res)
+    }
+
+    /// Iterate over blocks' hashes of the longest chain lazily, ordered from the tip to genesis
+    /// (the reverse of `all_blocks_in_longest_chain`), since blocks only link to their parents
+    pub fn iter_longest_chain(&self) -> impl Iterator<Item = H256> + '_ {
+        std::iter::successors(Some(self.tip), move |hash| {
+            if self.is_genesis(hash) {
+                None
+            } else {
+                Some(self.hash_to_block[hash].get_parent())
+            }
+        })
+    }
+
+    /// Take a snapshot of the tip and the blocks, which is cheap as it shares the block storage
+    pub fn view(&self) -> ChainView {
+        ChainView {
+            tip: self.tip,
+            height: self.tip_height(),
+            blocks: self.hash_to_block.clone(),
+        }
+    }
+
+    /// Get the last `n` blocks of the longest chain, newest first, e.g. for a page of an explorer
+    pub fn recent_blocks(&self, n: usize) -> Vec<Block> {
+        self.iter_longest_chain()
+            .take(n)
+            .map(|hash| Block::clone(&self.hash_to_block[&hash]))
+            .collect()
+    }
+
+    /// Get a block locator of the longest chain: the hashes of the tip, tip-1, tip-2, tip-4, tip-8,
+    /// ..., and always genesis, so that a peer can find our fork point in O(log n) hashes
+    pub fn block_locator(&self) -> Vec<H256> {
+        let mut locator: Vec<H256> = self
+            .iter_longest_chain()
+            .enumerate()
+            .filter(|(depth, _)| *depth == 0 || depth.is_power_of_two())
+            .map(|(_, hash)| hash)
+            .collect();
+
+        if locator.last() != Some(&self.genesis_hash) {
+            locator.push(self.genesis_hash);
+        }
+        locator
+    }
+
+    /// Get the longest chain's blocks' hashes after the latest block of `locator` in it (or after
+    /// genesis if none is), ordered from old to new, ending at `stop` or after `max_count` blocks
+    pub fn branch_after_locator(
+        &self,
+        locator: &[H256],
+        stop: &H256,
+        max_count: usize,
+    ) -> Vec<H256> {
+        let longest_chain = self.all_blocks_in_longest_chain();
+        let hash_to_height: HashMap<H256, usize> = longest_chain
+            .iter()
+            .enumerate()
+            .map(|(height, hash)| (*hash, height))
+            .collect();
+        let fork_height = locator
+            .iter()
+            .find_map(|hash| hash_to_height.get(hash))
+            .cloned()
+            .unwrap_or(0);
+
+        let mut branch = vec![];
+        for hash in longest_chain
+            .into_iter()
+            .skip(fork_height + 1)
+            .take(max_count)
+        {
+            branch.push(hash);
+            if hash == *stop {
+                break;
+            }
+        }
+        branch
+    }
+
+    /// Get all blocks' hashes that are not in the longest chain (stale blocks), in no particular order
+    pub fn orphan_blocks(&self) -> Vec<H256> {
+        let longest_chain: HashSet<H256> = self.all_blocks_in_longest_chain().into_iter().collect();
+
+        self.hash_to_block
+            .keys()
+            .filter(|hash| !longest_chain.contains(hash))
+            .cloned()
+            .collect()
+    }
+
+    /// Get the number of blocks built on top of a block in the longest chain (0 for the tip), None if
+    /// the block is unknown or not in the longest chain
+    pub fn confirmations(&self, block: &H256) -> Option<u128> {
+        let block_len = *self.hash_to_len.get(block)?;
+        let tip_len = self.hash_to_len[&self.tip];
+        if block_len > tip_len {
+            return None;
+        }
+
+        let depth = tip_len - block_len;
+        if self.iter_longest_chain().nth(depth as usize) == Some(*block) {
+            Some(depth)
+        } else {
+            None
+        }
+    }
+
+    /// Serialize the blocks of the longest chain, ordered from genesis to the tip
+    pub fn export_snapshot(&self) -> Vec<u8> {
+        let block_vec: Vec<&Block> = self
+            .all_blocks_in_longest_chain()
+            .iter()
+            .map(|hash| self.hash_to_block[hash].as_ref())
+            .collect();
+        bincode::serialize(&block_vec).unwrap()
+    }
+
+    /// Rebuild a blockchain of the given parameters from the output of `export_snapshot`,
+    /// validating every block with its transactions as if it were received from a peer
+    pub fn import_snapshot(
+        bytes: &[u8],
+        params: &GenesisParams,
+    ) -> Result<Blockchain, SnapshotError> {
+        let block_vec: Vec<Block> =
+            bincode::deserialize(bytes).map_err(SnapshotError::Malformed)?;
+        let mut blockchain = Blockchain::with_params(params.clone());
+
+        let mut block_iter = block_vec.into_iter();
+        if let Some(genesis_block) = block_iter.next() {
+            if genesis_block.hash() != blockchain.tip() {
+                return Err(SnapshotError::GenesisMismatch(genesis_block.hash()));
+            }
+        }
+        for block in block_iter {
+            let hash = block.hash();
+            if let Err(e) = blockchain.submit_block(block) {
+                return Err(SnapshotError::InvalidBlock(hash, e));
+            }
+        }
+        Ok(blockchain)
+    }
+
+    /// Get the hashes of all blocks without children, except the longest chain's tip, in no
+    /// particular order
+    pub fn fork_tips(&self) -> Vec<H256> {
+        let parents: HashSet<H256> = self
+            .hash_to_block
+            .values()
+            .map(|block| block.get_parent())
+            .collect();
+
+        self.hash_to_block
+            .keys()
+            .filter(|hash| **hash != self.tip && !parents.contains(hash))
+            .cloned()
+            .collect()
+    }
+
+    /// Get the hashes of the other blocks with the same parent as a block, so at the same height,
+    /// in no particular order. Empty for genesis or an unknown block.
+    pub fn siblings(&self, hash: &H256) -> Vec<H256> {
+        let parent = match self.hash_to_block.get(hash) {
+            Some(block) if !self.is_genesis(hash) => block.get_parent(),
+            _ => return vec![],
+        };
+        self.hash_to_block
+            .iter()
+            .filter(|(other, block)| *other != hash && block.get_parent() == parent)
+            .map(|(other, _)| *other)
+            .collect()
+    }
+
+    /// Check if a chain through the block could only become the longest by a reorg deeper than
+    /// `max_reorg_depth`
+    pub fn is_stale_fork(&self, hash: &H256) -> bool {
+        self.reorg_depth(hash) > self.params.max_reorg_depth
+    }
+
+    /// Remove the blocks off the longest chain which could only join it by a reorg deeper than
+    /// `max_reorg_depth`, since it is refused. Return how many are removed.
+    pub fn prune_stale_forks(&mut self) -> usize {
+        let longest_chain: HashSet<H256> = self.iter_longest_chain().collect();
+        let stale_vec: Vec<H256> = self
+            .hash_to_block
+            .keys()
+            .filter(|hash| !longest_chain.contains(hash))
+            .filter(|hash| self.is_stale_fork(hash))
+            .cloned()
+            .collect();
+        for hash in stale_vec.iter() {
+            self.hash_to_block.remove(hash);
+            self.hash_to_len.remove(hash);
+            self.hash_to_work.remove(hash);
+            self.hash_to_state.remove(hash);
+        }
+        stale_vec.len()
+    }
+
+    /// Check that the blockchain is consistent, e.g. in tests after complex operations: every block
+    /// but genesis has a known parent and is one block longer, `max_len` is the length of the chain
+    /// ending at the tip, no block has more work than the tip unless on a fork too deep to reorg
+    /// to, and no state holds more money than the premine plus the block rewards
+    pub fn check_invariants(&self) -> Result<(), String> {
+        let premine: u128 = self
+            .params
+            .premine
+            .iter()
+            .map(|(_, value)| *value as u128)
+            .sum();
+        let tip_work = self
+            .hash_to_work
+            .get(&self.tip)
+            .cloned()
+            .unwrap_or_default();
+        for (hash, block) in self.hash_to_block.iter() {
+            let len = *self
+                .hash_to_len
+                .get(hash)
+                .ok_or_else(|| format!("Block {} has no length", hash))?;
+            let work = *self
+                .hash_to_work
+                .get(hash)
+                .ok_or_else(|| format!("Block {} has no work", hash))?;
+            let state = self
+                .hash_to_state
+                .get(hash)
+                .ok_or_else(|| format!("Block {} has no state", hash))?;
+            if !self.is_genesis(hash) {
+                let parent_len = self.hash_to_len.get(&block.get_parent()).ok_or_else(|| {
+                    format!("Parent {} of block {} is missing", block.get_parent(), hash)
+                })?;
+                if len != parent_len + 1 {
+                    return Err(format!(
+                        "Block {} has length {}, its parent {}",
+                        hash, len, parent_len
+                    ));
+                }
+            }
+            if work > tip_work && !self.is_stale_fork(hash) {
+                return Err(format!("Block {} has more work than the tip", hash));
+            }
+            let max_supply = premine + BLOCK_REWARD as u128 * (len - 1);
+            if state.total_balance() > max_supply {
+                return Err(format!(
+                    "State of block {} holds {} coins, more than the {} issued",
+                    hash,
+                    state.total_balance(),
+                    max_supply
+                ));
+            }
+        }
+
+        let chain_len = self
+            .chain_from(self.tip)
+            .ok_or_else(|| format!("Tip {} does not descend from genesis", self.tip))?
+            .len() as u128;
+        if self.max_len != chain_len {
+            return Err(format!(
+                "Max length is {}, the chain ending at the tip has {} blocks",
+                self.max_len, chain_len
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn summary(&self) -> ChainSummary {
+        ChainSummary {
+            tip: self.tip,
+            height: self.tip_height(),
+            total_blocks: self.hash_to_block.len(),
+            fork_count: self.fork_tips().len(),
+            total_txs: self
+                .iter_longest_chain()
+                .map(|hash| self.hash_to_block[&hash].content().tx_count())
+                .sum(),
+        }
+    }
+
+    /// Find a transaction on the longest chain, and return the hash of its block, the Merkle proof
+    /// of the signed transaction against the block's Merkle root, its index and the number of
+    /// transactions in the block. None for a block with batches, whose Merkle root covers them too.
+    pub fn tx_inclusion_proof(&self, txid: &H256) -> Option<(H256, Vec<H256>, usize, usize)> {
+        let block_hash = self.block_containing_tx(txid)?;
+        let content = self.hash_to_block[&block_hash].content();
+        if !content.batches.is_empty() {
+            return None;
+        }
+        let data = &content.data;
+        let index = data.iter().position(|tx| tx.txid() == *txid)?;
+        let proof = MerkleTree::new(data).proof(index);
+        Some((block_hash, proof, index, data.len()))
+    }
+
+    /// Get the transactions on the longest chain sent or received by an address, with their
+    /// blocks' hashes, ordered from genesis to the tip
+    // It scans every transaction of the longest chain, see `txids_for_address` for the indexed query
+    pub fn address_history(&self, addr: &Address) -> Vec<(H256, Transaction)> {
+        let mut history = vec![];
+        for block_hash in self.all_blocks_in_longest_chain() {
+            for tx in self.hash_to_block[&block_hash].content().transactions() {
+                if tx.sender() == *addr || tx.receiver() == *addr {
+                    history.push((block_hash, tx.clone()));
+                }
+            }
+        }
+        history
     }
+
+    /// Get a bloom filter of all known blocks' hashes, for peers to find out what they are missing
+    pub fn known_blocks_filter(&self) -> BloomFilter {
+        let mut filter = BloomFilter::new(self.hash_to_block.len());
+        for hash in self.hash_to_block.keys() {
+            filter.insert(hash);
+        }
+        filter
+    }
+}
+
+// The genesis block derived from the parameters, the same on every node
+fn genesis_block(params: &GenesisParams) -> Block {
+    let genesis_data: Vec<SignedTransaction> = Vec::new();
+    let genesis_header = Header {
+        parent: [0u8; 32].into(),
+        nonce: 0u32,
+        difficulty: params.difficulty,
+        timestamp: params.timestamp,
+        merkle_root: MerkleTree::new(&genesis_data).root(),
+    };
+    Block::new(
+        genesis_header,
+        Content {
+            data: genesis_data,
+            batches: vec![],
+        },
+    )
+}
+
+/// Expected number of hashes to meet a target, i.e. 2^256 / (target + 1), computed as
+/// !target / (target + 1) + 1 like Bitcoin does. It saturates for targets below 2^128, which are
+/// too hard to meet anyway.
+pub fn block_work(target: &H256) -> u128 {
+    let bytes: [u8; 32] = target.into();
+    let target_hi = u128::from_be_bytes(bytes[..16].try_into().unwrap());
+    let target_lo = u128::from_be_bytes(bytes[16..].try_into().unwrap());
+    if target_hi == 0 {
+        return u128::MAX;
+    }
+    if target_hi == u128::MAX && target_lo == u128::MAX {
+        return 1;
+    }
+
+    let (divisor_lo, carry) = target_lo.overflowing_add(1);
+    let divisor_hi = target_hi + carry as u128;
+    let (dividend_hi, dividend_lo) = (!target_hi, !target_lo);
+    // 256-bit long division, the quotient fits in 128 bits as the divisor exceeds 2^128
+    let (mut rem_hi, mut rem_lo) = (0u128, 0u128);
+    let mut quotient = 0u128;
+    for i in (0..256).rev() {
+        let overflow = rem_hi >> 127 == 1;
+        let next_bit = if i >= 128 {
+            (dividend_hi >> (i - 128)) & 1
+        } else {
+            (dividend_lo >> i) & 1
+        };
+        rem_hi = (rem_hi << 1) | (rem_lo >> 127);
+        rem_lo = (rem_lo << 1) | next_bit;
+        if overflow || (rem_hi, rem_lo) >= (divisor_hi, divisor_lo) {
+            let (lo, borrow) = rem_lo.overflowing_sub(divisor_lo);
+            rem_lo = lo;
+            rem_hi = rem_hi.wrapping_sub(divisor_hi).wrapping_sub(borrow as u128);
+            if i < 128 {
+                quotient |= 1 << i;
+            }
+        }
+    }
+    quotient.saturating_add(1)
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -127,6 +1079,1169 @@ mod tests {
             vec![genesis_hash, c1_b2.hash(), c1_b3.hash(), c1_b4.hash()]
         )
     }
+
+    #[test]
+    fn equal_length_tie_break() {
+        let mut blockchain_1 = Blockchain::new();
+        let mut blockchain_2 = Blockchain::new();
+        let genesis_hash = blockchain_1.tip();
+        let c1_b2 = generate_random_block(&genesis_hash);
+        let c2_b2 = generate_random_block(&genesis_hash);
+
+        blockchain_1.insert(&c1_b2);
+        blockchain_1.insert(&c2_b2);
+        blockchain_2.insert(&c2_b2);
+        blockchain_2.insert(&c1_b2);
+        assert_eq!(blockchain_1.tip(), blockchain_2.tip());
+        assert_eq!(
+            blockchain_1.tip(),
+            std::cmp::min(c1_b2.hash(), c2_b2.hash())
+        );
+    }
+
+    #[test]
+    fn iter_longest_chain_matches_vec() {
+        let mut blockchain = Blockchain::new();
+        let mut parent_hash = blockchain.tip();
+        for _ in 0..5 {
+            let block = generate_random_block(&parent_hash);
+            blockchain.insert(&block);
+            parent_hash = block.hash();
+        }
+        let genesis_hash = blockchain.all_blocks_in_longest_chain()[0];
+        blockchain.insert(&generate_random_block(&genesis_hash));
+
+        let mut iterated: Vec<H256> = blockchain.iter_longest_chain().collect();
+        iterated.reverse();
+        assert_eq!(iterated, blockchain.all_blocks_in_longest_chain());
+        assert_eq!(
+            blockchain.iter_longest_chain().next(),
+            Some(blockchain.tip())
+        );
+        assert_eq!(blockchain.iter_longest_chain().count(), 6);
+    }
+
+    #[test]
+    fn block_locator_spacing() {
+        let mut blockchain = Blockchain::new();
+        for _ in 1..20 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block);
+        }
+        // heights from 0 (genesis) to 19 (tip)
+        let chain = blockchain.all_blocks_in_longest_chain();
+        assert_eq!(chain.len(), 20);
+        let expected: Vec<H256> = [19, 18, 17, 15, 11, 3, 0]
+            .iter()
+            .map(|h| chain[*h])
+            .collect();
+        assert_eq!(blockchain.block_locator(), expected);
+
+        let genesis_only = Blockchain::new();
+        assert_eq!(genesis_only.block_locator(), vec![genesis_only.tip()]);
+    }
+
+    #[test]
+    fn branch_after_locator_of_forked_peer() {
+        let mut blockchain = Blockchain::new();
+        let mut peer_blockchain = Blockchain::new();
+        for _ in 0..3 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block);
+            peer_blockchain.insert(&block);
+        }
+        let fork_height = 3;
+        // the peer forks away, while we extend the common chain
+        for _ in 0..2 {
+            peer_blockchain.insert(&generate_random_block(&peer_blockchain.tip()));
+        }
+        for _ in 0..4 {
+            blockchain.insert(&generate_random_block(&blockchain.tip()));
+        }
+
+        let chain = blockchain.all_blocks_in_longest_chain();
+        let locator = peer_blockchain.block_locator();
+        let no_stop = H256::from([0u8; 32]);
+        assert_eq!(
+            blockchain.branch_after_locator(&locator, &no_stop, 100),
+            chain[fork_height + 1..].to_vec()
+        );
+        assert_eq!(
+            blockchain.branch_after_locator(&locator, &no_stop, 2),
+            chain[fork_height + 1..fork_height + 3].to_vec()
+        );
+        assert_eq!(
+            blockchain.branch_after_locator(&locator, &chain[fork_height + 1], 100),
+            vec![chain[fork_height + 1]]
+        );
+    }
+
+    #[test]
+    fn orphan_blocks_of_losing_fork() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let c1_b2 = generate_random_block(&genesis_hash);
+        let c1_b3 = generate_random_block(&c1_b2.hash());
+        let c2_b2 = generate_random_block(&genesis_hash);
+        let c2_b3 = generate_random_block(&c2_b2.hash());
+        let c2_b4 = generate_random_block(&c2_b3.hash());
+
+        blockchain.insert(&c1_b2);
+        blockchain.insert(&c1_b3);
+        assert!(blockchain.orphan_blocks().is_empty());
+        blockchain.insert(&c2_b2);
+        blockchain.insert(&c2_b3);
+        blockchain.insert(&c2_b4);
+
+        let mut orphans = blockchain.orphan_blocks();
+        orphans.sort();
+        let mut expected = vec![c1_b2.hash(), c1_b3.hash()];
+        expected.sort();
+        assert_eq!(orphans, expected);
+    }
+
+    #[test]
+    fn reject_timestamp_below_median_time_past() {
+        let mut blockchain = Blockchain::new();
+        let mut parent_hash = blockchain.tip();
+        // timestamps of the last 11 blocks are 100, 200, ..., 1100, permuted
+        for i in [3u128, 11, 1, 7, 5, 9, 2, 10, 4, 8, 6].iter() {
+            let mut block = generate_random_block(&parent_hash);
+            block.header.timestamp = i * 100;
+            blockchain.insert(&block);
+            parent_hash = block.hash();
+        }
+        assert_eq!(blockchain.median_time_past(&parent_hash), 600);
+
+        let mut block = generate_random_block(&parent_hash);
+        block.header.timestamp = 599;
+        assert_eq!(
+            blockchain.validate_block(&block),
+            Err(BlockValidationError::StaleTimestamp)
+        );
+        block.header.timestamp = 600;
+        assert_eq!(
+            blockchain.validate_block(&block),
+            Err(BlockValidationError::StaleTimestamp)
+        );
+        block.header.timestamp = 601;
+        assert_eq!(blockchain.validate_block(&block), Ok(()));
+    }
+
+    #[test]
+    fn validate_header_chain_linkage_and_difficulty() {
+        let blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let b2 = generate_random_block(&genesis_hash);
+        let b3 = generate_random_block(&b2.hash());
+        let b4 = generate_random_block(&b3.hash());
+        let headers = vec![b2.header.clone(), b3.header.clone(), b4.header.clone()];
+        assert_eq!(blockchain.validate_header_chain(&headers), Ok(()));
+
+        // a gap in the chain
+        assert_eq!(
+            blockchain.validate_header_chain(&[b2.header.clone(), b4.header.clone()]),
+            Err(BlockValidationError::MissingParent)
+        );
+        // not extending any known block
+        assert_eq!(
+            blockchain.validate_header_chain(&[b3.header.clone(), b4.header.clone()]),
+            Err(BlockValidationError::MissingParent)
+        );
+        // a difficulty different from the parent's one
+        let mut easier_b3 = b3.clone();
+        easier_b3.header.difficulty = [254u8; 32].into();
+        assert_eq!(
+            blockchain.validate_header_chain(&[b2.header.clone(), easier_b3.header]),
+            Err(BlockValidationError::DifficultyMismatch)
+        );
+    }
+
+    #[test]
+    fn median_time_past_of_short_chain() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut b2 = generate_random_block(&genesis_hash);
+        b2.header.timestamp = 50;
+        blockchain.insert(&b2);
+        // genesis at 0 and b2 at 50
+        assert_eq!(blockchain.median_time_past(&b2.hash()), 50);
+        assert_eq!(blockchain.median_time_past(&genesis_hash), 0);
+    }
+
+    #[test]
+    fn confirmations_toward_tip() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let b2 = generate_random_block(&genesis_hash);
+        let b3 = generate_random_block(&b2.hash());
+        let fork_b2 = generate_random_block(&genesis_hash);
+        blockchain.insert(&b2);
+        blockchain.insert(&b3);
+        blockchain.insert(&fork_b2);
+
+        assert_eq!(blockchain.confirmations(&genesis_hash), Some(2));
+        assert_eq!(blockchain.confirmations(&b2.hash()), Some(1));
+        assert_eq!(blockchain.confirmations(&b3.hash()), Some(0));
+        assert_eq!(blockchain.confirmations(&fork_b2.hash()), None);
+        assert_eq!(blockchain.confirmations(&[7u8; 32].into()), None);
+    }
+
+    #[test]
+    fn summary_of_small_forked_chain() {
+        use crate::types::transaction::generate_random_signed_transaction;
+
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut b1 = generate_random_block(&genesis_hash);
+        b1.content_mut().data = vec![
+            generate_random_signed_transaction(),
+            generate_random_signed_transaction(),
+        ];
+        b1.header.merkle_root = MerkleTree::new(&b1.content().data).root();
+        let mut b2 = generate_random_block(&b1.hash());
+        b2.content_mut().data = vec![generate_random_signed_transaction()];
+        b2.header.merkle_root = MerkleTree::new(&b2.content().data).root();
+        let mut fork_b1 = generate_random_block(&genesis_hash);
+        fork_b1.content_mut().data = vec![generate_random_signed_transaction()];
+        fork_b1.header.merkle_root = MerkleTree::new(&fork_b1.content().data).root();
+
+        blockchain.insert(&b1);
+        blockchain.insert(&b2);
+        blockchain.insert(&fork_b1);
+
+        assert_eq!(blockchain.fork_tips(), vec![fork_b1.hash()]);
+        assert_eq!(
+            blockchain.summary(),
+            ChainSummary {
+                tip: b2.hash(),
+                height: 2,
+                total_blocks: 4,
+                fork_count: 1,
+                total_txs: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut blockchain = Blockchain::new();
+        for _ in 0..5 {
+            blockchain.insert(&generate_random_block(&blockchain.tip()));
+        }
+        // a stale block is not part of the snapshot
+        blockchain.insert(&generate_random_block(
+            &blockchain.all_blocks_in_longest_chain()[1],
+        ));
+
+        let snapshot = blockchain.export_snapshot();
+        let imported = Blockchain::import_snapshot(&snapshot, &GenesisParams::default()).unwrap();
+        assert_eq!(imported.tip(), blockchain.tip());
+        assert_eq!(
+            imported.all_blocks_in_longest_chain(),
+            blockchain.all_blocks_in_longest_chain()
+        );
+        assert_eq!(imported.hash_to_block.len(), 6);
+
+        assert!(matches!(
+            Blockchain::import_snapshot(&snapshot[..snapshot.len() - 1], &GenesisParams::default()),
+            Err(SnapshotError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn import_snapshot_validates_transactions() {
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::transaction::{sign_transaction, Transaction};
+
+        let (alice, alice_key) = random_address_with_keypair();
+        let params = GenesisParams {
+            premine: vec![(alice, 100)],
+            ..Default::default()
+        };
+        let block_with_tx = |parent: &H256, value: i64, nonce: u64| {
+            let mut block = generate_random_block(parent);
+            block.content_mut().data = vec![sign_transaction(
+                Transaction::new(alice, [2u8; 20].into(), value, 0, nonce),
+                &alice_key,
+            )];
+            block.header.merkle_root = block.content().merkle_root();
+            block
+        };
+        let mut blockchain = Blockchain::with_params(params.clone());
+        blockchain.insert(&block_with_tx(&blockchain.tip(), 30, 0));
+        let snapshot = blockchain.export_snapshot();
+        let imported = Blockchain::import_snapshot(&snapshot, &params).unwrap();
+        assert_eq!(imported.tip(), blockchain.tip());
+        assert_eq!(imported.tip_state().account(&alice).balance, 70);
+
+        // inserted without validation, so the snapshot carries an overdraft
+        let overdrawing_block = block_with_tx(&blockchain.tip(), 80, 1);
+        blockchain.insert(&overdrawing_block);
+        let snapshot = blockchain.export_snapshot();
+        assert!(matches!(
+            Blockchain::import_snapshot(&snapshot, &params),
+            Err(SnapshotError::InvalidBlock(
+                hash,
+                BlockValidationError::InvalidTransaction(TxValidationError::InsufficientBalance)
+            )) if hash == overdrawing_block.hash()
+        ));
+    }
+
+    #[test]
+    fn tx_inclusion_proof_verifies() {
+        use crate::types::merkle::verify;
+        use crate::types::transaction::generate_random_signed_transaction;
+
+        let mut blockchain = Blockchain::new();
+        blockchain.insert(&generate_random_block(&blockchain.tip()));
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content_mut().data = (0..5)
+            .map(|_| generate_random_signed_transaction())
+            .collect();
+        block.header.merkle_root = MerkleTree::new(&block.content().data).root();
+        blockchain.insert(&block);
+
+        let signed_tx = &block.content().data[3];
+        let (block_hash, proof, index, leaf_size) =
+            blockchain.tx_inclusion_proof(&signed_tx.txid()).unwrap();
+        assert_eq!(block_hash, block.hash());
+        assert_eq!((index, leaf_size), (3, 5));
+        let merkle_root = blockchain.hash_to_block[&block_hash].header.merkle_root;
+        assert!(verify(
+            &merkle_root,
+            &signed_tx.hash(),
+            &proof,
+            index,
+            leaf_size
+        ));
+
+        let unknown_tx = generate_random_signed_transaction();
+        assert!(blockchain.tx_inclusion_proof(&unknown_tx.txid()).is_none());
+    }
+
+    #[test]
+    fn block_validation_error_variants() {
+        use crate::types::transaction::generate_random_signed_transaction;
+
+        let mut blockchain = Blockchain::new();
+        let parent = generate_random_block(&blockchain.tip());
+        blockchain.insert(&parent);
+        let block = generate_random_block(&parent.hash());
+        assert_eq!(blockchain.validate_block(&block), Ok(()));
+
+        let orphan = generate_random_block(&block.hash());
+        assert_eq!(
+            blockchain.validate_block(&orphan),
+            Err(BlockValidationError::MissingParent)
+        );
+
+        let mut hard_block = block.clone();
+        hard_block.header.difficulty = H256::from([0u8; 32]);
+        assert_eq!(
+            blockchain.validate_block(&hard_block),
+            Err(BlockValidationError::DifficultyMismatch)
+        );
+
+        // a parent with the hardest difficulty, which no hash can meet
+        let mut hard_parent = generate_random_block(&blockchain.tip());
+        hard_parent.header.difficulty = H256::from([0u8; 32]);
+        blockchain.insert(&hard_parent);
+        let mut no_pow_block = generate_random_block(&hard_parent.hash());
+        no_pow_block.header.difficulty = H256::from([0u8; 32]);
+        assert_eq!(
+            blockchain.validate_block(&no_pow_block),
+            Err(BlockValidationError::BadPow)
+        );
+
+        let mut future_block = block.clone();
+        future_block.header.timestamp += 2 * MAX_FUTURE_BLOCK_TIME_MS;
+        assert_eq!(
+            blockchain.validate_block(&future_block),
+            Err(BlockValidationError::FutureTimestamp)
+        );
+
+        let mut tampered_block = block.clone();
+        tampered_block.content_mut().data = vec![generate_random_signed_transaction()];
+        assert_eq!(
+            blockchain.validate_block(&tampered_block),
+            Err(BlockValidationError::BadMerkleRoot)
+        );
+
+        let mut oversized_block = block;
+        oversized_block.content_mut().data =
+            vec![generate_random_signed_transaction(); MAX_BLOCK_BYTES / 100];
+        oversized_block.header.merkle_root =
+            MerkleTree::new(&oversized_block.content().data).root();
+        assert_eq!(
+            blockchain.validate_block(&oversized_block),
+            Err(BlockValidationError::Oversized)
+        );
+    }
+
+    #[test]
+    fn refuse_reorg_deeper_than_limit() {
+        let mut blockchain = Blockchain::with_params(GenesisParams {
+            max_reorg_depth: 2,
+            ..Default::default()
+        });
+        let genesis_hash = blockchain.tip();
+        for _ in 0..4 {
+            blockchain.insert(&generate_random_block(&blockchain.tip()));
+        }
+        let chain = blockchain.all_blocks_in_longest_chain();
+        let tip = blockchain.tip();
+
+        // a longer fork from genesis would replace 4 blocks
+        let mut fork_tip = genesis_hash;
+        for _ in 0..6 {
+            let block = generate_random_block(&fork_tip);
+            blockchain.insert(&block);
+            fork_tip = block.hash();
+        }
+        assert_eq!(blockchain.tip(), tip);
+        assert_eq!(
+            blockchain.common_ancestor(&fork_tip, &tip),
+            Some(genesis_hash)
+        );
+
+        // a longer fork from the third block replaces 2 blocks only, which are then pruned
+        let mut fork_tip = chain[2];
+        let mut disconnected = vec![];
+        for _ in 0..3 {
+            let block = generate_random_block(&fork_tip);
+            if let Some(reorg) = blockchain.insert(&block) {
+                disconnected.extend(reorg.disconnected);
+            }
+            fork_tip = block.hash();
+        }
+        assert_eq!(blockchain.tip(), fork_tip);
+        assert_eq!(disconnected, vec![tip, chain[3]]);
+    }
+
+    #[test]
+    fn address_history_of_sent_and_received() {
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::transaction::{generate_random_signed_transaction, sign_transaction};
+
+        let (alice, alice_key) = random_address_with_keypair();
+        let (bob, bob_key) = random_address_with_keypair();
+        let sent_tx = Transaction::new(alice, bob, 10, 1, 0);
+        let received_tx = Transaction::new(bob, alice, 5, 1, 0);
+
+        let mut blockchain = Blockchain::new();
+        for data in [
+            vec![sign_transaction(sent_tx.clone(), &alice_key)],
+            vec![generate_random_signed_transaction()],
+            vec![
+                generate_random_signed_transaction(),
+                sign_transaction(received_tx.clone(), &bob_key),
+            ],
+        ]
+        .iter()
+        {
+            let mut block = generate_random_block(&blockchain.tip());
+            block.header.merkle_root = MerkleTree::new(data).root();
+            block.content_mut().data = data.clone();
+            blockchain.insert(&block);
+        }
+
+        let chain = blockchain.all_blocks_in_longest_chain();
+        let history: Vec<(H256, H256)> = blockchain
+            .address_history(&alice)
+            .into_iter()
+            .map(|(block_hash, tx)| (block_hash, tx.hash()))
+            .collect();
+        assert_eq!(
+            history,
+            vec![(chain[1], sent_tx.hash()), (chain[3], received_tx.hash())]
+        );
+    }
+
+    #[test]
+    fn address_index_matches_scan_after_reorg() {
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::transaction::sign_transaction;
+
+        let accounts: Vec<_> = (0..3).map(|_| random_address_with_keypair()).collect();
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        // a block of transactions between the accounts
+        let block_on = |parent: &H256, nonce: u64| {
+            let data: Vec<SignedTransaction> = (0..accounts.len())
+                .map(|i| {
+                    let (sender, key) = &accounts[i];
+                    let receiver = accounts[(i + 1) % accounts.len()].0;
+                    sign_transaction(Transaction::new(*sender, receiver, 1, 0, nonce), key)
+                })
+                .collect();
+            let mut block = generate_random_block(parent);
+            block.header.merkle_root = MerkleTree::new(&data).root();
+            block.content_mut().data = data;
+            block
+        };
+        let assert_index_matches_scan = |blockchain: &Blockchain| {
+            for (addr, _) in accounts.iter() {
+                let scanned: Vec<H256> = blockchain
+                    .address_history(addr)
+                    .into_iter()
+                    .map(|(_, tx)| tx.hash())
+                    .collect();
+                assert_eq!(blockchain.txids_for_address(addr), scanned);
+            }
+        };
+
+        let b1 = block_on(&genesis_hash, 0);
+        let b2 = block_on(&b1.hash(), 1);
+        blockchain.insert(&b1);
+        blockchain.insert(&b2);
+        assert_eq!(blockchain.txids_for_address(&accounts[0].0).len(), 4);
+        assert_index_matches_scan(&blockchain);
+
+        // a longer fork replaces b2
+        let fork_b2 = block_on(&b1.hash(), 2);
+        let fork_b3 = block_on(&fork_b2.hash(), 3);
+        blockchain.insert(&fork_b2);
+        blockchain.insert(&fork_b3);
+        assert_eq!(blockchain.tip(), fork_b3.hash());
+        assert_eq!(blockchain.txids_for_address(&accounts[0].0).len(), 6);
+        assert_index_matches_scan(&blockchain);
+    }
+
+    #[test]
+    fn future_timestamp_against_mock_clock() {
+        use crate::types::clock::MockClock;
+
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let blockchain = Blockchain::with_params_and_clock(GenesisParams::default(), clock.clone());
+        let mut block = generate_random_block(&blockchain.tip());
+        block.header.timestamp = 1_000_000 + MAX_FUTURE_BLOCK_TIME_MS + 1;
+        assert_eq!(
+            blockchain.validate_block(&block),
+            Err(BlockValidationError::FutureTimestamp)
+        );
+        clock.advance(1);
+        assert_eq!(blockchain.validate_block(&block), Ok(()));
+    }
+
+    #[test]
+    fn block_work_of_targets() {
+        assert_eq!(block_work(&H256::from([255u8; 32])), 1);
+        let mut raw_bytes = [255u8; 32];
+        raw_bytes[0] = 0x7f;
+        assert_eq!(block_work(&H256::from(raw_bytes)), 2);
+        raw_bytes[0] = 0;
+        assert_eq!(block_work(&H256::from(raw_bytes)), 256);
+        raw_bytes[0] = 0x3f;
+        raw_bytes[1] = 0;
+        // 2^256 / (0x3f00ff..ff + 1) rounds down to 4
+        assert_eq!(block_work(&H256::from(raw_bytes)), 4);
+        assert_eq!(block_work(&H256::from([0u8; 32])), u128::MAX);
+    }
+
+    #[test]
+    fn most_work_wins_over_longest() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        for _ in 0..4 {
+            blockchain.insert(&generate_random_block(&blockchain.tip()));
+        }
+        assert_eq!(blockchain.tip_work(), 5);
+
+        // one block with 256 times the work of the easy ones
+        let mut raw_bytes = [255u8; 32];
+        raw_bytes[0] = 0;
+        let mut hard_block = generate_random_block(&genesis_hash);
+        hard_block.header.difficulty = H256::from(raw_bytes);
+        blockchain.insert(&hard_block);
+
+        assert_eq!(blockchain.tip(), hard_block.hash());
+        assert_eq!(blockchain.tip_work(), 257);
+        assert_eq!(blockchain.tip_height(), 1);
+        assert_eq!(blockchain.all_blocks_in_longest_chain().len(), 2);
+    }
+
+    #[test]
+    fn genesis_hash_is_first_of_longest_chain() {
+        let params = GenesisParams {
+            timestamp: 42,
+            ..GenesisParams::default()
+        };
+        let mut blockchain = Blockchain::with_params(params);
+        let genesis_hash = blockchain.genesis_hash();
+        assert_eq!(genesis_hash, blockchain.tip());
+        for _ in 0..3 {
+            blockchain.insert(&generate_random_block(&blockchain.tip()));
+        }
+
+        assert_eq!(blockchain.all_blocks_in_longest_chain()[0], genesis_hash);
+        assert!(blockchain.is_genesis(&genesis_hash));
+        assert!(!blockchain.is_genesis(&blockchain.tip()));
+        assert_ne!(genesis_hash, Blockchain::new().genesis_hash());
+    }
+
+    #[test]
+    fn state_follows_blocks() {
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::transaction::sign_transaction;
+
+        let (alice, alice_key) = random_address_with_keypair();
+        let bob: Address = [2u8; 20].into();
+        let mut blockchain = Blockchain::with_params(GenesisParams {
+            premine: vec![(alice, 100)],
+            ..Default::default()
+        });
+        let genesis_hash = blockchain.tip();
+        assert_eq!(blockchain.tip_state().account(&alice).balance, 100);
+
+        let mut block = generate_random_block(&genesis_hash);
+        block.content_mut().data = vec![
+            sign_transaction(Transaction::new(alice, bob, 30, 1, 0), &alice_key),
+            // spending more than left has no effect
+            sign_transaction(Transaction::new(alice, bob, 80, 0, 1), &alice_key),
+        ];
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tip_state().account(&alice).balance, 69);
+        assert_eq!(blockchain.tip_state().account(&alice).nonce, 1);
+        assert_eq!(blockchain.tip_state().account(&bob).balance, 30);
+        // the parent's state is kept
+        assert_eq!(
+            blockchain
+                .state(&genesis_hash)
+                .unwrap()
+                .account(&bob)
+                .balance,
+            0
+        );
+    }
+
+    #[test]
+    fn recent_blocks_newest_first() {
+        let mut blockchain = Blockchain::new();
+        // genesis and 9 mined blocks
+        for _ in 0..9 {
+            blockchain.insert(&generate_random_block(&blockchain.tip()));
+        }
+        let longest_chain = blockchain.all_blocks_in_longest_chain();
+        assert_eq!(longest_chain.len(), 10);
+
+        let recent_hashes: Vec<H256> = blockchain
+            .recent_blocks(3)
+            .iter()
+            .map(|block| block.hash())
+            .collect();
+        assert_eq!(
+            recent_hashes,
+            vec![longest_chain[9], longest_chain[8], longest_chain[7]]
+        );
+        assert_eq!(blockchain.recent_blocks(20).len(), 10);
+    }
+
+    #[test]
+    fn block_containing_tx_of_longest_chain() {
+        use crate::types::transaction::generate_random_signed_transaction;
+
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let tx_vec = vec![
+            generate_random_signed_transaction(),
+            generate_random_signed_transaction(),
+        ];
+        let mut block = generate_random_block(&genesis_hash);
+        block.content_mut().data = tx_vec.clone();
+        blockchain.insert(&block);
+        for tx in tx_vec.iter() {
+            assert_eq!(
+                blockchain.block_containing_tx(&tx.txid()),
+                Some(block.hash())
+            );
+        }
+        let unknown_tx = generate_random_signed_transaction();
+        assert_eq!(blockchain.block_containing_tx(&unknown_tx.txid()), None);
+
+        // a longer fork without the transactions
+        let fork_block = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork_block);
+        blockchain.insert(&generate_random_block(&fork_block.hash()));
+        assert_eq!(blockchain.block_containing_tx(&tx_vec[0].txid()), None);
+    }
+
+    #[test]
+    fn prune_forks_beyond_reorg_depth() {
+        let mut blockchain = Blockchain::with_params(GenesisParams {
+            max_reorg_depth: 3,
+            ..Default::default()
+        });
+        let genesis_hash = blockchain.tip();
+        let stale_block = generate_random_block(&genesis_hash);
+        blockchain.insert(&stale_block);
+        // a longest chain of 5 blocks, whose length is not a multiple of the reorg depth, so that
+        // the insert did not prune
+        let mut parent_hash = genesis_hash;
+        for _ in 0..4 {
+            let block = generate_random_block(&parent_hash);
+            blockchain.insert(&block);
+            parent_hash = block.hash();
+        }
+        // may still win by a reorg of depth 3
+        let recent_fork_block = generate_random_block(&blockchain.all_blocks_in_longest_chain()[2]);
+        blockchain.insert(&recent_fork_block);
+
+        assert_eq!(blockchain.prune_stale_forks(), 1);
+        assert!(!blockchain.hash_to_block.contains_key(&stale_block.hash()));
+        assert!(blockchain.state(&stale_block.hash()).is_none());
+        assert!(blockchain
+            .hash_to_block
+            .contains_key(&recent_fork_block.hash()));
+        assert_eq!(blockchain.all_blocks_in_longest_chain().len(), 5);
+    }
+
+    #[test]
+    fn prune_stale_forks_on_insert() {
+        let mut blockchain = Blockchain::with_params(GenesisParams {
+            max_reorg_depth: 2,
+            ..Default::default()
+        });
+        let genesis_hash = blockchain.tip();
+        let stale_block = generate_random_block(&genesis_hash);
+        blockchain.insert(&stale_block);
+        let mut parent_hash = genesis_hash;
+        for _ in 0..2 {
+            let block = generate_random_block(&parent_hash);
+            blockchain.insert(&block);
+            parent_hash = block.hash();
+        }
+        assert!(blockchain.hash_to_block.contains_key(&stale_block.hash()));
+
+        // the longest chain reaches 4 blocks, a multiple of the reorg depth
+        blockchain.insert(&generate_random_block(&parent_hash));
+        assert!(!blockchain.hash_to_block.contains_key(&stale_block.hash()));
+        assert!(blockchain.state(&stale_block.hash()).is_none());
+        assert_eq!(blockchain.all_blocks_in_longest_chain().len(), 4);
+        assert_eq!(blockchain.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn submit_valid_and_invalid_blocks() {
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::transaction::{coinbase_transaction, sign_transaction};
+
+        let (alice, key) = random_address_with_keypair();
+        let bob: Address = [2u8; 20].into();
+        let mut blockchain = Blockchain::with_params(GenesisParams {
+            premine: vec![(alice, 100)],
+            ..Default::default()
+        });
+        let genesis_hash = blockchain.tip();
+        let block_with = |data: Vec<SignedTransaction>| {
+            let mut block = generate_random_block(&genesis_hash);
+            block.header.merkle_root = MerkleTree::new(&data).root();
+            block.content_mut().data = data;
+            block
+        };
+
+        let valid_block = block_with(vec![
+            coinbase_transaction(bob, BLOCK_REWARD, 1),
+            sign_transaction(Transaction::new(alice, bob, 30, 1, 0), &key),
+        ]);
+        assert_eq!(blockchain.submit_block(valid_block.clone()), Ok(None));
+        assert_eq!(blockchain.tip(), valid_block.hash());
+        assert_eq!(blockchain.tip_state().account(&alice).balance, 69);
+
+        let overdrawing_block = block_with(vec![sign_transaction(
+            Transaction::new(alice, bob, 101, 0, 0),
+            &key,
+        )]);
+        assert_eq!(
+            blockchain.submit_block(overdrawing_block.clone()),
+            Err(BlockValidationError::InvalidTransaction(
+                TxValidationError::InsufficientBalance
+            ))
+        );
+        let greedy_block = block_with(vec![coinbase_transaction(bob, BLOCK_REWARD + 1, 1)]);
+        assert_eq!(
+            blockchain.submit_block(greedy_block),
+            Err(BlockValidationError::BadCoinbase)
+        );
+        let mut bad_pow_block = block_with(vec![]);
+        bad_pow_block.header.difficulty = H256::default();
+        assert_eq!(
+            blockchain.submit_block(bad_pow_block),
+            Err(BlockValidationError::DifficultyMismatch)
+        );
+        assert!(!blockchain
+            .hash_to_block
+            .contains_key(&overdrawing_block.hash()));
+        assert_eq!(blockchain.tip(), valid_block.hash());
+    }
+
+    #[test]
+    fn submit_blocks_with_batches() {
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::transaction::{sign_batch, SignedTransactionBatch};
+
+        let (alice, key) = random_address_with_keypair();
+        let bob: Address = [2u8; 20].into();
+        let mut blockchain = Blockchain::with_params(GenesisParams {
+            premine: vec![(alice, 100)],
+            ..Default::default()
+        });
+        let genesis_hash = blockchain.tip();
+        let block_with = |batches: Vec<SignedTransactionBatch>| {
+            let mut block = generate_random_block(&genesis_hash);
+            block.content_mut().batches = batches;
+            block.header.merkle_root = block.merkle_root();
+            block
+        };
+
+        // the second transaction spends more than the first leaves
+        let overdrawing_batch = sign_batch(
+            vec![
+                Transaction::new(alice, bob, 30, 0, 0),
+                Transaction::new(alice, bob, 80, 0, 1),
+            ],
+            &key,
+        );
+        let overdrawing_block = block_with(vec![overdrawing_batch]);
+        assert_eq!(
+            blockchain.submit_block(overdrawing_block.clone()),
+            Err(BlockValidationError::InvalidTransaction(
+                TxValidationError::InsufficientBalance
+            ))
+        );
+        assert!(!blockchain
+            .hash_to_block
+            .contains_key(&overdrawing_block.hash()));
+        assert_eq!(blockchain.tip_state().account(&alice).balance, 100);
+
+        let batch = sign_batch(
+            vec![
+                Transaction::new(alice, bob, 30, 0, 0),
+                Transaction::new(alice, bob, 20, 0, 1),
+            ],
+            &key,
+        );
+        let valid_block = block_with(vec![batch.clone()]);
+        assert_eq!(blockchain.submit_block(valid_block.clone()), Ok(None));
+        assert_eq!(blockchain.tip_state().account(&alice).balance, 50);
+        assert_eq!(blockchain.tip_state().account(&alice).nonce, 2);
+        assert_eq!(blockchain.txids_for_address(&bob), batch.txids());
+        assert_eq!(blockchain.summary().total_txs, 2);
+    }
+
+    #[test]
+    fn view_keeps_snapshot_state() {
+        let mut blockchain = Blockchain::new();
+        let block_1 = generate_random_block(&blockchain.tip());
+        blockchain.insert(&block_1);
+        let view = blockchain.view();
+
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert(&block_2);
+        assert_eq!(view.tip, block_1.hash());
+        assert_eq!(view.height, 1);
+        assert!(view.get_block(&block_2.hash()).is_none());
+        assert_eq!(
+            view.longest_chain(),
+            vec![blockchain.genesis_hash(), block_1.hash()]
+        );
+        assert_eq!(blockchain.view().longest_chain().len(), 3);
+    }
+
+    #[test]
+    fn chain_from_losing_fork() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let fork_block = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork_block);
+        let losing_tip = generate_random_block(&fork_block.hash());
+        blockchain.insert(&losing_tip);
+        let mut parent_hash = genesis_hash;
+        for _ in 0..3 {
+            let block = generate_random_block(&parent_hash);
+            blockchain.insert(&block);
+            parent_hash = block.hash();
+        }
+        assert_eq!(blockchain.tip(), parent_hash);
+
+        assert_eq!(
+            blockchain.chain_from(losing_tip.hash()),
+            Some(vec![genesis_hash, fork_block.hash(), losing_tip.hash()])
+        );
+        assert_eq!(
+            blockchain.chain_from(blockchain.tip()),
+            Some(blockchain.all_blocks_in_longest_chain())
+        );
+        assert_eq!(
+            blockchain.chain_from(genesis_hash),
+            Some(vec![genesis_hash])
+        );
+        assert_eq!(blockchain.chain_from([9u8; 32].into()), None);
+    }
+
+    #[test]
+    fn get_header_of_block() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+
+        let header = blockchain.get_header(&block.hash()).unwrap();
+        assert_eq!(header.hash(), block.header.hash());
+        assert_eq!(header.timestamp, block.header.timestamp);
+        assert_eq!(header.difficulty, block.get_difficulty());
+        assert!(blockchain.get_header(&[7u8; 32].into()).is_none());
+    }
+
+    #[test]
+    fn report_reorg_to_heavier_fork() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let a1 = generate_random_block(&genesis_hash);
+        let a2 = generate_random_block(&a1.hash());
+        assert_eq!(blockchain.insert(&a1), None);
+        assert_eq!(blockchain.insert(&a2), None);
+
+        // a fork with less work does not move the tip
+        let b1 = generate_random_block(&genesis_hash);
+        assert_eq!(blockchain.insert(&b1), None);
+
+        // one block with 256 times the work makes it the longest chain
+        let mut raw_bytes = [255u8; 32];
+        raw_bytes[0] = 0;
+        let mut b2 = generate_random_block(&b1.hash());
+        b2.header.difficulty = H256::from(raw_bytes);
+        assert_eq!(
+            blockchain.insert(&b2),
+            Some(ReorgInfo {
+                disconnected: vec![a2.hash(), a1.hash()],
+                connected: vec![b1.hash(), b2.hash()],
+            })
+        );
+
+        // extending the tip is no reorg
+        assert_eq!(blockchain.insert(&generate_random_block(&b2.hash())), None);
+    }
+
+    #[test]
+    fn check_invariants_of_chain() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        for _ in 0..3 {
+            blockchain.insert(&generate_random_block(&blockchain.tip()));
+        }
+        blockchain.insert(&generate_random_block(&genesis_hash));
+        assert_eq!(blockchain.check_invariants(), Ok(()));
+
+        blockchain.max_len += 1;
+        assert!(blockchain.check_invariants().is_err());
+    }
+
+    #[test]
+    fn reject_too_many_transactions() {
+        use crate::types::transaction::generate_random_signed_transaction;
+
+        let blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut block = generate_random_block(&genesis_hash);
+        // small enough in bytes
+        block.content_mut().data = (0..=MAX_TXS_PER_BLOCK)
+            .map(|_| generate_random_signed_transaction())
+            .collect();
+        block.header.merkle_root = MerkleTree::new(&block.content().data).root();
+        assert!(bincode::serialized_size(&block).unwrap() as usize <= MAX_BLOCK_BYTES);
+        assert_eq!(
+            blockchain.validate_block(&block),
+            Err(BlockValidationError::TooManyTransactions)
+        );
+
+        block.content_mut().data.pop();
+        block.header.merkle_root = MerkleTree::new(&block.content().data).root();
+        assert_eq!(blockchain.validate_block(&block), Ok(()));
+    }
+
+    #[test]
+    fn would_accept_tx_at_block() {
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::transaction::{generate_signed_transaction, sign_transaction};
+
+        let (alice, key) = random_address_with_keypair();
+        let bob: Address = [2u8; 20].into();
+        let tx = sign_transaction(Transaction::new(alice, bob, 30, 1, 0), &key);
+
+        let blockchain = Blockchain::new();
+        assert_eq!(
+            blockchain.would_accept_tx(&tx, &blockchain.genesis_hash()),
+            Err(TxValidationError::InsufficientBalance)
+        );
+
+        let premined_blockchain = Blockchain::with_params(GenesisParams {
+            premine: vec![(alice, 100)],
+            ..Default::default()
+        });
+        let genesis_hash = premined_blockchain.genesis_hash();
+        assert_eq!(
+            premined_blockchain.would_accept_tx(&tx, &genesis_hash),
+            Ok(())
+        );
+        // nothing is applied
+        assert_eq!(premined_blockchain.tip_state().account(&alice).balance, 100);
+        assert_eq!(
+            premined_blockchain.would_accept_tx(
+                &generate_signed_transaction(tx.transaction().clone()),
+                &genesis_hash
+            ),
+            Err(TxValidationError::WrongSender)
+        );
+        assert_eq!(
+            premined_blockchain.would_accept_tx(&tx, &[7u8; 32].into()),
+            Err(TxValidationError::UnknownBlock)
+        );
+    }
+
+    #[test]
+    fn normalize_timestamp_after_median_time_past() {
+        let blockchain = Blockchain::with_params(GenesisParams {
+            timestamp: 1_000,
+            ..Default::default()
+        });
+        let genesis_hash = blockchain.genesis_hash();
+        assert_eq!(blockchain.normalize_timestamp(&genesis_hash, 500), 1_001);
+        assert_eq!(blockchain.normalize_timestamp(&genesis_hash, 1_000), 1_001);
+        assert_eq!(blockchain.normalize_timestamp(&genesis_hash, 2_000), 2_000);
+    }
+
+    #[test]
+    fn tx_confirmations_after_empty_blocks() {
+        use crate::types::transaction::generate_random_signed_transaction;
+
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let tx = generate_random_signed_transaction();
+        let mut block = generate_random_block(&genesis_hash);
+        block.content_mut().data = vec![tx.clone()];
+        block.header.merkle_root = MerkleTree::new(&block.content().data).root();
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tx_confirmations(&tx.txid()), Some(1));
+
+        for _ in 0..3 {
+            blockchain.insert(&generate_random_block(&blockchain.tip()));
+        }
+        assert_eq!(blockchain.tx_confirmations(&tx.txid()), Some(4));
+        assert_eq!(blockchain.tx_confirmations(&[7u8; 32].into()), None);
+    }
+
+    #[test]
+    fn difficulty_history_of_longest_chain() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let genesis_difficulty = H256::from(GENESIS_DIFFICULTY);
+        let mut raw_bytes = [255u8; 32];
+        raw_bytes[0] = 127;
+        let harder = H256::from(raw_bytes);
+
+        let b1 = generate_random_block(&genesis_hash);
+        let mut b2 = generate_random_block(&b1.hash());
+        b2.header.difficulty = harder;
+        blockchain.insert(&b1);
+        blockchain.insert(&b2);
+        // a losing fork is not reported
+        blockchain.insert(&generate_random_block(&genesis_hash));
+
+        assert_eq!(
+            blockchain.difficulty_history(),
+            vec![
+                (0, genesis_difficulty),
+                (1, genesis_difficulty),
+                (2, harder)
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_insert_changes_nothing() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let b1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&b1);
+        // an inconsistent blockchain makes the next insert fail once the block's state is computed
+        blockchain.hash_to_work.remove(&b1.hash());
+
+        let fork_block = generate_random_block(&genesis_hash);
+        let result = catch_unwind(AssertUnwindSafe(|| blockchain.insert(&fork_block)));
+        assert!(result.is_err());
+        let fork_hash = fork_block.hash();
+        assert!(!blockchain.hash_to_block.contains_key(&fork_hash));
+        assert!(!blockchain.hash_to_len.contains_key(&fork_hash));
+        assert!(!blockchain.hash_to_work.contains_key(&fork_hash));
+        assert!(!blockchain.hash_to_state.contains_key(&fork_hash));
+        assert_eq!(blockchain.tip(), b1.hash());
+        assert_eq!(blockchain.tip_height(), 1);
+    }
+
+    #[test]
+    fn siblings_on_same_parent() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let b1 = generate_random_block(&genesis_hash);
+        let fork_b1 = generate_random_block(&genesis_hash);
+        let b2 = generate_random_block(&b1.hash());
+        blockchain.insert(&b1);
+        blockchain.insert(&fork_b1);
+        blockchain.insert(&b2);
+
+        assert_eq!(blockchain.siblings(&b1.hash()), vec![fork_b1.hash()]);
+        assert_eq!(blockchain.siblings(&fork_b1.hash()), vec![b1.hash()]);
+        assert!(blockchain.siblings(&b2.hash()).is_empty());
+        assert!(blockchain.siblings(&genesis_hash).is_empty());
+        assert!(blockchain.siblings(&[7u8; 32].into()).is_empty());
+    }
+
+    #[test]
+    fn reject_duplicate_transaction() {
+        use crate::types::transaction::generate_random_signed_transaction;
+
+        let blockchain = Blockchain::new();
+        let tx = generate_random_signed_transaction();
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content_mut().data = vec![tx.clone(), generate_random_signed_transaction(), tx];
+        block.header.merkle_root = MerkleTree::new(&block.content().data).root();
+        assert_eq!(
+            blockchain.validate_block(&block),
+            Err(BlockValidationError::DuplicateTransaction)
+        );
+
+        block.content_mut().data.pop();
+        block.header.merkle_root = MerkleTree::new(&block.content().data).root();
+        assert_eq!(blockchain.validate_block(&block), Ok(()));
+    }
+
+    #[test]
+    fn account_count_after_new_accounts() {
+        use crate::types::address::address_from_seed;
+        use crate::types::transaction::sign_transaction;
+
+        let (alice, alice_key) = address_from_seed(1);
+        let bob = address_from_seed(2).0;
+        let carol = address_from_seed(3).0;
+        let mut blockchain = Blockchain::with_params(GenesisParams {
+            premine: vec![(alice, 100)],
+            ..Default::default()
+        });
+        assert_eq!(blockchain.account_count(), 1);
+
+        // alice spends the whole balance, creating two accounts
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content_mut().data = vec![
+            sign_transaction(Transaction::new(alice, bob, 60, 0, 0), &alice_key),
+            sign_transaction(Transaction::new(alice, carol, 40, 0, 1), &alice_key),
+        ];
+        block.header.merkle_root = MerkleTree::new(&block.content().data).root();
+        assert_eq!(blockchain.submit_block(block), Ok(None));
+        assert_eq!(blockchain.account_count(), 2);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -4,22 +4,26 @@ extern crate hex_literal;
 
 pub mod api;
 pub mod blockchain;
+pub mod mempool;
 pub mod miner;
 pub mod network;
+pub mod node;
 pub mod types;
 
 use api::Server as ApiServer;
-use blockchain::Blockchain;
 use clap::clap_app;
-use log::{error, info};
-use smol::channel;
+use log::error;
+use node::{Node, NodeConfig};
 use std::net;
 use std::process;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time;
 
 fn main() {
+    // the defaults of the network settings are the node's
+    let defaults = NodeConfig::default();
+    let default_block_msgs_burst = defaults.block_msgs_burst.to_string();
+    let default_block_msgs_per_sec = defaults.block_msgs_per_sec.to_string();
+    let default_max_msg_bytes = defaults.max_msg_bytes.to_string();
+
     // parse command line arguments
     let matches = clap_app!(Bitcoin =>
      (version: "0.1")
@@ -29,6 +33,12 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg min_peers: --("min-peers") [INT] default_value("0") "Keeps dialing the known peers while fewer are connected")
+     (@arg block_msgs_burst: --("block-burst") [INT] default_value(&default_block_msgs_burst) "Sets the number of block messages a peer may send in a burst")
+     (@arg block_msgs_per_sec: --("block-rate") [RATE] default_value(&default_block_msgs_per_sec) "Sets the number of block messages per second a peer may send after a burst")
+     (@arg relay_fanout: --("relay-fanout") [INT] "Announces new blocks and transactions to this many random peers, to all if not set")
+     (@arg max_msg_bytes: --("max-msg-bytes") [BYTES] default_value(&default_max_msg_bytes) "Rejects larger messages from peers")
+     (@arg tip_reannounce: --("tip-reannounce") [MS] "Re-announces the tip to peers at the given interval, off if not set")
     )
     .get_matches();
 
@@ -36,10 +46,6 @@ fn main() {
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
 
-    // create a new thread-safe blockchain object
-    let blockchain = Blockchain::new();
-    let blockchain = Arc::new(Mutex::new(blockchain));
-
     // parse p2p server address
     let p2p_addr = matches
         .value_of("peer_addr")
@@ -60,14 +66,7 @@ fn main() {
             process::exit(1);
         });
 
-    // create channels between server and worker
-    let (msg_tx, msg_rx) = channel::bounded(10000);
-
-    // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
-
-    // start the worker
+    // parse the number of p2p workers
     let p2p_workers = matches
         .value_of("p2p_workers")
         .unwrap()
@@ -76,50 +75,106 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
-    let worker_ctx = network::worker::Worker::new(p2p_workers, msg_rx, &server, &blockchain);
-    worker_ctx.start();
-
-    // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain);
-    miner_ctx.start();
-    miner_worker_ctx.start();
-
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
-            }
+
+    // parse the number of peers to keep connected
+    let min_peers = matches
+        .value_of("min_peers")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing min peers: {}", e);
+            process::exit(1);
         });
-    }
+
+    // parse the rate limit of block messages from each peer
+    let block_msgs_burst = matches
+        .value_of("block_msgs_burst")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block burst: {}", e);
+            process::exit(1);
+        });
+    let block_msgs_per_sec = matches
+        .value_of("block_msgs_per_sec")
+        .unwrap()
+        .parse::<f64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block rate: {}", e);
+            process::exit(1);
+        });
+
+    // parse the number of peers to relay to, all of them if not set
+    let relay_fanout = matches.value_of("relay_fanout").map(|fanout| {
+        fanout.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing relay fanout: {}", e);
+            process::exit(1);
+        })
+    });
+
+    // parse the max size of messages from peers
+    let max_msg_bytes = matches
+        .value_of("max_msg_bytes")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max message bytes: {}", e);
+            process::exit(1);
+        });
+
+    // parse the interval of tip re-announcements, off if not set
+    let tip_reannounce_ms = matches.value_of("tip_reannounce").map(|interval| {
+        interval.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing tip re-announce interval: {}", e);
+            process::exit(1);
+        })
+    });
+
+    // parse known peers, skipping the malformed ones
+    let seed_peers: Vec<net::SocketAddr> = matches
+        .values_of("known_peer")
+        .map(|known_peers| {
+            known_peers
+                .filter_map(|peer| match peer.parse::<net::SocketAddr>() {
+                    Ok(x) => Some(x),
+                    Err(e) => {
+                        error!("Error parsing peer address {}: {}", peer, e);
+                        None
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // start the blockchain, mempool, p2p server, worker and miner, and connect to known peers
+    let node = Node::start(NodeConfig {
+        listen_addr: p2p_addr,
+        seed_peers,
+        miner_lambda: None,
+        p2p_workers,
+        min_peers,
+        block_msgs_burst,
+        block_msgs_per_sec,
+        relay_fanout,
+        max_msg_bytes,
+        tip_reannounce_ms,
+        ..Default::default()
+    })
+    .unwrap_or_else(|e| {
+        error!("Error starting the node: {}", e);
+        process::exit(1);
+    });
 
     // start the API server
-    ApiServer::start(api_addr, &miner, &server, &blockchain);
+    ApiServer::start(
+        api_addr,
+        &node.miner,
+        &node.server,
+        &node.blockchain,
+        &node.mempool,
+        &node.sync_tracker,
+        &node.network_metrics,
+    );
 
     loop {
         std::thread::park();
@@ -0,0 +1,704 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::blockchain::{Blockchain, ReorgInfo};
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::Hashable;
+use crate::types::hash::H256;
+use crate::types::state::State;
+use crate::types::transaction::TxValidationError;
+use crate::types::transaction::{SignedTransaction, SignedTransactionBatch};
+
+/// How long a transaction may stay in mempool without being mined
+pub const TX_TTL_MS: u128 = 10 * 60 * 1000;
+/// Interval between two evictions of expired transactions
+pub const EVICT_INTERVAL_MS: u64 = 30 * 1000;
+/// Max number of transactions kept in mempool by default
+pub const DEFAULT_MAX_SIZE: usize = 10000;
+/// Max number of transactions waiting for the transaction of their previous nonce
+pub const DEFAULT_MAX_ORPHANS: usize = 1000;
+/// Max number of transaction batches kept in mempool by default
+pub const DEFAULT_MAX_BATCHES: usize = 1000;
+/// How much higher the fee of a transaction has to be to replace the pending one of same nonce
+pub const MIN_FEE_BUMP: u64 = 1;
+
+/// Valid transactions which have not been included in the blockchain yet
+pub struct Mempool {
+    pub hash_to_tx: HashMap<H256, SignedTransaction>, // in-memory storage, keyed by txid
+    pub hash_to_ingest_time: HashMap<H256, u128>,     // unix time in millis of insertion
+    pub max_size: usize, // when full, a lower-fee transaction gives way to a higher-fee one
+    pub sender_nonce_to_hash: HashMap<(Address, u64), H256>,
+    // waiting for the previous nonce, with their ingest time in millis
+    pub orphans: HashMap<(Address, u64), (SignedTransaction, u128)>,
+    pub max_orphans: usize,
+    // batches of transactions signed together, by hash, with their ingest time in millis
+    pub batches: HashMap<H256, (SignedTransactionBatch, u128)>,
+    pub max_batches: usize,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mempool {
+    /// Create an empty mempool
+    pub fn new() -> Self {
+        Self::with_max_size(DEFAULT_MAX_SIZE)
+    }
+
+    /// Create an empty mempool holding at most `max_size` transactions
+    pub fn with_max_size(max_size: usize) -> Self {
+        Mempool {
+            hash_to_tx: HashMap::new(),
+            hash_to_ingest_time: HashMap::new(),
+            max_size,
+            sender_nonce_to_hash: HashMap::new(),
+            orphans: HashMap::new(),
+            max_orphans: DEFAULT_MAX_ORPHANS,
+            batches: HashMap::new(),
+            max_batches: DEFAULT_MAX_BATCHES,
+        }
+    }
+
+    /// Insert a transaction into mempool, return false if it or another one with the same sender
+    /// and nonce is already there, or if mempool is full of transactions paying no lower fee
+    // Assumption: the transaction is already validated
+    pub fn insert(&mut self, tx: &SignedTransaction) -> bool {
+        self.insert_at(tx, now_ms())
+    }
+
+    /// Insert a transaction into mempool with the given ingest time in millis
+    pub fn insert_at(&mut self, tx: &SignedTransaction, now_ms: u128) -> bool {
+        let txid = tx.txid();
+        let transaction = tx.transaction();
+        let key = (transaction.sender(), transaction.nonce());
+        if self.hash_to_tx.contains_key(&txid) || self.sender_nonce_to_hash.contains_key(&key) {
+            return false;
+        }
+        if self.hash_to_tx.len() >= self.max_size {
+            // evict the lowest-fee transaction only for a higher-fee one
+            let lowest_fee_tx = self
+                .hash_to_tx
+                .iter()
+                .min_by_key(|(_, tx)| tx.transaction().fee())
+                .map(|(hash, tx)| (*hash, tx.transaction().fee()));
+            match lowest_fee_tx {
+                Some((hash, fee)) if fee < tx.transaction().fee() => {
+                    self.remove(&hash);
+                }
+                _ => return false,
+            }
+        }
+        self.sender_nonce_to_hash.insert(key, txid);
+        self.hash_to_tx.insert(txid, tx.clone());
+        self.hash_to_ingest_time.insert(txid, now_ms);
+        true
+    }
+
+    /// Insert a transaction if its nonce follows the sender's nonce in the state or a pending
+    /// transaction, otherwise keep it as an orphan until the missing nonces arrive. Return the
+    /// txids inserted into mempool, including the orphans it unblocks, in nonce order.
+    // Assumption: the transaction is already validated
+    pub fn insert_or_orphan(&mut self, tx: &SignedTransaction, state: &State) -> Vec<H256> {
+        self.insert_or_orphan_at(tx, state, now_ms())
+    }
+
+    /// Insert a transaction or keep it as an orphan, as by `insert_or_orphan`, with the given
+    /// ingest time in millis
+    pub fn insert_or_orphan_at(
+        &mut self,
+        tx: &SignedTransaction,
+        state: &State,
+        now_ms: u128,
+    ) -> Vec<H256> {
+        let sender = tx.transaction().sender();
+        let nonce = tx.transaction().nonce();
+        let account_nonce = state.account(&sender).nonce;
+        if nonce < account_nonce {
+            // already applied
+            return vec![];
+        }
+        if nonce > account_nonce && !self.sender_nonce_to_hash.contains_key(&(sender, nonce - 1)) {
+            if self.orphans.len() < self.max_orphans {
+                self.orphans.insert((sender, nonce), (tx.clone(), now_ms));
+            }
+            return vec![];
+        }
+
+        if self.sender_nonce_to_hash.contains_key(&(sender, nonce)) {
+            return if self.replace_by_fee(tx.clone()) {
+                vec![tx.txid()]
+            } else {
+                vec![]
+            };
+        }
+        let mut inserted_vec = vec![];
+        if !self.insert_at(tx, now_ms) {
+            return inserted_vec;
+        }
+        inserted_vec.push(tx.txid());
+        let mut next_nonce = nonce.checked_add(1);
+        while let Some((orphan, _)) = next_nonce.and_then(|n| self.orphans.remove(&(sender, n))) {
+            if !self.insert_at(&orphan, now_ms) {
+                break;
+            }
+            inserted_vec.push(orphan.txid());
+            next_nonce = next_nonce.and_then(|n| n.checked_add(1));
+        }
+        inserted_vec
+    }
+
+    /// Insert a batch of transactions, return false if it is already there or mempool is full of
+    /// batches. Its transactions apply all together or not at all, so it is kept apart from the
+    /// single ones, and conflicts with them are left to the miner.
+    // Assumption: the batch is already validated
+    pub fn insert_batch(&mut self, batch: &SignedTransactionBatch) -> bool {
+        self.insert_batch_at(batch, now_ms())
+    }
+
+    /// Insert a batch of transactions with the given ingest time in millis
+    pub fn insert_batch_at(&mut self, batch: &SignedTransactionBatch, now_ms: u128) -> bool {
+        let hash = batch.hash();
+        if self.batches.contains_key(&hash) || self.batches.len() >= self.max_batches {
+            return false;
+        }
+        self.batches.insert(hash, (batch.clone(), now_ms));
+        true
+    }
+
+    pub fn contains_batch(&self, hash: &H256) -> bool {
+        self.batches.contains_key(hash)
+    }
+
+    pub fn remove_batch(&mut self, hash: &H256) -> Option<SignedTransactionBatch> {
+        self.batches.remove(hash).map(|(batch, _)| batch)
+    }
+
+    /// Iterate over the pending batches, in no particular order
+    pub fn iter_batches(&self) -> impl Iterator<Item = &SignedTransactionBatch> {
+        self.batches.values().map(|(batch, _)| batch)
+    }
+
+    /// Get the nonce of an account's next transaction: the nonce in the state, after the pending
+    /// transactions following it
+    pub fn next_nonce(&self, addr: &Address, state: &State) -> u64 {
+        let mut nonce = state.account(addr).nonce;
+        while self.sender_nonce_to_hash.contains_key(&(*addr, nonce)) {
+            nonce += 1;
+        }
+        nonce
+    }
+
+    /// Get an account's pending transactions which follow the nonce in the state, in nonce order
+    /// up to `before_nonce` excluded
+    pub fn pending_before(
+        &self,
+        addr: &Address,
+        state: &State,
+        before_nonce: u64,
+    ) -> Vec<&SignedTransaction> {
+        (state.account(addr).nonce..before_nonce)
+            .map_while(|nonce| self.sender_nonce_to_hash.get(&(*addr, nonce)))
+            .map(|hash| &self.hash_to_tx[hash])
+            .collect()
+    }
+
+    /// Replace the pending transaction of the same sender and nonce, if the new one pays at least
+    /// `MIN_FEE_BUMP` more fee. Return whether it is replaced.
+    // Assumption: the transaction is already validated
+    pub fn replace_by_fee(&mut self, tx: SignedTransaction) -> bool {
+        let transaction = tx.transaction();
+        let key = (transaction.sender(), transaction.nonce());
+        let pending_hash = match self.sender_nonce_to_hash.get(&key) {
+            Some(hash) if *hash != tx.txid() => *hash,
+            _ => return false,
+        };
+        let pending_fee = self.hash_to_tx[&pending_hash].transaction().fee();
+        match pending_fee.checked_add(MIN_FEE_BUMP) {
+            Some(min_fee) if transaction.fee() >= min_fee => {}
+            _ => return false,
+        }
+        self.remove(&pending_hash);
+        self.insert(&tx)
+    }
+
+    /// Return to mempool the transactions of the blocks a reorg disconnects, which are not in the
+    /// connected blocks and are still accepted on the new tip, oldest first. A transaction of a
+    /// later nonce than the account's waits for the one before it, as by `insert_or_orphan`. The
+    /// batches none of whose transactions are connected are returned too. Return the txids
+    /// inserted into mempool.
+    pub fn replay_reorg(&mut self, blockchain: &Blockchain, reorg: &ReorgInfo) -> Vec<H256> {
+        let tip = blockchain.tip();
+        let tip_state = blockchain.tip_state();
+        let connected_txids: HashSet<H256> = reorg
+            .connected
+            .iter()
+            .filter_map(|hash| blockchain.hash_to_block.get(hash))
+            .flat_map(|block| block.content().transactions().map(|tx| tx.hash()))
+            .collect();
+
+        let mut inserted_vec = vec![];
+        for hash in reorg.disconnected.iter().rev() {
+            let block = match blockchain.hash_to_block.get(hash) {
+                Some(block) => block,
+                None => continue,
+            };
+            for tx in block.content().data.iter() {
+                if tx.is_coinbase() || connected_txids.contains(&tx.txid()) {
+                    continue;
+                }
+                let nonce_ahead =
+                    tx.transaction().nonce() > tip_state.account(&tx.transaction().sender()).nonce;
+                match blockchain.would_accept_tx(tx, &tip) {
+                    Ok(()) => {}
+                    Err(TxValidationError::BadNonce) if nonce_ahead => {}
+                    Err(_) => continue,
+                }
+                inserted_vec.extend(self.insert_or_orphan(tx, tip_state));
+            }
+            // a batch applies all together, which the miner checks
+            for batch in block.content().batches.iter() {
+                let txids = batch.txids();
+                if txids.iter().any(|txid| connected_txids.contains(txid)) {
+                    continue;
+                }
+                if self.insert_batch(batch) {
+                    inserted_vec.extend(txids);
+                }
+            }
+        }
+        inserted_vec
+    }
+
+    /// Check the orphans against the tip state once blocks connect: drop those of an applied nonce,
+    /// and insert those following the sender's nonce or a pending transaction, with the orphans
+    /// they unblock. Return the txids inserted into mempool.
+    pub fn update_orphans(&mut self, state: &State) -> Vec<H256> {
+        self.orphans
+            .retain(|(sender, nonce), _| *nonce >= state.account(sender).nonce);
+        let ready_vec: Vec<(Address, u64)> = self
+            .orphans
+            .keys()
+            .filter(|(sender, nonce)| {
+                *nonce == state.account(sender).nonce
+                    || self
+                        .sender_nonce_to_hash
+                        .contains_key(&(*sender, nonce - 1))
+            })
+            .cloned()
+            .collect();
+        let mut inserted_vec = vec![];
+        for key in ready_vec.iter() {
+            // it may be unblocked by a ready one before
+            if let Some((orphan, ingest_ms)) = self.orphans.remove(key) {
+                inserted_vec.extend(self.insert_or_orphan_at(&orphan, state, ingest_ms));
+            }
+        }
+        inserted_vec
+    }
+
+    /// Check whether a transaction is waiting for the one of its previous nonce
+    pub fn contains_orphan(&self, tx: &SignedTransaction) -> bool {
+        let transaction = tx.transaction();
+        self.orphans
+            .get(&(transaction.sender(), transaction.nonce()))
+            .is_some_and(|(orphan, _)| orphan.txid() == tx.txid())
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.hash_to_tx.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&SignedTransaction> {
+        self.hash_to_tx.get(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hash_to_tx.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hash_to_tx.is_empty()
+    }
+
+    /// Get a copy of all pending transactions, in no particular order
+    // It clones every transaction while the mempool is locked, so keep it for APIs and debugging,
+    // the miner borrows them through `iter` instead
+    pub fn snapshot(&self) -> Vec<SignedTransaction> {
+        self.hash_to_tx.values().cloned().collect()
+    }
+
+    /// Iterate over the pending transactions, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &SignedTransaction> {
+        self.hash_to_tx.values()
+    }
+
+    pub fn remove(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        self.hash_to_ingest_time.remove(hash);
+        let tx = self.hash_to_tx.remove(hash)?;
+        let key = (tx.transaction().sender(), tx.transaction().nonce());
+        if self.sender_nonce_to_hash.get(&key) == Some(hash) {
+            self.sender_nonce_to_hash.remove(&key);
+        }
+        Some(tx)
+    }
+
+    /// Remove the transactions and batches of a block joining the longest chain, return how many
+    /// are removed
+    pub fn remove_confirmed(&mut self, block: &Block) -> usize {
+        let removed_txs = block
+            .content()
+            .transactions()
+            .filter(|tx| self.remove(&tx.hash()).is_some())
+            .count();
+        let removed_batches = block
+            .content()
+            .batches
+            .iter()
+            .filter(|batch| self.remove_batch(&batch.hash()).is_some())
+            .count();
+        removed_txs + removed_batches
+    }
+
+    /// Remove transactions, orphans and batches inserted more than `ttl_ms` before `now_ms`, return
+    /// how many are removed
+    pub fn evict_expired(&mut self, now_ms: u128, ttl_ms: u128) -> usize {
+        let expired: Vec<H256> = self
+            .hash_to_ingest_time
+            .iter()
+            .filter(|(_, ingest_ms)| now_ms.saturating_sub(**ingest_ms) > ttl_ms)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired.iter() {
+            self.remove(hash);
+        }
+        let kept_count = self.orphans.len() + self.batches.len();
+        self.orphans
+            .retain(|_, (_, ingest_ms)| now_ms.saturating_sub(*ingest_ms) <= ttl_ms);
+        self.batches
+            .retain(|_, (_, ingest_ms)| now_ms.saturating_sub(*ingest_ms) <= ttl_ms);
+        expired.len() + kept_count - self.orphans.len() - self.batches.len()
+    }
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mempool;
+    use crate::types::address::Address;
+    use crate::types::state::State;
+    use crate::types::transaction::{
+        generate_random_signed_transaction, generate_signed_transaction, SignedTransaction,
+        Transaction,
+    };
+
+    fn signed_transaction_with_fee(nonce: u64, fee: u64) -> SignedTransaction {
+        let alice: Address = [1u8; 20].into();
+        let bob: Address = [2u8; 20].into();
+        generate_signed_transaction(Transaction::new(alice, bob, 1, fee, nonce))
+    }
+
+    #[test]
+    fn evict_expired_transactions() {
+        let mut mempool = Mempool::new();
+        let old_tx = generate_random_signed_transaction();
+        let new_tx = generate_random_signed_transaction();
+        assert!(mempool.insert_at(&old_tx, 1_000));
+        assert!(mempool.insert_at(&new_tx, 5_000));
+
+        // nothing is older than the TTL yet
+        assert_eq!(mempool.evict_expired(4_000, 3_000), 0);
+        assert!(mempool.contains(&old_tx.txid()));
+
+        // the clock advances past the TTL of the first transaction only
+        assert_eq!(mempool.evict_expired(4_001, 3_000), 1);
+        assert!(!mempool.contains(&old_tx.txid()));
+        assert!(mempool.contains(&new_tx.txid()));
+        assert!(!mempool.hash_to_ingest_time.contains_key(&old_tx.txid()));
+    }
+
+    #[test]
+    fn evict_expired_orphans() {
+        let alice: Address = [1u8; 20].into();
+        let bob: Address = [2u8; 20].into();
+        let state = State::new();
+        let mut mempool = Mempool::new();
+        let old_orphan = generate_signed_transaction(Transaction::new(alice, bob, 1, 0, 2));
+        let new_orphan = generate_signed_transaction(Transaction::new(alice, bob, 1, 0, 3));
+        mempool.insert_or_orphan_at(&old_orphan, &state, 1_000);
+        mempool.insert_or_orphan_at(&new_orphan, &state, 5_000);
+
+        assert_eq!(mempool.evict_expired(4_000, 3_000), 0);
+        assert_eq!(mempool.evict_expired(4_001, 3_000), 1);
+        assert!(!mempool.contains_orphan(&old_orphan));
+        assert!(mempool.contains_orphan(&new_orphan));
+    }
+
+    #[test]
+    fn insert_and_evict_batches() {
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::hash::Hashable;
+        use crate::types::transaction::sign_batch;
+
+        let (alice, alice_key) = random_address_with_keypair();
+        let bob: Address = [2u8; 20].into();
+        let mut mempool = Mempool::new();
+        let old_batch = sign_batch(vec![Transaction::new(alice, bob, 1, 0, 0)], &alice_key);
+        let new_batch = sign_batch(vec![Transaction::new(alice, bob, 2, 0, 0)], &alice_key);
+        assert!(mempool.insert_batch_at(&old_batch, 1_000));
+        assert!(!mempool.insert_batch_at(&old_batch, 1_000));
+        assert!(mempool.insert_batch_at(&new_batch, 5_000));
+        assert_eq!(mempool.iter_batches().count(), 2);
+        // batches are kept apart from the single transactions
+        assert!(mempool.is_empty());
+
+        assert_eq!(mempool.evict_expired(4_001, 3_000), 1);
+        assert!(!mempool.contains_batch(&old_batch.hash()));
+        assert!(mempool.contains_batch(&new_batch.hash()));
+        assert!(mempool.remove_batch(&new_batch.hash()).is_some());
+        assert_eq!(mempool.iter_batches().count(), 0);
+    }
+
+    #[test]
+    fn evict_lowest_fee_when_full() {
+        let mut mempool = Mempool::with_max_size(2);
+        let low_fee_tx = signed_transaction_with_fee(0, 1);
+        let mid_fee_tx = signed_transaction_with_fee(1, 5);
+        let high_fee_tx = signed_transaction_with_fee(2, 9);
+        assert!(mempool.insert(&low_fee_tx));
+        assert!(mempool.insert(&mid_fee_tx));
+
+        assert!(mempool.insert(&high_fee_tx));
+        assert_eq!(mempool.hash_to_tx.len(), 2);
+        assert!(!mempool.contains(&low_fee_tx.txid()));
+        assert!(mempool.contains(&mid_fee_tx.txid()));
+        assert!(mempool.contains(&high_fee_tx.txid()));
+        assert!(!mempool.hash_to_ingest_time.contains_key(&low_fee_tx.txid()));
+    }
+
+    #[test]
+    fn reject_lower_fee_when_full() {
+        let mut mempool = Mempool::with_max_size(2);
+        let mid_fee_tx = signed_transaction_with_fee(0, 5);
+        let high_fee_tx = signed_transaction_with_fee(1, 9);
+        assert!(mempool.insert(&mid_fee_tx));
+        assert!(mempool.insert(&high_fee_tx));
+
+        // neither a lower nor an equal fee replaces the lowest-fee transaction
+        assert!(!mempool.insert(&signed_transaction_with_fee(2, 1)));
+        assert!(!mempool.insert(&signed_transaction_with_fee(3, 5)));
+        assert_eq!(mempool.hash_to_tx.len(), 2);
+        assert!(mempool.contains(&mid_fee_tx.txid()));
+        assert!(mempool.contains(&high_fee_tx.txid()));
+    }
+
+    #[test]
+    fn snapshot_of_pending_transactions() {
+        let mut mempool = Mempool::new();
+        assert!(mempool.is_empty());
+        let tx_vec: Vec<SignedTransaction> = (0..3)
+            .map(|_| generate_random_signed_transaction())
+            .collect();
+        for tx in tx_vec.iter() {
+            assert!(mempool.insert(tx));
+        }
+
+        assert_eq!(mempool.len(), 3);
+        assert!(!mempool.is_empty());
+        let snapshot = mempool.snapshot();
+        assert_eq!(snapshot.len(), tx_vec.len());
+        for tx in tx_vec.iter() {
+            assert!(snapshot.iter().any(|pending| pending.txid() == tx.txid()));
+        }
+    }
+
+    #[test]
+    fn promote_orphan_after_previous_nonce() {
+        let alice: Address = [1u8; 20].into();
+        let bob: Address = [2u8; 20].into();
+        let mut state = State::new();
+        state.credit(&alice, 100);
+        state
+            .apply_transaction(&Transaction::new(alice, bob, 10, 0, 0))
+            .unwrap();
+        let mut mempool = Mempool::new();
+        let tx_1 = generate_signed_transaction(Transaction::new(alice, bob, 10, 0, 1));
+        let tx_2 = generate_signed_transaction(Transaction::new(alice, bob, 10, 0, 2));
+
+        // nonce 2 waits for nonce 1
+        assert!(mempool.insert_or_orphan(&tx_2, &state).is_empty());
+        assert!(!mempool.contains(&tx_2.txid()));
+        assert!(mempool.contains_orphan(&tx_2));
+        assert_eq!(
+            mempool.insert_or_orphan(&tx_1, &state),
+            vec![tx_1.txid(), tx_2.txid()]
+        );
+        assert!(mempool.orphans.is_empty());
+
+        // both apply in nonce order
+        for tx in [tx_1, tx_2].iter() {
+            let pending = mempool.get(&tx.txid()).unwrap();
+            assert_eq!(state.apply_transaction(pending.transaction()), Ok(()));
+        }
+        // an applied nonce is not pending again
+        let stale_tx = generate_signed_transaction(Transaction::new(alice, bob, 10, 0, 0));
+        assert!(mempool.insert_or_orphan(&stale_tx, &state).is_empty());
+        assert!(!mempool.contains_orphan(&stale_tx));
+    }
+
+    #[test]
+    fn update_orphans_on_connected_blocks() {
+        let alice: Address = [1u8; 20].into();
+        let bob: Address = [2u8; 20].into();
+        let mut state = State::new();
+        state.credit(&alice, 100);
+        let mut mempool = Mempool::new();
+        let tx_vec: Vec<SignedTransaction> = (0..4)
+            .map(|nonce| generate_signed_transaction(Transaction::new(alice, bob, 10, 0, nonce)))
+            .collect();
+        for tx in tx_vec[1..].iter() {
+            assert!(mempool.insert_or_orphan(tx, &state).is_empty());
+        }
+
+        // a block applies nonces 0 and 1, which never reached this mempool
+        for tx in tx_vec[..2].iter() {
+            state.apply_transaction(tx.transaction()).unwrap();
+        }
+        assert_eq!(
+            mempool.update_orphans(&state),
+            vec![tx_vec[2].txid(), tx_vec[3].txid()]
+        );
+        assert!(mempool.orphans.is_empty());
+        assert!(mempool.contains(&tx_vec[2].txid()));
+        assert!(mempool.contains(&tx_vec[3].txid()));
+        assert!(!mempool.contains(&tx_vec[1].txid()));
+    }
+
+    #[test]
+    fn bound_orphan_pool() {
+        let alice: Address = [1u8; 20].into();
+        let bob: Address = [2u8; 20].into();
+        let state = State::new();
+        let mut mempool = Mempool::new();
+        mempool.max_orphans = 1;
+        let orphan_1 = generate_signed_transaction(Transaction::new(alice, bob, 1, 0, 5));
+        let orphan_2 = generate_signed_transaction(Transaction::new(alice, bob, 1, 0, 7));
+        mempool.insert_or_orphan(&orphan_1, &state);
+        mempool.insert_or_orphan(&orphan_2, &state);
+        assert!(mempool.contains_orphan(&orphan_1));
+        assert!(!mempool.contains_orphan(&orphan_2));
+    }
+
+    #[test]
+    fn replace_pending_by_higher_fee() {
+        let mut mempool = Mempool::new();
+        let low_fee_tx = signed_transaction_with_fee(0, 5);
+        // another value, so that it is another transaction
+        let same_fee_tx = generate_signed_transaction(Transaction::new(
+            [1u8; 20].into(),
+            [2u8; 20].into(),
+            2,
+            5,
+            0,
+        ));
+        let high_fee_tx = signed_transaction_with_fee(0, 6);
+        assert!(mempool.insert(&low_fee_tx));
+        // a second transaction of the same nonce only gets in by replacement
+        assert!(!mempool.insert(&high_fee_tx));
+
+        assert!(!mempool.replace_by_fee(same_fee_tx.clone()));
+        assert!(mempool.contains(&low_fee_tx.txid()));
+        assert!(mempool.replace_by_fee(high_fee_tx.clone()));
+        assert!(!mempool.contains(&low_fee_tx.txid()));
+        assert!(mempool.contains(&high_fee_tx.txid()));
+        assert_eq!(mempool.len(), 1);
+        // nothing to replace at another nonce
+        assert!(!mempool.replace_by_fee(signed_transaction_with_fee(1, 9)));
+    }
+
+    #[test]
+    fn next_nonce_after_pending_transactions() {
+        let alice: Address = [1u8; 20].into();
+        let bob: Address = [2u8; 20].into();
+        let mut state = State::new();
+        state.credit(&alice, 100);
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.next_nonce(&alice, &state), 0);
+
+        // one confirmed, one pending
+        state
+            .apply_transaction(&Transaction::new(alice, bob, 10, 0, 0))
+            .unwrap();
+        let pending_tx = generate_signed_transaction(Transaction::new(alice, bob, 10, 0, 1));
+        mempool.insert_or_orphan(&pending_tx, &state);
+        assert_eq!(mempool.next_nonce(&alice, &state), 2);
+        assert_eq!(mempool.next_nonce(&bob, &state), 0);
+
+        // an orphan after a gap is not pending yet
+        let orphan_tx = generate_signed_transaction(Transaction::new(alice, bob, 10, 0, 3));
+        mempool.insert_or_orphan(&orphan_tx, &state);
+        assert_eq!(mempool.next_nonce(&alice, &state), 2);
+    }
+
+    #[test]
+    fn replay_transactions_of_disconnected_block() {
+        use crate::blockchain::{Blockchain, GenesisParams};
+        use crate::types::address::address_from_seed;
+        use crate::types::block::{generate_random_block, Block};
+        use crate::types::hash::{Hashable, H256};
+        use crate::types::merkle::MerkleTree;
+        use crate::types::transaction::sign_transaction;
+
+        fn block_with_txs(parent: &H256, txs: Vec<SignedTransaction>) -> Block {
+            let mut block = generate_random_block(parent);
+            block.content_mut().data = txs;
+            block.header.merkle_root = MerkleTree::new(&block.content().data).root();
+            block
+        }
+
+        let (alice, alice_key) = address_from_seed(1);
+        let (carol, carol_key) = address_from_seed(3);
+        let bob: Address = [2u8; 20].into();
+        let mut blockchain = Blockchain::with_params(GenesisParams {
+            premine: vec![(alice, 100), (carol, 100)],
+            ..Default::default()
+        });
+        let genesis_hash = blockchain.tip();
+        let alice_tx = sign_transaction(Transaction::new(alice, bob, 10, 0, 0), &alice_key);
+        let alice_next_tx = sign_transaction(Transaction::new(alice, bob, 10, 0, 1), &alice_key);
+        let carol_tx = sign_transaction(Transaction::new(carol, bob, 10, 0, 0), &carol_key);
+        let a1 = block_with_txs(
+            &genesis_hash,
+            vec![alice_tx.clone(), alice_next_tx.clone(), carol_tx.clone()],
+        );
+        assert_eq!(blockchain.insert(&a1), None);
+
+        // the heavier fork spends carol's nonce differently
+        let carol_other_tx = sign_transaction(Transaction::new(carol, bob, 20, 0, 0), &carol_key);
+        let b1 = block_with_txs(&genesis_hash, vec![carol_other_tx]);
+        // of equal work, b1 may already be the tip for its smaller hash
+        let b1_reorg = blockchain.insert(&b1);
+        let mut raw_bytes = [255u8; 32];
+        raw_bytes[0] = 0;
+        let mut b2 = block_with_txs(&b1.hash(), vec![]);
+        b2.header.difficulty = H256::from(raw_bytes);
+        let reorg = blockchain.insert(&b2).or(b1_reorg).unwrap();
+
+        let mut mempool = Mempool::new();
+        assert_eq!(
+            mempool.replay_reorg(&blockchain, &reorg),
+            vec![alice_tx.txid(), alice_next_tx.txid()]
+        );
+        assert!(!mempool.contains(&carol_tx.txid()));
+        assert_eq!(mempool.len(), 2);
+    }
+}
@@ -1,35 +1,93 @@
 pub mod worker;
 
-use log::info;
+use log::{info, warn};
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time;
 
 use std::thread;
-use std::time::SystemTime;
-use std::time::UNIX_EPOCH;
 
-use crate::blockchain::Blockchain;
+#[cfg(any(test, test_utilities))]
+use crate::blockchain::GenesisParams;
+use crate::blockchain::{Blockchain, BLOCK_REWARD, MAX_BLOCK_BYTES, MAX_TXS_PER_BLOCK};
+use crate::mempool::Mempool;
+use crate::network::sync::SyncTracker;
+use crate::types::address::Address;
 use crate::types::block::Block;
 use crate::types::block::Content;
 use crate::types::block::Header;
-use crate::types::hash::Hashable;
-use crate::types::merkle::MerkleTree;
+use crate::types::clock::{Clock, SystemClock};
+use crate::types::hash::{Hashable, H256};
+use crate::types::state::State;
+use crate::types::transaction::{coinbase_transaction, SignedTransaction, SignedTransactionBatch};
 
 enum ControlSignal {
-    Start(u64), // the number controls the lambda of interval between block generation
-    Update,     // update the block in mining, it may due to new blockchain tip or new transaction
+    Start(u64),   // the number controls the lambda of interval between block generation
+    MineN(u64),   // mine the given number of blocks without interval, then pause
+    Update,       // update the block in mining, it may due to new blockchain tip or new transaction
+    MineOn(H256), // mine the next block on the given parent rather than the tip, e.g. for forks
     Exit,
 }
 
 enum OperatingState {
     Paused,
     Run(u64),
+    RunN(u64),           // the number of blocks left to mine before pausing
+    WaitingForSync(u64), // the lambda to run with once caught up with peers
     ShutDown,
 }
 
+/// How often a miner waiting for sync checks the sync status again
+const SYNC_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+/// How often the block in mining is rebuilt, to pick up new transactions and notice a parent
+/// pruned from the blockchain
+const CONTENT_REFRESH_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// Max number of nonces tried for a block by test miners, so that a difficulty too hard to mine
+/// fails the test fast rather than hanging it
+#[cfg(any(test, test_utilities))]
+const MAX_TEST_ATTEMPTS: u64 = 100_000;
+
+/// Max number of found blocks waiting for their broadcast to be timed, blocks found beyond it are
+/// not timed, e.g. without a miner worker
+const MAX_TIMED_BLOCKS: usize = 100;
+
+/// Latency from finding a block to broadcasting it, shared by the miner and the miner worker
+#[derive(Default)]
+pub struct MiningLatency {
+    found_at: Mutex<HashMap<H256, time::Instant>>,
+    last: Mutex<Option<time::Duration>>,
+}
+
+impl MiningLatency {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Start timing a block when it is found
+    pub fn record_found(&self, hash: H256) {
+        let mut found_at = self.found_at.lock().unwrap();
+        if found_at.len() < MAX_TIMED_BLOCKS {
+            found_at.insert(hash, time::Instant::now());
+        }
+    }
+
+    /// Stop timing a block when it is broadcast, return its latency if it was timed
+    pub fn record_broadcast(&self, hash: &H256) -> Option<time::Duration> {
+        let latency = self.found_at.lock().unwrap().remove(hash)?.elapsed();
+        *self.last.lock().unwrap() = Some(latency);
+        Some(latency)
+    }
+
+    /// Get the latency of the latest block broadcast
+    pub fn last(&self) -> Option<time::Duration> {
+        *self.last.lock().unwrap()
+    }
+}
+
 /// Miner thread context
 pub struct Context {
     /// Channel for receiving control signal from API server
@@ -37,6 +95,12 @@ pub struct Context {
     operating_state: OperatingState,
     finished_block_chan: Sender<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    sync_tracker: Arc<Mutex<SyncTracker>>,
+    clock: Arc<dyn Clock>,   // for block timestamps
+    reward_address: Address, // receiver of the coinbase of mined blocks
+    latency: Arc<MiningLatency>,
+    max_attempts: Option<u64>, // nonces tried for a block before shutting down, unlimited if None
 }
 
 #[derive(Clone)]
@@ -45,7 +109,21 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>) -> (Context, Handle, Receiver<Block>) {
+pub fn new(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    sync_tracker: &Arc<Mutex<SyncTracker>>,
+) -> (Context, Handle, Receiver<Block>) {
+    new_with_clock(blockchain, mempool, sync_tracker, Arc::new(SystemClock))
+}
+
+/// Create a miner taking block timestamps from the given time source
+pub fn new_with_clock(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    sync_tracker: &Arc<Mutex<SyncTracker>>,
+    clock: Arc<dyn Clock>,
+) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
 
@@ -54,6 +132,12 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>) -> (Context, Handle, Receiver<Bl
         operating_state: OperatingState::Paused,
         finished_block_chan: finished_block_sender,
         blockchain: Arc::clone(blockchain),
+        mempool: Arc::clone(mempool),
+        sync_tracker: Arc::clone(sync_tracker),
+        clock,
+        reward_address: Address::default(),
+        latency: Arc::new(MiningLatency::new()),
+        max_attempts: None,
     };
 
     let handle = Handle {
@@ -63,11 +147,122 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>) -> (Context, Handle, Receiver<Bl
     (ctx, handle, finished_block_receiver)
 }
 
+/// Difficulty for mining in tests, met by one header in 16 on average. It is harder than the
+/// default genesis target, which every header meets, so that tests exercise the PoW search.
+#[cfg(any(test, test_utilities))]
+pub fn easy_difficulty() -> H256 {
+    let mut bytes = [255u8; 32];
+    bytes[0] = 0x0f;
+    bytes.into()
+}
+
 #[cfg(any(test, test_utilities))]
 fn test_new() -> (Context, Handle, Receiver<Block>) {
-    let blockchain = Blockchain::new();
+    test_new_with_difficulty(easy_difficulty())
+}
+
+#[cfg(any(test, test_utilities))]
+fn test_new_with_difficulty(difficulty: H256) -> (Context, Handle, Receiver<Block>) {
+    let blockchain = Blockchain::with_params(GenesisParams {
+        difficulty,
+        ..Default::default()
+    });
     let blockchain = Arc::new(Mutex::new(blockchain));
-    new(&blockchain)
+    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
+    let (mut ctx, handle, finished_block_chan) = new(&blockchain, &mempool, &sync_tracker);
+    ctx.max_attempts = Some(MAX_TEST_ATTEMPTS);
+    (ctx, handle, finished_block_chan)
+}
+
+/// Order the transactions of each sender by ascending nonce, from the sender's nonce in the state
+/// on, dropping those after a gap. Senders keep the order of their first transaction.
+fn order_by_nonce<'a>(
+    state: &State,
+    candidates: Vec<&'a SignedTransaction>,
+) -> Vec<&'a SignedTransaction> {
+    let mut sender_rank: HashMap<Address, usize> = HashMap::new();
+    for tx in candidates.iter() {
+        let rank = sender_rank.len();
+        sender_rank.entry(tx.transaction().sender()).or_insert(rank);
+    }
+    let mut candidates = candidates;
+    candidates.sort_by_key(|tx| {
+        let transaction = tx.transaction();
+        (sender_rank[&transaction.sender()], transaction.nonce())
+    });
+
+    let mut next_nonces: HashMap<Address, u64> = HashMap::new();
+    candidates
+        .into_iter()
+        .filter(|tx| {
+            let sender = tx.transaction().sender();
+            let next_nonce = next_nonces
+                .entry(sender)
+                .or_insert_with(|| state.account(&sender).nonce);
+            if tx.transaction().nonce() == *next_nonce {
+                *next_nonce += 1;
+                true
+            } else {
+                false
+            }
+        })
+        .collect()
+}
+
+/// Keep the transactions that apply in order onto the state, dropping those conflicting with the
+/// ones before, e.g. reusing a nonce or spending more than the balance left
+fn drop_conflicts<'a>(
+    state: &State,
+    candidates: Vec<&'a SignedTransaction>,
+) -> Vec<&'a SignedTransaction> {
+    let mut working_state = state.clone();
+    candidates
+        .into_iter()
+        .filter(|tx| working_state.apply_transaction(tx.transaction()).is_ok())
+        .collect()
+}
+
+/// Take the batches applying in order onto the state after the ones before, skipping those which
+/// would exceed `max_bytes` or `max_txs` transactions in total
+fn select_batches<'a>(
+    state: &State,
+    candidates: Vec<&'a SignedTransactionBatch>,
+    max_bytes: usize,
+    max_txs: usize,
+) -> Vec<&'a SignedTransactionBatch> {
+    let mut working_state = state.clone();
+    let (mut total_bytes, mut total_txs) = (0, 0);
+    candidates
+        .into_iter()
+        .filter(|batch| {
+            let (bytes, txs) = (batch.size_bytes(), batch.transactions().len());
+            if total_bytes + bytes > max_bytes
+                || total_txs + txs > max_txs
+                || working_state.apply_batch(batch).is_err()
+            {
+                return false;
+            }
+            total_bytes += bytes;
+            total_txs += txs;
+            true
+        })
+        .collect()
+}
+
+/// Take transactions in order until the next one would exceed `max_bytes` in total
+fn select_transactions(
+    candidates: Vec<&SignedTransaction>,
+    max_bytes: usize,
+) -> Vec<&SignedTransaction> {
+    let mut total_bytes = 0;
+    candidates
+        .into_iter()
+        .take_while(|tx| {
+            total_bytes += tx.size_bytes();
+            total_bytes <= max_bytes
+        })
+        .collect()
 }
 
 impl Handle {
@@ -81,12 +276,34 @@ impl Handle {
             .unwrap();
     }
 
+    pub fn mine_n(&self, count: u64) {
+        self.control_chan.send(ControlSignal::MineN(count)).unwrap();
+    }
+
     pub fn update(&self) {
         self.control_chan.send(ControlSignal::Update).unwrap();
     }
+
+    /// Mine the next block on the given parent, which has to be in the blockchain, rather than on
+    /// the tip
+    pub fn mine_on(&self, parent: H256) {
+        self.control_chan
+            .send(ControlSignal::MineOn(parent))
+            .unwrap();
+    }
 }
 
 impl Context {
+    /// Pay the rewards of mined blocks to the given address, which is the zero address by default
+    pub fn set_reward_address(&mut self, address: Address) {
+        self.reward_address = address;
+    }
+
+    /// Get the latency of mined blocks, to share with the miner worker broadcasting them
+    pub fn latency(&self) -> Arc<MiningLatency> {
+        Arc::clone(&self.latency)
+    }
+
     /// Spawn miner thread in paused mode
     pub fn start(mut self) {
         thread::Builder::new()
@@ -98,17 +315,174 @@ impl Context {
         info!("Miner initialized into paused mode");
     }
 
+    /// Mining on a stale tip wastes work, so continuous mining waits until caught up with peers
+    fn run_state(&self, lambda: u64) -> OperatingState {
+        if self.sync_in_progress() {
+            info!(
+                "Miner waiting for sync before mining with lambda {}",
+                lambda
+            );
+            OperatingState::WaitingForSync(lambda)
+        } else {
+            info!("Miner starting in continuous mode with lambda {}", lambda);
+            OperatingState::Run(lambda)
+        }
+    }
+
+    fn sync_in_progress(&self) -> bool {
+        let current_height = self.blockchain.lock().unwrap().tip_height();
+        let status = self.sync_tracker.lock().unwrap().status(current_height);
+        status.in_progress
+    }
+
+    /// Select the mempool transactions valid on the parent's state and fitting in a block, leaving
+    /// room for the header and the rest of an empty block, after the coinbase paying the reward.
+    /// The batches valid after them are taken in the room left. At most `MAX_TXS_PER_BLOCK`
+    /// transactions are taken, including the coinbase and those in batches.
+    fn block_content(&self, parent: &H256) -> Content {
+        let empty_block = Block::new(
+            Header {
+                parent: H256::default(),
+                nonce: 0,
+                difficulty: H256::default(),
+                timestamp: 0,
+                merkle_root: H256::default(),
+            },
+            Content {
+                data: vec![],
+                batches: vec![],
+            },
+        );
+        let empty_block_bytes = bincode::serialized_size(&empty_block).unwrap() as usize;
+        // the parent may not be inserted by the miner worker yet, its block is then mined empty
+        let parent_state = self
+            .blockchain
+            .lock()
+            .unwrap()
+            .state(parent)
+            .cloned()
+            .unwrap_or_default();
+        let coinbase =
+            coinbase_transaction(self.reward_address, BLOCK_REWARD, parent_state.height() + 1);
+        let max_bytes = MAX_BLOCK_BYTES - empty_block_bytes - coinbase.size_bytes();
+        // only the selected transactions are copied out of the mempool
+        let _mempool = self.mempool.lock().unwrap();
+        let candidates = order_by_nonce(&parent_state, _mempool.iter().collect());
+        let candidates = drop_conflicts(&parent_state, candidates);
+        let selected: Vec<&SignedTransaction> = select_transactions(candidates, max_bytes)
+            .into_iter()
+            .take(MAX_TXS_PER_BLOCK - 1)
+            .collect();
+
+        let mut working_state = parent_state;
+        for tx in selected.iter() {
+            // they apply in order, as checked by `drop_conflicts`
+            let _ = working_state.apply_transaction(tx.transaction());
+        }
+        let selected_bytes: usize = selected.iter().map(|tx| tx.size_bytes()).sum();
+        let batches = select_batches(
+            &working_state,
+            _mempool.iter_batches().collect(),
+            max_bytes - selected_bytes,
+            MAX_TXS_PER_BLOCK - 1 - selected.len(),
+        );
+
+        let mut data = vec![coinbase];
+        data.extend(selected.into_iter().cloned());
+        Content {
+            data,
+            batches: batches.into_iter().cloned().collect(),
+        }
+    }
+
+    /// Check that the parent is in the blockchain, or descends from it through mined blocks the
+    /// miner worker has not inserted yet, and is not on a fork which would be pruned. Mined blocks
+    /// already inserted are forgotten.
+    fn parent_viable(&self, parent: &H256, mined_parents: &mut HashMap<H256, H256>) -> bool {
+        let _blockchain = self.blockchain.lock().unwrap();
+        mined_parents.retain(|hash, _| !_blockchain.hash_to_block.contains_key(hash));
+        let mut hash = *parent;
+        while !_blockchain.hash_to_block.contains_key(&hash) {
+            match mined_parents.get(&hash) {
+                Some(mined_parent) => hash = *mined_parent,
+                None => return false,
+            }
+        }
+        !_blockchain.is_stale_fork(&hash)
+    }
+
+    /// Get the earliest timestamp in millis valid for a child of the parent. Mined blocks the miner
+    /// worker has not inserted yet are each followed by a later timestamp.
+    fn min_timestamp(
+        &self,
+        parent: &H256,
+        mined_parents: &HashMap<H256, H256>,
+        mined_timestamps: &HashMap<H256, u128>,
+    ) -> u128 {
+        let mut min_timestamp = 0;
+        let mut hash = *parent;
+        while let Some(mined_parent) = mined_parents.get(&hash) {
+            if let Some(timestamp) = mined_timestamps.get(&hash) {
+                min_timestamp = min_timestamp.max(timestamp + 1);
+            }
+            hash = *mined_parent;
+        }
+        self.blockchain
+            .lock()
+            .unwrap()
+            .normalize_timestamp(&hash, min_timestamp)
+    }
+
+    fn known_parent(&self, parent: &H256) -> bool {
+        if self
+            .blockchain
+            .lock()
+            .unwrap()
+            .hash_to_block
+            .contains_key(parent)
+        {
+            true
+        } else {
+            warn!("Not mining on unknown parent block {}", parent);
+            false
+        }
+    }
+
+    fn run_n_state(n: u64) -> OperatingState {
+        if n == 0 {
+            OperatingState::Paused
+        } else {
+            OperatingState::RunN(n)
+        }
+    }
+
     fn miner_loop(&mut self) {
         use rand::Rng;
         let mut rng = rand::thread_rng();
 
         let _blockchain = self.blockchain.lock().unwrap();
         let mut _parent_hash = _blockchain.tip();
-        let _difficulty = _blockchain.hash_to_block[&_parent_hash].get_difficulty();
+        let _difficulty = _blockchain.get_header(&_parent_hash).unwrap().difficulty;
 
         // drop the mutex guard for other thread to access
         drop(_blockchain);
 
+        // the content is selected again when the parent changes, and every
+        // `CONTENT_REFRESH_INTERVAL` to pick up new transactions, rather than for every nonce, as
+        // the merkle root is costly for full blocks
+        let mut _content = Content {
+            data: vec![],
+            batches: vec![],
+        };
+        let mut _merkle_root = H256::default();
+        let mut _content_stale = true;
+        let mut _content_refreshed = time::Instant::now();
+        let mut _mined_parents: HashMap<H256, H256> = HashMap::new();
+        let mut _mined_timestamps: HashMap<H256, u128> = HashMap::new();
+        // the clock may not have advanced since the parent was mined, or may be behind it
+        let mut _min_timestamp = 0u128;
+        let mut _attempts = 0u64;
+
         // main mining loop
         loop {
             // check and react to control signals
@@ -121,18 +495,63 @@ impl Context {
                             self.operating_state = OperatingState::ShutDown;
                         }
                         ControlSignal::Start(i) => {
-                            info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
+                            self.operating_state = self.run_state(i);
+                        }
+                        ControlSignal::MineN(n) => {
+                            info!("Miner starting to mine {} blocks", n);
+                            self.operating_state = Self::run_n_state(n);
                         }
                         ControlSignal::Update => {
                             // in paused state, don't need to update
                         }
+                        ControlSignal::MineOn(parent) => {
+                            if self.known_parent(&parent) {
+                                _parent_hash = parent;
+                                _content_stale = true;
+                            }
+                        }
                     };
                     continue;
                 }
                 OperatingState::ShutDown => {
                     return;
                 }
+                OperatingState::WaitingForSync(lambda) => {
+                    match self.control_chan.recv_timeout(SYNC_POLL_INTERVAL) {
+                        Ok(ControlSignal::Exit) => {
+                            info!("Miner shutting down");
+                            self.operating_state = OperatingState::ShutDown;
+                        }
+                        Ok(ControlSignal::Start(i)) => {
+                            self.operating_state = self.run_state(i);
+                        }
+                        Ok(ControlSignal::MineN(n)) => {
+                            info!("Miner starting to mine {} blocks", n);
+                            self.operating_state = Self::run_n_state(n);
+                        }
+                        Ok(ControlSignal::Update) | Err(RecvTimeoutError::Timeout) => {
+                            if !self.sync_in_progress() {
+                                info!("Miner caught up, mining with lambda {}", lambda);
+                                self.operating_state = OperatingState::Run(lambda);
+                            }
+                        }
+                        Ok(ControlSignal::MineOn(parent)) => {
+                            // mine on it once caught up, rather than on the tip reached
+                            if self.known_parent(&parent) {
+                                _parent_hash = parent;
+                                _content_stale = true;
+                            }
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => {
+                            panic!("Miner control channel detached")
+                        }
+                    }
+                    // mine on the tip reached by the sync
+                    _parent_hash = self.blockchain.lock().unwrap().tip();
+                    _content_stale = true;
+                    continue;
+                }
                 _ => match self.control_chan.try_recv() {
                     Ok(signal) => {
                         match signal {
@@ -141,11 +560,21 @@ impl Context {
                                 self.operating_state = OperatingState::ShutDown;
                             }
                             ControlSignal::Start(i) => {
-                                info!("Miner restarting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
+                                self.operating_state = self.run_state(i);
+                            }
+                            ControlSignal::MineN(n) => {
+                                info!("Miner restarting to mine {} blocks", n);
+                                self.operating_state = Self::run_n_state(n);
                             }
                             ControlSignal::Update => {
                                 _parent_hash = self.blockchain.lock().unwrap().tip();
+                                _content_stale = true;
+                            }
+                            ControlSignal::MineOn(parent) => {
+                                if self.known_parent(&parent) {
+                                    _parent_hash = parent;
+                                    _content_stale = true;
+                                }
                             }
                         };
                     }
@@ -158,29 +587,70 @@ impl Context {
             }
 
             // actual mining, create a block
-            let _signed_txs = vec![];
-
-            let _candidate_block = Block {
-                header: Header {
-                    parent: _parent_hash,
-                    difficulty: _difficulty,
-                    timestamp: SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis(),
-                    nonce: rng.gen(),
-                    merkle_root: MerkleTree::new(&_signed_txs).root(),
-                },
-                content: Content { data: _signed_txs },
+            if _content_stale || _content_refreshed.elapsed() >= CONTENT_REFRESH_INTERVAL {
+                if !self.parent_viable(&_parent_hash, &mut _mined_parents) {
+                    let tip = self.blockchain.lock().unwrap().tip();
+                    warn!(
+                        "Parent block {} is pruned, mining on the tip {} instead",
+                        _parent_hash, tip
+                    );
+                    _parent_hash = tip;
+                }
+                _content = self.block_content(&_parent_hash);
+                _merkle_root = _content.merkle_root();
+                _mined_timestamps.retain(|hash, _| _mined_parents.contains_key(hash));
+                _min_timestamp =
+                    self.min_timestamp(&_parent_hash, &_mined_parents, &_mined_timestamps);
+                _content_stale = false;
+                _content_refreshed = time::Instant::now();
+            }
+
+            let _candidate_header = Header {
+                parent: _parent_hash,
+                difficulty: _difficulty,
+                timestamp: self.clock.now_ms().max(_min_timestamp),
+                nonce: rng.gen(),
+                merkle_root: _merkle_root,
             };
 
-            // check if block is successfully generated
-            if _candidate_block.hash() <= _difficulty {
+            // check if block is successfully generated, the block hash is the header's
+            if _candidate_header.hash() <= _difficulty {
+                let _candidate_block = Block::new(_candidate_header, _content.clone());
+                self.latency.record_found(_candidate_block.hash());
                 self.finished_block_chan
                     .send(_candidate_block.clone())
                     .expect("Send finished block error");
 
+                _mined_parents.insert(_candidate_block.hash(), _parent_hash);
+                _mined_timestamps
+                    .insert(_candidate_block.hash(), _candidate_block.header.timestamp);
                 _parent_hash = _candidate_block.hash();
+                let mut _mempool = self.mempool.lock().unwrap();
+                for tx in &_content.data {
+                    _mempool.remove(&tx.txid());
+                }
+                for batch in &_content.batches {
+                    _mempool.remove_batch(&batch.hash());
+                }
+                drop(_mempool);
+                _content_stale = true;
+
+                if let OperatingState::RunN(n) = self.operating_state {
+                    self.operating_state = Self::run_n_state(n - 1);
+                    if let OperatingState::Paused = self.operating_state {
+                        info!("Miner finished the requested blocks, pausing");
+                    }
+                }
+                _attempts = 0;
+            } else {
+                _attempts += 1;
+                if Some(_attempts) == self.max_attempts {
+                    warn!(
+                        "Miner found no block in {} attempts, difficulty {} is too hard, shutting down",
+                        _attempts, _difficulty
+                    );
+                    return;
+                }
             }
 
             if let OperatingState::Run(i) = self.operating_state {
@@ -199,6 +669,7 @@ impl Context {
 mod test {
     use crate::types::hash::Hashable;
     use ntest::timeout;
+    use std::time::Duration;
 
     #[test]
     #[timeout(60000)]
@@ -213,6 +684,473 @@ mod test {
             block_prev = block_next;
         }
     }
+
+    #[test]
+    #[timeout(60000)]
+    fn miner_mine_n_then_pause() {
+        let (miner_ctx, miner_handle, finished_block_chan) = super::test_new();
+        miner_ctx.start();
+        miner_handle.mine_n(3);
+        for _ in 0..3 {
+            finished_block_chan.recv().unwrap();
+        }
+        assert!(finished_block_chan
+            .recv_timeout(Duration::from_millis(500))
+            .is_err());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn miner_uses_mock_clock() {
+        use crate::blockchain::Blockchain;
+        use crate::mempool::Mempool;
+        use crate::network::sync::SyncTracker;
+        use crate::types::clock::MockClock;
+        use std::sync::{Arc, Mutex};
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
+        let clock = Arc::new(MockClock::new(1_234_567));
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new_with_clock(&blockchain, &mempool, &sync_tracker, clock);
+        miner_ctx.start();
+        miner_handle.mine_n(1);
+        let block = finished_block_chan.recv().unwrap();
+        assert_eq!(block.header.timestamp, 1_234_567);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn miner_waits_for_sync() {
+        use crate::blockchain::Blockchain;
+        use crate::mempool::Mempool;
+        use crate::network::sync::SyncTracker;
+        use crate::types::block::generate_random_block;
+        use std::sync::{Arc, Mutex};
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
+        sync_tracker.lock().unwrap().on_advertised_tip(2);
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new(&blockchain, &mempool, &sync_tracker);
+        miner_ctx.start();
+        miner_handle.start(0);
+        assert!(finished_block_chan
+            .recv_timeout(Duration::from_millis(500))
+            .is_err());
+
+        // catch up with the advertised tip
+        let mut _blockchain = blockchain.lock().unwrap();
+        for _ in 0..2 {
+            let block = generate_random_block(&_blockchain.tip());
+            _blockchain.insert(&block);
+        }
+        let synced_tip = _blockchain.tip();
+        drop(_blockchain);
+
+        let block = finished_block_chan.recv().unwrap();
+        assert_eq!(block.get_parent(), synced_tip);
+    }
+
+    #[test]
+    fn select_transactions_up_to_byte_limit() {
+        use crate::types::transaction::{generate_random_signed_transaction, SignedTransaction};
+
+        // unsigned transactions carry no signature nor key, so are smaller
+        let signed_tx = generate_random_signed_transaction();
+        let unsigned_tx = SignedTransaction::default();
+        let (signed_bytes, unsigned_bytes) = (signed_tx.size_bytes(), unsigned_tx.size_bytes());
+        assert!(unsigned_bytes < signed_bytes);
+        let candidates = [
+            signed_tx.clone(),
+            unsigned_tx.clone(),
+            signed_tx.clone(),
+            unsigned_tx,
+            signed_tx,
+        ];
+
+        let max_bytes = 2 * signed_bytes + 2 * unsigned_bytes - 1;
+        let selected = super::select_transactions(candidates.iter().collect(), max_bytes);
+        assert_eq!(selected.len(), 3);
+        let selected = super::select_transactions(candidates.iter().collect(), max_bytes + 1);
+        assert_eq!(selected.len(), 4);
+        assert!(super::select_transactions(candidates.iter().collect(), unsigned_bytes).is_empty());
+    }
+
+    #[test]
+    fn order_transactions_by_nonce() {
+        use crate::types::address::{random_address_with_keypair, Address};
+        use crate::types::state::State;
+        use crate::types::transaction::{sign_transaction, Transaction};
+
+        let (alice, key) = random_address_with_keypair();
+        let bob: Address = [2u8; 20].into();
+        let mut state = State::new();
+        state.credit(&alice, 100);
+        state
+            .apply_transaction(&Transaction::new(alice, bob, 1, 0, 0))
+            .unwrap();
+
+        let tx_with_nonce =
+            |nonce| sign_transaction(Transaction::new(alice, bob, 1, 0, nonce), &key);
+        // the one of nonce 5 leaves a gap
+        let candidates = [
+            tx_with_nonce(3),
+            tx_with_nonce(5),
+            tx_with_nonce(1),
+            tx_with_nonce(2),
+        ];
+        let ordered = super::drop_conflicts(
+            &state,
+            super::order_by_nonce(&state, candidates.iter().collect()),
+        );
+        let nonces: Vec<u64> = ordered.iter().map(|tx| tx.transaction().nonce()).collect();
+        assert_eq!(nonces, vec![1, 2, 3]);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn mine_mempool_transactions() {
+        use crate::blockchain::{Blockchain, GenesisParams};
+        use crate::mempool::Mempool;
+        use crate::network::sync::SyncTracker;
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::transaction::{sign_transaction, Transaction};
+        use std::sync::{Arc, Mutex};
+
+        let (alice, alice_key) = random_address_with_keypair();
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(GenesisParams {
+            premine: vec![(alice, 100)],
+            ..Default::default()
+        })));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let tx = sign_transaction(
+            Transaction::new(alice, [2u8; 20].into(), 30, 1, 0),
+            &alice_key,
+        );
+        mempool.lock().unwrap().insert(&tx);
+        let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new(&blockchain, &mempool, &sync_tracker);
+        miner_ctx.start();
+        miner_handle.mine_n(2);
+
+        let block = finished_block_chan.recv().unwrap();
+        assert_eq!(block.content().data.len(), 2);
+        assert!(block.content().data[0].is_coinbase());
+        assert_eq!(block.content().data[1].txid(), tx.txid());
+        assert!(blockchain.lock().unwrap().validate_block(&block).is_ok());
+        // mined transactions leave the mempool
+        assert_eq!(finished_block_chan.recv().unwrap().content().data.len(), 1);
+        assert!(mempool.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn mine_mempool_batches() {
+        use crate::blockchain::{Blockchain, GenesisParams};
+        use crate::mempool::Mempool;
+        use crate::network::sync::SyncTracker;
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::transaction::{sign_batch, Transaction};
+        use std::sync::{Arc, Mutex};
+
+        let (alice, alice_key) = random_address_with_keypair();
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(GenesisParams {
+            premine: vec![(alice, 100)],
+            ..Default::default()
+        })));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let batch = sign_batch(
+            vec![
+                Transaction::new(alice, [2u8; 20].into(), 30, 0, 0),
+                Transaction::new(alice, [3u8; 20].into(), 40, 0, 1),
+            ],
+            &alice_key,
+        );
+        // overdraws after the first batch, so it is left out
+        let overdrawing_batch = sign_batch(
+            vec![Transaction::new(alice, [2u8; 20].into(), 50, 0, 2)],
+            &alice_key,
+        );
+        mempool.lock().unwrap().insert_batch(&batch);
+        mempool.lock().unwrap().insert_batch(&overdrawing_batch);
+        let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new(&blockchain, &mempool, &sync_tracker);
+        miner_ctx.start();
+        miner_handle.mine_n(2);
+
+        let block = finished_block_chan.recv().unwrap();
+        assert_eq!(block.content().batches.len(), 1);
+        assert_eq!(block.content().batches[0].hash(), batch.hash());
+        assert_eq!(block.content().tx_count(), 3);
+        assert!(blockchain.lock().unwrap().validate_block(&block).is_ok());
+        // mined batches leave the mempool
+        finished_block_chan.recv().unwrap();
+        assert!(!mempool.lock().unwrap().contains_batch(&batch.hash()));
+        assert!(mempool
+            .lock()
+            .unwrap()
+            .contains_batch(&overdrawing_batch.hash()));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn mine_one_of_conflicting_transactions() {
+        use crate::blockchain::{Blockchain, GenesisParams};
+        use crate::mempool::Mempool;
+        use crate::network::sync::SyncTracker;
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::transaction::{sign_transaction, Transaction};
+        use std::sync::{Arc, Mutex};
+
+        let (alice, alice_key) = random_address_with_keypair();
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(GenesisParams {
+            premine: vec![(alice, 100)],
+            ..Default::default()
+        })));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        // both spend the same nonce
+        let tx_1 = sign_transaction(
+            Transaction::new(alice, [2u8; 20].into(), 60, 0, 0),
+            &alice_key,
+        );
+        let tx_2 = sign_transaction(
+            Transaction::new(alice, [3u8; 20].into(), 70, 0, 0),
+            &alice_key,
+        );
+        mempool.lock().unwrap().insert(&tx_1);
+        mempool.lock().unwrap().insert(&tx_2);
+        let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new(&blockchain, &mempool, &sync_tracker);
+        miner_ctx.start();
+        miner_handle.mine_n(1);
+
+        let block = finished_block_chan.recv().unwrap();
+        assert_eq!(block.content().data.len(), 2);
+        let txid = block.content().data[1].txid();
+        assert!(txid == tx_1.txid() || txid == tx_2.txid());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn mine_on_tip_after_parent_pruned() {
+        use crate::blockchain::{Blockchain, GenesisParams};
+        use crate::mempool::Mempool;
+        use crate::network::sync::SyncTracker;
+        use crate::types::block::generate_random_block;
+        use std::sync::{Arc, Mutex};
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(GenesisParams {
+            max_reorg_depth: 2,
+            ..Default::default()
+        })));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new(&blockchain, &mempool, &sync_tracker);
+        miner_ctx.start();
+        miner_handle.mine_n(1);
+        let stale_block = finished_block_chan.recv().unwrap();
+        blockchain.lock().unwrap().insert(&stale_block);
+
+        // a longer fork replaces the mined block, which the inserts then prune
+        let mut _blockchain = blockchain.lock().unwrap();
+        let mut parent_hash = genesis_hash;
+        for _ in 0..4 {
+            let block = generate_random_block(&parent_hash);
+            _blockchain.insert(&block);
+            parent_hash = block.hash();
+        }
+        assert!(!_blockchain.hash_to_block.contains_key(&stale_block.hash()));
+        let tip = _blockchain.tip();
+        drop(_blockchain);
+
+        miner_handle.mine_n(1);
+        let block = finished_block_chan.recv().unwrap();
+        assert_eq!(block.get_parent(), tip);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn supply_grows_by_block_reward() {
+        use crate::blockchain::{Blockchain, GenesisParams, BLOCK_REWARD};
+        use crate::mempool::Mempool;
+        use crate::network::sync::SyncTracker;
+        use crate::types::address::Address;
+        use std::sync::{Arc, Mutex};
+
+        let miner_address: Address = [3u8; 20].into();
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(GenesisParams {
+            premine: vec![([1u8; 20].into(), 1000)],
+            ..Default::default()
+        })));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
+        let (mut miner_ctx, miner_handle, finished_block_chan) =
+            super::new(&blockchain, &mempool, &sync_tracker);
+        miner_ctx.set_reward_address(miner_address);
+        miner_ctx.start();
+        assert_eq!(blockchain.lock().unwrap().total_supply(), 1000);
+
+        miner_handle.mine_n(3);
+        for i in 1..=3 {
+            let block = finished_block_chan.recv().unwrap();
+            let mut _blockchain = blockchain.lock().unwrap();
+            _blockchain.insert(&block);
+            assert_eq!(
+                _blockchain.total_supply(),
+                (1000 + i * BLOCK_REWARD) as u128
+            );
+            assert_eq!(
+                _blockchain.tip_state().account(&miner_address).balance,
+                i * BLOCK_REWARD
+            );
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn mine_valid_block_with_empty_mempool() {
+        use crate::blockchain::Blockchain;
+        use crate::mempool::Mempool;
+        use crate::network::sync::SyncTracker;
+        use crate::types::hash::H256;
+        use crate::types::merkle::{MerkleTree, EMPTY_MERKLE_ROOT};
+        use crate::types::transaction::SignedTransaction;
+        use std::sync::{Arc, Mutex};
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new(&blockchain, &mempool, &sync_tracker);
+        miner_ctx.start();
+        miner_handle.mine_n(1);
+
+        // only the coinbase is mined
+        let block = finished_block_chan.recv().unwrap();
+        assert_eq!(block.content().data.len(), 1);
+        assert!(blockchain.lock().unwrap().validate_block(&block).is_ok());
+
+        // a block without any transaction has the genesis' merkle root
+        let _blockchain = blockchain.lock().unwrap();
+        let genesis = &_blockchain.hash_to_block[&_blockchain.genesis_hash()];
+        let empty_data: Vec<SignedTransaction> = vec![];
+        assert_eq!(genesis.header.merkle_root, H256::from(EMPTY_MERKLE_ROOT));
+        assert_eq!(
+            MerkleTree::new(&empty_data).root(),
+            genesis.header.merkle_root
+        );
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn find_block_under_easy_difficulty() {
+        let (miner_ctx, miner_handle, finished_block_chan) = super::test_new();
+        miner_ctx.start();
+        miner_handle.mine_n(1);
+        let block = finished_block_chan.recv().unwrap();
+        assert_eq!(block.get_difficulty(), super::easy_difficulty());
+        assert!(block.hash() <= super::easy_difficulty());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn fail_fast_on_unminable_difficulty() {
+        // only the zero hash meets the zero target
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::test_new_with_difficulty([0u8; 32].into());
+        miner_ctx.start();
+        miner_handle.mine_n(1);
+        // the miner gives up, dropping its end of the channel
+        assert!(finished_block_chan.recv().is_err());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn mine_fork_on_chosen_parent() {
+        let (miner_ctx, miner_handle, finished_block_chan) = super::test_new();
+        let genesis_hash = miner_ctx.blockchain.lock().unwrap().tip();
+        miner_ctx.start();
+
+        miner_handle.mine_on(genesis_hash);
+        miner_handle.mine_n(1);
+        let block_1 = finished_block_chan.recv().unwrap();
+        miner_handle.mine_on(genesis_hash);
+        miner_handle.mine_n(1);
+        let block_2 = finished_block_chan.recv().unwrap();
+        assert_eq!(block_1.get_parent(), genesis_hash);
+        assert_eq!(block_2.get_parent(), genesis_hash);
+        assert_ne!(block_1.hash(), block_2.hash());
+
+        // an unknown parent is ignored, the miner goes on after its last block
+        miner_handle.mine_on([7u8; 32].into());
+        miner_handle.mine_n(1);
+        assert_eq!(
+            finished_block_chan.recv().unwrap().get_parent(),
+            block_2.hash()
+        );
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn timestamp_after_parent_with_frozen_clock() {
+        use crate::blockchain::{Blockchain, GenesisParams};
+        use crate::mempool::Mempool;
+        use crate::network::sync::SyncTracker;
+        use crate::types::clock::MockClock;
+        use std::sync::{Arc, Mutex};
+
+        // the genesis timestamp and the clock are both in millis, the clock being behind
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(GenesisParams {
+            timestamp: 1_000,
+            ..Default::default()
+        })));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
+        let clock = Arc::new(MockClock::new(500));
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new_with_clock(&blockchain, &mempool, &sync_tracker, clock);
+        miner_ctx.start();
+        miner_handle.mine_n(3);
+        let blocks: Vec<_> = (0..3)
+            .map(|_| finished_block_chan.recv().unwrap())
+            .collect();
+        let timestamps: Vec<u128> = blocks.iter().map(|block| block.header.timestamp).collect();
+        assert_eq!(timestamps, vec![1_001, 1_002, 1_003]);
+        assert_eq!(
+            blockchain.lock().unwrap().validate_block(&blocks[0]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn mine_on_default_genesis() {
+        use crate::blockchain::{Blockchain, GENESIS_DIFFICULTY};
+        use crate::mempool::Mempool;
+        use crate::network::sync::SyncTracker;
+        use crate::types::hash::H256;
+        use std::sync::{Arc, Mutex};
+
+        // the genesis difficulty any node starts from is met by a real miner
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
+        let (miner_ctx, miner_handle, finished_block_chan) =
+            super::new(&blockchain, &mempool, &sync_tracker);
+        miner_ctx.start();
+        miner_handle.mine_n(1);
+        let block = finished_block_chan.recv().unwrap();
+        assert_eq!(block.get_difficulty(), H256::from(GENESIS_DIFFICULTY));
+        assert_eq!(blockchain.lock().unwrap().submit_block(block), Ok(None));
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
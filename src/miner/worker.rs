@@ -1,19 +1,29 @@
+use super::MiningLatency;
+use crate::mempool::Mempool;
 use crate::network::message::Message;
 use crate::types::block::Block;
 use crate::types::hash::Hashable;
 use crate::{blockchain::Blockchain, network::server::Handle as ServerHandle};
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
+use log::{debug, info, warn};
 use std::{
     sync::{Arc, Mutex},
     thread,
+    time::Duration,
 };
 
+/// Number of tries to broadcast a finished block before giving up
+const MAX_BROADCAST_ATTEMPTS: u32 = 4;
+/// Wait before the first retry, doubled after each following failure
+const BROADCAST_BACKOFF_BASE: Duration = Duration::from_millis(50);
+
 #[derive(Clone)]
 pub struct Worker {
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    latency: Arc<MiningLatency>,
 }
 
 impl Worker {
@@ -21,14 +31,22 @@ impl Worker {
         server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
         blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
     ) -> Self {
         Self {
             server: server.clone(),
             finished_block_chan,
             blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            latency: Arc::new(MiningLatency::new()),
         }
     }
 
+    /// Time the broadcast of blocks found by the miner sharing this latency
+    pub fn set_latency(&mut self, latency: &Arc<MiningLatency>) {
+        self.latency = Arc::clone(latency);
+    }
+
     pub fn start(self) {
         thread::Builder::new()
             .name("miner-worker".to_string())
@@ -47,11 +65,166 @@ impl Worker {
                 .expect("Receive finished block error");
 
             let mut _blockchain = self.blockchain.lock().unwrap();
-            _blockchain.insert(&_block);
+            let reorg = _blockchain.insert(&_block);
+            let mut _mempool = self.mempool.lock().unwrap();
+            if let Some(reorg) = reorg {
+                // the block may be mined on another fork than the tip, e.g. with `MineOn`
+                let replayed_vec = _mempool.replay_reorg(&_blockchain, &reorg);
+                debug!(
+                    "Reorg to mined block disconnecting {} blocks returned {} transactions to \
+                     mempool",
+                    reorg.disconnected.len(),
+                    replayed_vec.len()
+                );
+                // the blocks of the fork joining the longest chain may be from peers
+                for hash in reorg.connected.iter() {
+                    _mempool.remove_confirmed(&_blockchain.hash_to_block[hash]);
+                }
+            }
+            // orphan transactions may be waiting for the nonces the block applies
+            _mempool.update_orphans(_blockchain.tip_state());
+            drop(_mempool);
             drop(_blockchain);
 
-            self.server
-                .broadcast(Message::NewBlockHashes(vec![_block.hash()])); // blocking operation
+            // the block is kept locally even if no peer hears about it. Peers likely have its
+            // transactions already, from the gossip which put them in our mempool.
+            self.broadcast_with_retry(Message::CompactBlock {
+                header: _block.header.clone(),
+                txids: _block.content().data.iter().map(|tx| tx.txid()).collect(),
+            });
+            if let Some(latency) = self.latency.record_broadcast(&_block.hash()) {
+                debug!(
+                    "Block {} broadcast {:?} after found",
+                    _block.hash(),
+                    latency
+                );
+            }
+        }
+    }
+
+    /// Broadcast a message, retrying with exponential backoff, return false if all tries fail
+    fn broadcast_with_retry(&self, msg: Message) -> bool {
+        let mut backoff = BROADCAST_BACKOFF_BASE;
+        for attempt in 1..=MAX_BROADCAST_ATTEMPTS {
+            if self.server.try_broadcast(msg.clone()) {
+                return true;
+            }
+            if attempt < MAX_BROADCAST_ATTEMPTS {
+                debug!(
+                    "Broadcast attempt {} failed, retrying in {:?}",
+                    attempt, backoff
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+        warn!(
+            "Giving up broadcasting after {} attempts: {:?}",
+            MAX_BROADCAST_ATTEMPTS, msg
+        );
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Worker;
+    use crate::blockchain::Blockchain;
+    use crate::mempool::Mempool;
+    use crate::network::server::Handle as ServerHandle;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::Hashable;
+    use crossbeam::channel::unbounded;
+    use ntest::timeout;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    #[timeout(60000)]
+    fn keep_block_when_broadcast_fails() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        // the server is gone, so every broadcast fails
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        drop(server_receiver);
+        let (finished_block_sender, finished_block_chan) = unbounded();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let worker = Worker::new(&server, finished_block_chan, &blockchain, &mempool);
+        worker.start();
+
+        let block_1 = generate_random_block(&blockchain.lock().unwrap().tip());
+        let block_2 = generate_random_block(&block_1.hash());
+        finished_block_sender.send(block_1.clone()).unwrap();
+        finished_block_sender.send(block_2.clone()).unwrap();
+
+        // the worker moves on to the second block after giving up on the first broadcast
+        while blockchain.lock().unwrap().tip() != block_2.hash() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(blockchain
+            .lock()
+            .unwrap()
+            .hash_to_block
+            .contains_key(&block_1.hash()));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn record_latency_from_found_to_broadcast() {
+        use crate::miner::MiningLatency;
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (finished_block_sender, finished_block_chan) = unbounded();
+        let latency = Arc::new(MiningLatency::new());
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let mut worker = Worker::new(&server, finished_block_chan, &blockchain, &mempool);
+        worker.set_latency(&latency);
+        worker.start();
+
+        let block = generate_random_block(&blockchain.lock().unwrap().tip());
+        latency.record_found(block.hash());
+        finished_block_sender.send(block).unwrap();
+
+        while latency.last().is_none() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(latency.last().unwrap() >= Duration::from_secs(0));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn return_transactions_of_mined_reorg_to_mempool() {
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::merkle::MerkleTree;
+        use crate::types::transaction::{sign_transaction, Transaction};
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (finished_block_sender, finished_block_chan) = unbounded();
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let worker = Worker::new(&server, finished_block_chan, &blockchain, &mempool);
+        worker.start();
+
+        // the tip holds a transaction, then our fork of two blocks replaces it
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let (sender, key) = random_address_with_keypair();
+        let tx = sign_transaction(Transaction::new(sender, [2u8; 20].into(), 0, 0, 0), &key);
+        let mut tip_block = generate_random_block(&genesis_hash);
+        let tx_vec = vec![tx.clone()];
+        tip_block.header.merkle_root = MerkleTree::new(&tx_vec).root();
+        tip_block.content_mut().data = tx_vec;
+        blockchain.lock().unwrap().insert(&tip_block);
+        let block_1 = generate_random_block(&genesis_hash);
+        let block_2 = generate_random_block(&block_1.hash());
+        finished_block_sender.send(block_1).unwrap();
+        finished_block_sender.send(block_2.clone()).unwrap();
+
+        while blockchain.lock().unwrap().tip() != block_2.hash() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        while !mempool.lock().unwrap().contains(&tx.txid()) {
+            thread::sleep(Duration::from_millis(10));
         }
     }
 }
@@ -0,0 +1,82 @@
+use super::message::Message;
+use super::server::Handle as ServerHandle;
+use crate::blockchain::Blockchain;
+
+use log::{debug, info};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Re-announces the tip to all peers at a fixed interval, so that peers which missed the
+/// `NewBlockHashes` of a block, e.g. while disconnected, can still request it
+pub struct TipAnnouncer {
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    interval: Duration,
+}
+
+impl TipAnnouncer {
+    pub fn new(
+        server: &ServerHandle,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            server: server.clone(),
+            blockchain: Arc::clone(blockchain),
+            interval,
+        }
+    }
+
+    pub fn start(self) {
+        info!("Tip announcer started, every {:?}", self.interval);
+        thread::Builder::new()
+            .name("tip-announcer".to_string())
+            .spawn(move || loop {
+                thread::sleep(self.interval);
+                self.announce();
+            })
+            .unwrap();
+    }
+
+    /// Broadcast a `NewBlockHashes` of the current tip
+    pub fn announce(&self) {
+        let tip = self.blockchain.lock().unwrap().tip();
+        debug!("Re-announcing tip {}", tip);
+        self.server.broadcast(Message::NewBlockHashes(vec![tip]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TipAnnouncer;
+    use crate::blockchain::Blockchain;
+    use crate::network::message::Message;
+    use crate::network::server::Handle as ServerHandle;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::Hashable;
+    use ntest::timeout;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    #[timeout(60000)]
+    fn reannounce_tip_after_interval() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let block = generate_random_block(&blockchain.lock().unwrap().tip());
+        blockchain.lock().unwrap().insert(&block);
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let interval = Duration::from_millis(200);
+        let started = Instant::now();
+        TipAnnouncer::new(&server, &blockchain, interval).start();
+
+        for _ in 0..2 {
+            if let Some(Message::NewBlockHashes(hash_vec)) = server_receiver.recv() {
+                assert_eq!(hash_vec, vec![block.hash()]);
+            } else {
+                panic!();
+            }
+        }
+        assert!(started.elapsed() >= 2 * interval);
+    }
+}
@@ -1,6 +1,16 @@
 use serde::{Deserialize, Serialize};
 
-use crate::types::{block::Block, hash::H256, transaction::SignedTransaction};
+use crate::types::{
+    block::{Block, Header},
+    hash::H256,
+    transaction::{SignedTransaction, SignedTransactionBatch},
+};
+
+/// Version of the message format, peers with a different one are rejected
+pub const PROTOCOL_VERSION: u32 = 4;
+/// Max size of a serialized message, a peer sending a larger frame is disconnected before any of
+/// it is buffered
+pub const MAX_MSG_BYTES: u64 = 64 * 1024 * 1024;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -12,4 +22,20 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    HaveBlocksFilter(Vec<u8>), // serialized bloom filter of the blocks known by the sender
+    GetHeaders(Vec<H256>, H256), // block locator of the sender, and the hash to stop at
+    Headers(Vec<Header>),      // ordered from old to new
+    Tip(H256, u128),           // hash and height of the sender's longest chain tip
+    Version {
+        protocol_version: u32,
+        genesis: H256,
+        node_id: u64, // random per run, to detect connections to ourselves or twice to a peer
+    }, // exchanged when a peer connects
+    CompactBlock {
+        header: Header,
+        txids: Vec<H256>,
+    }, // a block announced without the transactions the receiver likely has
+    GetBlockTransactions(H256, Vec<H256>), // block hash, and txids missing to rebuild it
+    BlockTransactions(H256, Vec<SignedTransaction>),
+    TransactionBatches(Vec<SignedTransactionBatch>),
 }
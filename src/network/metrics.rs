@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::message::Message;
+
+/// Names of the counted message types, in the order of `counter_index`
+const MESSAGE_TYPES: [&str; 19] = [
+    "Ping",
+    "Pong",
+    "NewBlockHashes",
+    "GetBlocks",
+    "Blocks",
+    "NewTransactionHashes",
+    "GetTransactions",
+    "Transactions",
+    "HaveBlocksFilter",
+    "GetHeaders",
+    "Headers",
+    "Tip",
+    "Version",
+    "CompactBlock",
+    "GetBlockTransactions",
+    "BlockTransactions",
+    "TransactionBatches",
+    "Malformed", // not a message type, counts the bytes that fail to deserialize
+    "Total",
+];
+const MALFORMED: usize = 17;
+const TOTAL: usize = 18;
+
+/// Counters of received network messages by type, lock-free so that workers do not contend
+#[derive(Default)]
+pub struct NetworkMetrics {
+    counters: [AtomicU64; MESSAGE_TYPES.len()],
+}
+
+impl NetworkMetrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Count a received message
+    pub fn record(&self, msg: &Message) {
+        self.counters[Self::counter_index(msg)].fetch_add(1, Ordering::Relaxed);
+        self.counters[TOTAL].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count received bytes which are not a message
+    pub fn record_malformed(&self) {
+        self.counters[MALFORMED].fetch_add(1, Ordering::Relaxed);
+        self.counters[TOTAL].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the current count of each message type
+    pub fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+        MESSAGE_TYPES
+            .iter()
+            .zip(self.counters.iter())
+            .map(|(name, counter)| (*name, counter.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    fn counter_index(msg: &Message) -> usize {
+        match msg {
+            Message::Ping(_) => 0,
+            Message::Pong(_) => 1,
+            Message::NewBlockHashes(_) => 2,
+            Message::GetBlocks(_) => 3,
+            Message::Blocks(_) => 4,
+            Message::NewTransactionHashes(_) => 5,
+            Message::GetTransactions(_) => 6,
+            Message::Transactions(_) => 7,
+            Message::HaveBlocksFilter(_) => 8,
+            Message::GetHeaders(_, _) => 9,
+            Message::Headers(_) => 10,
+            Message::Tip(_, _) => 11,
+            Message::Version { .. } => 12,
+            Message::CompactBlock { .. } => 13,
+            Message::GetBlockTransactions(_, _) => 14,
+            Message::BlockTransactions(_, _) => 15,
+            Message::TransactionBatches(_) => 16,
+        }
+    }
+}
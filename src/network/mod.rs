@@ -1,4 +1,13 @@
+pub mod announcer;
 pub mod message;
+pub mod metrics;
+pub mod orphans;
 pub mod peer;
+pub mod peer_selector;
+pub mod rate_limit;
+pub mod rejections;
+pub mod seen_cache;
 pub mod server;
+pub mod sync;
+pub mod watchdog;
 pub mod worker;
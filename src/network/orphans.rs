@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+
+struct Orphan {
+    block: Block,
+    peer: SocketAddr, // which sent the block
+    received_at: Instant,
+}
+
+/// Bounded buffer of blocks waiting for their missing parent. Orphans are dropped after
+/// `timeout`, and the earliest received one is evicted when full.
+pub struct OrphanPool {
+    capacity: usize,
+    timeout: Duration,
+    by_parent: HashMap<H256, Vec<Orphan>>, // missing parent hash -> orphan blocks
+    len: usize,
+}
+
+impl OrphanPool {
+    pub fn new(capacity: usize, timeout: Duration) -> Self {
+        OrphanPool {
+            capacity,
+            timeout,
+            by_parent: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    /// Buffer a block from a peer until its parent arrives, return false if it is already
+    /// buffered
+    pub fn insert(&mut self, block: Block, peer: SocketAddr, now: Instant) -> bool {
+        if self.capacity == 0 || self.contains(&block.hash()) {
+            return false;
+        }
+        self.expire(now);
+        if self.len == self.capacity {
+            self.evict_earliest();
+        }
+        self.by_parent
+            .entry(block.get_parent())
+            .or_default()
+            .push(Orphan {
+                block,
+                peer,
+                received_at: now,
+            });
+        self.len += 1;
+        true
+    }
+
+    /// Take the orphans waiting for the given parent, with the peers that sent them
+    pub fn take_children(&mut self, parent: &H256) -> Vec<(Block, SocketAddr)> {
+        let children = self.by_parent.remove(parent).unwrap_or_default();
+        self.len -= children.len();
+        children
+            .into_iter()
+            .map(|orphan| (orphan.block, orphan.peer))
+            .collect()
+    }
+
+    /// Drop the orphans descending from a rejected block
+    pub fn discard_branch(&mut self, root: &H256) {
+        let mut parent_queue = vec![*root];
+        while let Some(parent_hash) = parent_queue.pop() {
+            for (block, _) in self.take_children(&parent_hash) {
+                parent_queue.push(block.hash());
+            }
+        }
+    }
+
+    /// Drop the orphans sent by a peer, e.g. once it disconnects
+    pub fn forget_peer(&mut self, peer: &SocketAddr) {
+        let mut len = 0;
+        for children in self.by_parent.values_mut() {
+            children.retain(|orphan| orphan.peer != *peer);
+            len += children.len();
+        }
+        self.by_parent.retain(|_, children| !children.is_empty());
+        self.len = len;
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.by_parent
+            .values()
+            .flatten()
+            .any(|orphan| orphan.block.hash() == *hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Drop the orphans received more than `timeout` before `now`
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let mut len = 0;
+        for children in self.by_parent.values_mut() {
+            children.retain(|orphan| now.duration_since(orphan.received_at) < timeout);
+            len += children.len();
+        }
+        self.by_parent.retain(|_, children| !children.is_empty());
+        self.len = len;
+    }
+
+    fn evict_earliest(&mut self) {
+        let earliest = self
+            .by_parent
+            .iter()
+            .flat_map(|(parent, children)| {
+                children
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, orphan)| (orphan.received_at, *parent, i))
+            })
+            .min_by_key(|(received_at, _, _)| *received_at);
+        if let Some((_, parent, i)) = earliest {
+            let children = self.by_parent.get_mut(&parent).unwrap();
+            children.remove(i);
+            if children.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+            self.len -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OrphanPool;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::{Hashable, H256};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn evict_earliest_orphan_when_full() {
+        let mut pool = OrphanPool::new(2, Duration::from_secs(60));
+        let peer = "127.0.0.1:6000".parse().unwrap();
+        let now = Instant::now();
+        let parent = H256::from([1u8; 32]);
+        let block_1 = generate_random_block(&parent);
+        let block_2 = generate_random_block(&parent);
+        let block_3 = generate_random_block(&block_1.hash());
+        assert!(pool.insert(block_1.clone(), peer, now));
+        assert!(!pool.insert(block_1.clone(), peer, now));
+        assert!(pool.insert(block_2.clone(), peer, now + Duration::from_secs(1)));
+        assert!(pool.insert(block_3.clone(), peer, now + Duration::from_secs(2)));
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&block_1.hash()));
+        assert!(pool.contains(&block_2.hash()));
+        assert!(pool.contains(&block_3.hash()));
+    }
+
+    #[test]
+    fn expire_old_orphans() {
+        let mut pool = OrphanPool::new(10, Duration::from_secs(60));
+        let peer = "127.0.0.1:6000".parse().unwrap();
+        let now = Instant::now();
+        let old_block = generate_random_block(&[1u8; 32].into());
+        let new_block = generate_random_block(&[2u8; 32].into());
+        pool.insert(old_block.clone(), peer, now);
+        pool.insert(new_block.clone(), peer, now + Duration::from_secs(61));
+        assert_eq!(pool.len(), 1);
+        assert!(!pool.contains(&old_block.hash()));
+        assert!(pool.contains(&new_block.hash()));
+    }
+
+    #[test]
+    fn forget_orphans_of_peer() {
+        let mut pool = OrphanPool::new(10, Duration::from_secs(60));
+        let peer_a = "127.0.0.1:6000".parse().unwrap();
+        let peer_b = "127.0.0.1:6001".parse().unwrap();
+        let now = Instant::now();
+        let parent = H256::from([1u8; 32]);
+        let block_a = generate_random_block(&parent);
+        let block_b = generate_random_block(&parent);
+        pool.insert(block_a.clone(), peer_a, now);
+        pool.insert(block_b.clone(), peer_b, now);
+        pool.forget_peer(&peer_a);
+        assert_eq!(pool.len(), 1);
+        let children = pool.take_children(&parent);
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].0.hash(), block_b.hash());
+        assert_eq!(children[0].1, peer_b);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn discard_descendants_of_rejected_block() {
+        let mut pool = OrphanPool::new(10, Duration::from_secs(60));
+        let peer = "127.0.0.1:6000".parse().unwrap();
+        let now = Instant::now();
+        let root = H256::from([1u8; 32]);
+        let child = generate_random_block(&root);
+        let grandchild = generate_random_block(&child.hash());
+        let other = generate_random_block(&[2u8; 32].into());
+        pool.insert(child, peer, now);
+        pool.insert(grandchild, peer, now);
+        pool.insert(other.clone(), peer, now);
+        pool.discard_branch(&root);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains(&other.hash()));
+    }
+}
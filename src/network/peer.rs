@@ -1,18 +1,34 @@
 use super::message::Message;
 use futures::{channel::mpsc, sink::SinkExt};
-use log::trace;
+use log::{trace, warn};
 use smol::Async;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Max number of messages waiting to be written to a peer, a peer not keeping up beyond it is
+/// disconnected
+pub const MAX_WRITE_QUEUE: usize = 1000;
 
 pub fn new(
     stream: &Async<std::net::TcpStream>,
-) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
+) -> std::io::Result<(WriteQueue, Handle)> {
     let (write_sender, write_receiver) = mpsc::unbounded();
     let addr = stream.get_ref().peer_addr()?;
+    let queue_depth = Arc::new(AtomicUsize::new(0));
+    let disconnected = Arc::new(AtomicBool::new(false));
     let handle = Handle {
         write_queue: write_sender,
+        queue_depth: Arc::clone(&queue_depth),
+        disconnected: Arc::clone(&disconnected),
+        stream: Some(Arc::new(stream.get_ref().try_clone()?)),
         addr,
     };
-    Ok((write_receiver, handle))
+    let write_queue = WriteQueue {
+        r: write_receiver,
+        queue_depth,
+        disconnected,
+    };
+    Ok((write_queue, handle))
 }
 
 #[derive(Copy, Clone)]
@@ -25,18 +41,55 @@ pub enum Direction {
 pub struct Handle {
     addr: std::net::SocketAddr,
     write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    queue_depth: Arc<AtomicUsize>, // number of messages in the write queue
+    disconnected: Arc<AtomicBool>,
+    stream: Option<Arc<std::net::TcpStream>>, // to close the connection, none for test handles
+}
+
+/// Messages to write to a peer, in the order of `Handle::write`
+pub struct WriteQueue {
+    r: mpsc::UnboundedReceiver<Vec<u8>>,
+    queue_depth: Arc<AtomicUsize>,
+    disconnected: Arc<AtomicBool>,
+}
+
+impl WriteQueue {
+    /// Get the next message to write, or None once the peer is disconnected, dropping the
+    /// messages still queued
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        if self.disconnected.load(Ordering::SeqCst) {
+            return None;
+        }
+        let buffer = futures::stream::StreamExt::next(&mut self.r).await?;
+        self.queue_depth.fetch_sub(1, Ordering::SeqCst);
+        if self.disconnected.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(buffer)
+    }
 }
 
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver {
-    r: mpsc::UnboundedReceiver<Vec<u8>>
+    r: WriteQueue,
 }
 
 impl Handle {
+    /// Queue a message to the peer. A peer with `MAX_WRITE_QUEUE` messages still queued does not
+    /// keep up, so it is disconnected rather than buffering without bound.
     pub fn write(&mut self, msg: Message) {
+        if self.queue_depth.load(Ordering::SeqCst) >= MAX_WRITE_QUEUE {
+            if !self.write_queue.is_closed() {
+                warn!("Disconnecting peer {} not keeping up with its {} queued messages", self.addr, MAX_WRITE_QUEUE);
+                self.disconnect();
+            }
+            return;
+        }
         let buffer = bincode::serialize(&msg).unwrap();
+        self.queue_depth.fetch_add(1, Ordering::SeqCst);
         smol::block_on(async move {
             if self.write_queue.send(buffer).await.is_err() {
+                self.queue_depth.fetch_sub(1, Ordering::SeqCst);
                 trace!("Trying to send to disconnected peer");
             }
         });
@@ -46,15 +99,46 @@ impl Handle {
         &self.addr
     }
 
+    /// Get the number of messages queued and not written to the peer yet
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::SeqCst)
+    }
+
+    /// Close the connection to the peer, without writing the messages still queued
+    pub fn disconnect(&self) {
+        self.disconnected.store(true, Ordering::SeqCst);
+        self.write_queue.close_channel();
+        // a peer not reading keeps the writer blocked in the middle of a message, so the socket
+        // is shut down here rather than once the writer gets to it
+        if let Some(stream) = &self.stream {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+
+    /// Check if the peer is disconnected, e.g. for not keeping up with its queue
+    pub fn is_disconnected(&self) -> bool {
+        self.write_queue.is_closed()
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
+        Self::test_handle_with_addr(std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321))
+    }
+
+    #[cfg(any(test,test_utilities))]
+    pub fn test_handle_with_addr(addr: std::net::SocketAddr) -> (Handle, TestReceiver) {
         let (s,r) = mpsc::unbounded();
+        let queue_depth = Arc::new(AtomicUsize::new(0));
+        let disconnected = Arc::new(AtomicBool::new(false));
         (Handle {
-            addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
+            addr,
             write_queue: s,
+            queue_depth: Arc::clone(&queue_depth),
+            disconnected: Arc::clone(&disconnected),
+            stream: None,
         },
         TestReceiver {
-            r
+            r: WriteQueue { r, queue_depth, disconnected }
         })
     }
 }
@@ -62,8 +146,39 @@ impl Handle {
 #[cfg(any(test,test_utilities))]
 impl TestReceiver {
     pub fn recv(&mut self) -> Message {
-        let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r)).unwrap();
+        let bytes = smol::block_on(self.r.next()).unwrap();
         let msg: Message = bincode::deserialize(&bytes).unwrap();
         msg
     }
-}
\ No newline at end of file
+
+    /// Check if the handle disconnected the peer
+    pub fn is_disconnected(&self) -> bool {
+        self.r.disconnected.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handle, MAX_WRITE_QUEUE};
+    use crate::network::message::Message;
+
+    #[test]
+    fn disconnect_peer_not_draining() {
+        let (mut handle, mut receiver) = Handle::test_handle();
+        for _ in 0..MAX_WRITE_QUEUE {
+            handle.write(Message::Ping(String::new()));
+        }
+        assert_eq!(handle.queue_depth(), MAX_WRITE_QUEUE);
+        assert!(!handle.is_disconnected());
+
+        // the queue stays bounded once the peer is disconnected
+        for _ in 0..10 {
+            handle.write(Message::Ping(String::new()));
+        }
+        assert_eq!(handle.queue_depth(), MAX_WRITE_QUEUE);
+        assert!(handle.is_disconnected());
+
+        // the messages queued before are dropped
+        assert!(smol::block_on(receiver.r.next()).is_none());
+    }
+}
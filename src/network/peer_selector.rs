@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Policy choosing which connected peer to ask for missing blocks
+pub trait PeerSelector: Send + Sync {
+    /// Record the tip height advertised by a peer
+    fn on_advertised_tip(&self, peer: SocketAddr, height: u128);
+
+    /// Forget what is recorded about a peer once it disconnects
+    fn on_disconnect(&self, peer: SocketAddr);
+
+    /// Choose among the connected peers the one to ask for blocks announced by `sender`
+    fn select(&self, sender: SocketAddr, connected: &[SocketAddr]) -> SocketAddr;
+}
+
+/// Prefer the connected peer which advertised the highest tip, the most recent one on equal
+/// heights, or the sender if no connected peer advertised any
+#[derive(Default)]
+pub struct HighestTipSelector {
+    tips: Mutex<HashMap<SocketAddr, (u128, u64)>>, // peer -> (tip height, order of advertisement)
+    advertisements: AtomicU64,
+}
+
+impl HighestTipSelector {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl PeerSelector for HighestTipSelector {
+    fn on_advertised_tip(&self, peer: SocketAddr, height: u128) {
+        let order = self.advertisements.fetch_add(1, Ordering::SeqCst);
+        self.tips.lock().unwrap().insert(peer, (height, order));
+    }
+
+    fn on_disconnect(&self, peer: SocketAddr) {
+        self.tips.lock().unwrap().remove(&peer);
+    }
+
+    fn select(&self, sender: SocketAddr, connected: &[SocketAddr]) -> SocketAddr {
+        let tips = self.tips.lock().unwrap();
+        connected
+            .iter()
+            .filter_map(|peer| tips.get(peer).map(|tip| (tip, *peer)))
+            .max()
+            .map(|(_, peer)| peer)
+            .unwrap_or(sender)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HighestTipSelector, PeerSelector};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn select_most_recent_highest_tip() {
+        let selector = HighestTipSelector::new();
+        let peers: Vec<SocketAddr> = (1..=3)
+            .map(|i| format!("127.0.0.1:{}", 6000 + i).parse().unwrap())
+            .collect();
+        assert_eq!(selector.select(peers[0], &peers), peers[0]);
+
+        selector.on_advertised_tip(peers[1], 5);
+        selector.on_advertised_tip(peers[2], 3);
+        assert_eq!(selector.select(peers[0], &peers), peers[1]);
+        selector.on_advertised_tip(peers[2], 5);
+        assert_eq!(selector.select(peers[0], &peers), peers[2]);
+
+        // a disconnected peer is not chosen
+        assert_eq!(selector.select(peers[0], &peers[..2]), peers[1]);
+        selector.on_disconnect(peers[2]);
+        assert_eq!(selector.tips.lock().unwrap().len(), 1);
+        assert_eq!(selector.select(peers[0], &peers), peers[1]);
+    }
+}
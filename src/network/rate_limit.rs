@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Number of peers with a bucket beyond which the buckets refilled to full are dropped, as a new
+/// bucket starts full anyway
+const MAX_TRACKED_PEERS: usize = 1000;
+
+/// Token bucket allowing bursts of `capacity` messages, refilled at `rate` messages per second
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, rate: f64, now: Instant) -> Self {
+        TokenBucket {
+            capacity: capacity as f64,
+            rate,
+            tokens: capacity as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Take a token if one is left after refilling for the time elapsed, return whether taken
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = self.last_refill.max(now);
+    }
+}
+
+/// One token bucket for each peer
+pub struct PeerRateLimiter {
+    capacity: u32,
+    rate: f64,
+    buckets: Mutex<HashMap<SocketAddr, TokenBucket>>,
+}
+
+impl PeerRateLimiter {
+    /// Allow bursts of `capacity` messages from each peer, then `rate` messages per second
+    pub fn new(capacity: u32, rate: f64) -> Self {
+        PeerRateLimiter {
+            capacity,
+            rate,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check if a message from a peer is within its rate, taking a token if so
+    pub fn allow(&self, peer: SocketAddr) -> bool {
+        self.allow_at(peer, Instant::now())
+    }
+
+    /// Same as `allow`, at the given time
+    pub fn allow_at(&self, peer: SocketAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_PEERS && !buckets.contains_key(&peer) {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                bucket.tokens < bucket.capacity
+            });
+        }
+        let (capacity, rate) = (self.capacity, self.rate);
+        buckets
+            .entry(peer)
+            .or_insert_with(|| TokenBucket::new(capacity, rate, now))
+            .try_take(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerRateLimiter;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn throttle_burst_and_pass_trickle() {
+        let limiter = PeerRateLimiter::new(3, 10.0);
+        let peer: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let other_peer: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let start = Instant::now();
+
+        // a burst passes up to the capacity
+        let passed = (0..10).filter(|_| limiter.allow_at(peer, start)).count();
+        assert_eq!(passed, 3);
+        // other peers have their own bucket
+        assert!(limiter.allow_at(other_peer, start));
+
+        // one message every 150ms is within the rate
+        let mut now = start;
+        for _ in 0..20 {
+            now += Duration::from_millis(150);
+            assert!(limiter.allow_at(peer, now));
+        }
+        // the tokens saved meanwhile are capped by the capacity, one of them just taken
+        let passed = (0..10).filter(|_| limiter.allow_at(peer, now)).count();
+        assert_eq!(passed, 2);
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::blockchain::BlockValidationError;
+use crate::types::hash::H256;
+use crate::types::transaction::TxValidationError;
+
+/// Why a block or a transaction from a peer is rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionReason {
+    Block(BlockValidationError),
+    Transaction(TxValidationError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub hash: H256, // of the block, or txid of the transaction
+    pub reason: RejectionReason,
+    pub peer: SocketAddr,
+}
+
+/// Ring buffer of the latest rejections, the earliest one is dropped when full
+pub struct RejectionLog {
+    capacity: usize,
+    entries: Mutex<VecDeque<Rejection>>, // from the earliest to the latest
+}
+
+impl RejectionLog {
+    pub fn new(capacity: usize) -> Self {
+        RejectionLog {
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, hash: H256, reason: RejectionReason, peer: SocketAddr) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(Rejection { hash, reason, peer });
+    }
+
+    /// Get the rejections kept, from the earliest to the latest
+    pub fn entries(&self) -> Vec<Rejection> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RejectionLog, RejectionReason};
+    use crate::blockchain::BlockValidationError;
+    use crate::types::transaction::TxValidationError;
+
+    #[test]
+    fn drop_earliest_rejection() {
+        let log = RejectionLog::new(2);
+        let peer = "127.0.0.1:6000".parse().unwrap();
+        log.record(
+            [1u8; 32].into(),
+            RejectionReason::Block(BlockValidationError::BadPow),
+            peer,
+        );
+        log.record(
+            [2u8; 32].into(),
+            RejectionReason::Transaction(TxValidationError::BadSignature),
+            peer,
+        );
+        log.record(
+            [3u8; 32].into(),
+            RejectionReason::Block(BlockValidationError::Oversized),
+            peer,
+        );
+
+        let entries = log.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].hash, [2u8; 32].into());
+        assert_eq!(entries[1].hash, [3u8; 32].into());
+        assert!(RejectionLog::new(0).entries().is_empty());
+    }
+}
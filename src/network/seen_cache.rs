@@ -0,0 +1,54 @@
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+
+/// Bounded set of recently seen items, the earliest seen item is evicted when full
+pub struct SeenCache<T> {
+    capacity: usize,
+    items: HashSet<T>,
+    order: VecDeque<T>, // from the earliest to the latest seen
+}
+
+impl<T: Hash + Eq + Copy> SeenCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        SeenCache {
+            capacity,
+            items: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Mark an item as seen, return false if it is already seen
+    pub fn insert(&mut self, item: T) -> bool {
+        if self.capacity == 0 || self.items.contains(&item) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            let earliest = self.order.pop_front().unwrap();
+            self.items.remove(&earliest);
+        }
+        self.items.insert(item);
+        self.order.push_back(item);
+        true
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.items.contains(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SeenCache;
+
+    #[test]
+    fn evict_earliest_seen() {
+        let mut cache = SeenCache::new(2);
+        assert!(cache.insert(1));
+        assert!(!cache.insert(1));
+        assert!(cache.insert(2));
+        assert!(cache.insert(3));
+        assert!(!cache.contains(&1));
+        assert!(cache.contains(&2));
+        assert!(cache.contains(&3));
+    }
+}
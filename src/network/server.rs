@@ -5,11 +5,12 @@ use super::message;
 use async_dup::Arc as AsyncArc;
 use futures::io::{AsyncReadExt, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
-use futures::{channel::oneshot, stream::StreamExt};
+use futures::channel::oneshot;
 use smol::{Async, Executor};
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use std::collections::HashMap;
 use std::net;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 
@@ -18,11 +19,20 @@ pub fn new(
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
+    let peers = Arc::new(Mutex::new(HashMap::new()));
+    let peer_ids = Arc::new(Mutex::new(HashMap::new()));
+    let disconnect_listeners = Arc::new(Mutex::new(vec![]));
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
+        peers: Arc::clone(&peers),
+        node_id: rand::random(),
+        peer_ids: Arc::clone(&peer_ids),
+        disconnect_listeners: Arc::clone(&disconnect_listeners),
     };
     let ctx = Context {
-        peers: std::collections::HashMap::new(),
+        peers,
+        peer_ids,
+        disconnect_listeners,
         addr,
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
@@ -32,7 +42,9 @@ pub fn new(
 }
 
 pub struct Context {
-    peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
+    peers: Arc<Mutex<HashMap<std::net::SocketAddr, peer::Handle>>>, // shared with handles for queries
+    peer_ids: Arc<Mutex<HashMap<std::net::SocketAddr, u64>>>, // node ids of the connected peers
+    disconnect_listeners: DisconnectListeners,
     addr: std::net::SocketAddr,
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
@@ -82,12 +94,18 @@ impl Context {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
-                    let handle = self.connect(&addr, ex.clone()).await;
+                    let handle = if addr == self.addr {
+                        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "connecting to ourselves"))
+                    } else if self.peers.lock().unwrap().contains_key(&addr) {
+                        Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "already connected"))
+                    } else {
+                        self.connect(&addr, ex.clone()).await
+                    };
                     result_chan.send(handle).unwrap();
                 }
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
-                    for (_, hd) in self.peers.iter_mut() {
+                    for (_, hd) in self.peers.lock().unwrap().iter_mut() {
                         hd.write(msg.clone());
                     }
                 }
@@ -97,7 +115,9 @@ impl Context {
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
-                    self.peers.remove(&addr);
+                    self.peers.lock().unwrap().remove(&addr);
+                    self.peer_ids.lock().unwrap().remove(&addr);
+                    notify_disconnect(&self.disconnect_listeners, addr);
                     info!("Peer {} disconnected", addr);
                 }
                 ControlSignal::SendToPeer((_receiver, _msg)) => {
@@ -160,6 +180,11 @@ impl Context {
                         break;
                     }
                 };
+                // a huge frame header would make us allocate as much
+                if msg_size as u64 > message::MAX_MSG_BYTES {
+                    warn!("Disconnecting peer {} sending a frame of {} bytes", addr, msg_size);
+                    break;
+                }
                 // then, read exactly msg_size bytes to get the whole message
                 if msg_buffer.len() < msg_size as usize {
                     msg_buffer.resize(msg_size as usize, 0);
@@ -180,7 +205,8 @@ impl Context {
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, so stop writing to it too
+            handle_copy.disconnect();
         })
             .detach();
 
@@ -189,7 +215,14 @@ impl Context {
         ex.spawn(async move {
             loop {
                 // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
+                let new_msg = match write_queue.next().await {
+                    Some(new_msg) => new_msg,
+                    None => {
+                        // the peer is disconnected or too slow, also stop reading from it
+                        let _ = stream.get_ref().shutdown(net::Shutdown::Both);
+                        break;
+                    }
+                };
 
                 // second, encode the length of the message
                 let size_buffer = (new_msg.len() as u32).to_be_bytes();
@@ -223,7 +256,7 @@ impl Context {
             .detach();
 
         // insert the peer handle so that we can broadcast to this guy later
-        self.peers.insert(addr, handle.clone());
+        self.peers.lock().unwrap().insert(addr, handle.clone());
         Ok(handle)
     }
 }
@@ -231,7 +264,20 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
+    peers: Arc<Mutex<HashMap<std::net::SocketAddr, peer::Handle>>>,
+    node_id: u64,
+    peer_ids: Arc<Mutex<HashMap<std::net::SocketAddr, u64>>>,
+    disconnect_listeners: DisconnectListeners,
+}
+
+/// Senders of the addresses of disconnected peers, one per subscriber
+type DisconnectListeners = Arc<Mutex<Vec<smol::channel::Sender<std::net::SocketAddr>>>>;
+
+fn notify_disconnect(listeners: &DisconnectListeners, addr: std::net::SocketAddr) {
+    // a subscriber that is gone stops being notified
+    listeners.lock().unwrap().retain(|listener| listener.try_send(addr).is_ok());
 }
+
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver{
     control_chan: smol::channel::Receiver<ControlSignal>,
@@ -263,6 +309,69 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
 
+    /// Broadcast without blocking, return false if the server is stopped or too busy to take it
+    pub fn try_broadcast(&self, msg: message::Message) -> bool {
+        self.control_chan.try_send(ControlSignal::BroadcastMessage(msg)).is_ok()
+    }
+
+    /// Get the number of connected peers
+    pub fn peer_count(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    /// Get the addresses of connected peers
+    pub fn peer_addrs(&self) -> Vec<std::net::SocketAddr> {
+        self.peers.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Get the handle of a connected peer, e.g. to ask it rather than the sender of a message
+    pub fn peer(&self, addr: &std::net::SocketAddr) -> Option<peer::Handle> {
+        self.peers.lock().unwrap().get(addr).cloned()
+    }
+
+    /// Get the number of messages queued to each connected peer, for diagnostics
+    pub fn peer_queue_depths(&self) -> Vec<(std::net::SocketAddr, usize)> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, hd)| (*addr, hd.queue_depth()))
+            .collect()
+    }
+
+    /// Get the random id of this node, which peers see in our `Version`
+    pub fn node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    /// Record the node id a peer announced, return false if it is ours or the one of another
+    /// connected peer, i.e. the connection loops back or duplicates another
+    pub fn identify_peer(&self, addr: std::net::SocketAddr, node_id: u64) -> bool {
+        if node_id == self.node_id {
+            return false;
+        }
+        let mut peer_ids = self.peer_ids.lock().unwrap();
+        if peer_ids.iter().any(|(other_addr, id)| *id == node_id && *other_addr != addr) {
+            return false;
+        }
+        peer_ids.insert(addr, node_id);
+        true
+    }
+
+    /// Get the address of each peer once it disconnects, e.g. to forget what is kept about it
+    pub fn subscribe_disconnects(&self) -> smol::channel::Receiver<std::net::SocketAddr> {
+        let (sender, receiver) = smol::channel::unbounded();
+        self.disconnect_listeners.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Close the connection to a peer
+    pub fn disconnect_peer(&self, addr: std::net::SocketAddr) {
+        if let Some(hd) = self.peers.lock().unwrap().get(&addr) {
+            hd.disconnect();
+        }
+    }
+
     pub fn send(&self, receiver: Address, msg: message::Message) {
         smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
     }
@@ -270,10 +379,27 @@ impl Handle {
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
-        let h = Handle {control_chan: s};
+        let h = Handle {
+            control_chan: s,
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            node_id: rand::random(),
+            peer_ids: Arc::new(Mutex::new(HashMap::new())),
+            disconnect_listeners: Arc::new(Mutex::new(vec![])),
+        };
         let t = TestReceiver {control_chan: r};
         (h,t)
     }
+
+    #[cfg(any(test,test_utilities))]
+    pub fn insert_test_peer(&self, handle: peer::Handle) {
+        self.peers.lock().unwrap().insert(*handle.addr(), handle);
+    }
+
+    #[cfg(any(test,test_utilities))]
+    pub fn remove_test_peer(&self, addr: std::net::SocketAddr) {
+        self.peers.lock().unwrap().remove(&addr);
+        notify_disconnect(&self.disconnect_listeners, addr);
+    }
 }
 
 enum ControlSignal {
@@ -286,3 +412,93 @@ enum ControlSignal {
     DroppedPeer(std::net::SocketAddr),
     SendToPeer((Address,message::Message)),
 }
+
+#[cfg(test)]
+mod test {
+    use ntest::timeout;
+    use std::thread;
+    use std::time;
+
+    #[test]
+    #[timeout(60000)]
+    fn count_connected_peers() {
+        let addr_a = "127.0.0.1:16170".parse().unwrap();
+        let addr_b = "127.0.0.1:16171".parse().unwrap();
+        let (msg_tx_a, _msg_rx_a) = smol::channel::unbounded();
+        let (msg_tx_b, _msg_rx_b) = smol::channel::unbounded();
+        let (ctx_a, server_a) = super::new(addr_a, msg_tx_a).unwrap();
+        let (ctx_b, server_b) = super::new(addr_b, msg_tx_b).unwrap();
+        ctx_a.start().unwrap();
+        ctx_b.start().unwrap();
+        assert_eq!(server_a.peer_count(), 0);
+        assert_eq!(server_b.peer_count(), 0);
+
+        server_b.connect(addr_a).unwrap();
+        assert_eq!(server_b.peer_count(), 1);
+        assert_eq!(server_b.peer_addrs(), vec![addr_a]);
+        // the incoming side registers the peer asynchronously
+        while server_a.peer_count() == 0 {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert_eq!(server_a.peer_count(), 1);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reject_self_and_duplicate_connections() {
+        let addr_a = "127.0.0.1:16172".parse().unwrap();
+        let addr_b = "127.0.0.1:16173".parse().unwrap();
+        let (msg_tx_a, _msg_rx_a) = smol::channel::unbounded();
+        let (msg_tx_b, _msg_rx_b) = smol::channel::unbounded();
+        let (ctx_a, _server_a) = super::new(addr_a, msg_tx_a).unwrap();
+        let (ctx_b, server_b) = super::new(addr_b, msg_tx_b).unwrap();
+        ctx_a.start().unwrap();
+        ctx_b.start().unwrap();
+
+        assert!(server_b.connect(addr_b).is_err());
+        server_b.connect(addr_a).unwrap();
+        assert!(server_b.connect(addr_a).is_err());
+        assert_eq!(server_b.peer_addrs(), vec![addr_a]);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn disconnect_peer_not_reading() {
+        use crate::network::message::Message;
+
+        let addr = "127.0.0.1:16176".parse().unwrap();
+        let (msg_tx, _msg_rx) = smol::channel::unbounded();
+        let (ctx, server) = super::new(addr, msg_tx).unwrap();
+        ctx.start().unwrap();
+        // a peer that connects and never reads what we write
+        let _stream = std::net::TcpStream::connect(addr).unwrap();
+        while server.peer_count() == 0 {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let peer_addr = server.peer_addrs()[0];
+        let mut hd = server.peer(&peer_addr).unwrap();
+
+        // fill the socket buffers until the writer is blocked and messages pile up
+        let payload = "x".repeat(1 << 20);
+        while hd.queue_depth() < 16 {
+            hd.write(Message::Ping(payload.clone()));
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        let disconnects = server.subscribe_disconnects();
+        server.disconnect_peer(peer_addr);
+        assert_eq!(smol::block_on(disconnects.recv()).unwrap(), peer_addr);
+        assert_eq!(server.peer_count(), 0);
+    }
+
+    #[test]
+    fn identify_peers_by_node_id() {
+        let (server, _server_receiver) = super::Handle::new_for_test();
+        let addr_a = "127.0.0.1:16174".parse().unwrap();
+        let addr_b = "127.0.0.1:16175".parse().unwrap();
+        assert!(!server.identify_peer(addr_a, server.node_id()));
+        assert!(server.identify_peer(addr_a, server.node_id().wrapping_add(1)));
+        // the same node again through another connection
+        assert!(!server.identify_peer(addr_b, server.node_id().wrapping_add(1)));
+        assert!(server.identify_peer(addr_a, server.node_id().wrapping_add(1)));
+    }
+}
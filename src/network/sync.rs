@@ -0,0 +1,64 @@
+use serde::Serialize;
+
+/// Progress of catching up with the longest chain advertised by peers
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncStatus {
+    pub current_height: u128,
+    pub best_known_height: u128,
+    pub in_progress: bool,
+}
+
+/// Keeps the highest tip height advertised by peers
+#[derive(Default)]
+pub struct SyncTracker {
+    best_advertised_height: u128,
+}
+
+impl SyncTracker {
+    pub fn new() -> Self {
+        SyncTracker {
+            best_advertised_height: 0,
+        }
+    }
+
+    /// Record the tip height advertised by a peer
+    pub fn on_advertised_tip(&mut self, height: u128) {
+        self.best_advertised_height = self.best_advertised_height.max(height);
+    }
+
+    /// Compare the local tip height with the best advertised one
+    pub fn status(&self, current_height: u128) -> SyncStatus {
+        SyncStatus {
+            current_height,
+            best_known_height: self.best_advertised_height.max(current_height),
+            in_progress: self.best_advertised_height > current_height,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SyncTracker;
+    use crate::blockchain::Blockchain;
+    use crate::types::block::generate_random_block;
+
+    #[test]
+    fn sync_in_progress_until_caught_up() {
+        let mut blockchain = Blockchain::new();
+        let mut tracker = SyncTracker::new();
+        assert!(!tracker.status(blockchain.tip_height()).in_progress);
+
+        tracker.on_advertised_tip(3);
+        let status = tracker.status(blockchain.tip_height());
+        assert_eq!(status.current_height, 0);
+        assert_eq!(status.best_known_height, 3);
+        assert!(status.in_progress);
+
+        for _ in 0..3 {
+            blockchain.insert(&generate_random_block(&blockchain.tip()));
+        }
+        let status = tracker.status(blockchain.tip_height());
+        assert_eq!(status.current_height, 3);
+        assert!(!status.in_progress);
+    }
+}
@@ -0,0 +1,116 @@
+use super::message::Message;
+use super::server::Handle as ServerHandle;
+use crate::blockchain::Blockchain;
+use crate::types::hash::H256;
+
+use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long the tip may stay unchanged before the node asks peers for newer blocks
+pub const STALE_TIP_TIMEOUT_MS: u128 = 5 * 60 * 1000;
+/// Interval between two checks of the tip
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Asks peers for the blocks after our tip when it stays unchanged for too long, since the node
+/// may be partitioned away from the miners
+pub struct StaleTipWatchdog {
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    timeout_ms: u128,
+    last_resync_ms: u128,
+}
+
+impl StaleTipWatchdog {
+    pub fn new(
+        server: &ServerHandle,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        timeout_ms: u128,
+    ) -> Self {
+        Self {
+            server: server.clone(),
+            blockchain: Arc::clone(blockchain),
+            timeout_ms,
+            last_resync_ms: 0,
+        }
+    }
+
+    pub fn start(mut self) {
+        thread::Builder::new()
+            .name("stale-tip-watchdog".to_string())
+            .spawn(move || loop {
+                thread::sleep(CHECK_INTERVAL);
+                self.check();
+            })
+            .unwrap();
+        info!("Stale tip watchdog started");
+    }
+
+    /// Broadcast a `GetHeaders` from our block locator if the tip, and the last resync, are older
+    /// than the timeout. Return whether it is broadcast.
+    pub fn check(&mut self) -> bool {
+        let _blockchain = self.blockchain.lock().unwrap();
+        let now_ms = _blockchain.clock().now_ms();
+        let last_activity_ms = _blockchain.last_tip_change_ms().max(self.last_resync_ms);
+        if now_ms.saturating_sub(last_activity_ms) <= self.timeout_ms {
+            return false;
+        }
+        let locator = _blockchain.block_locator();
+        drop(_blockchain);
+
+        warn!(
+            "No new block for {} ms, asking peers for newer blocks",
+            self.timeout_ms
+        );
+        self.last_resync_ms = now_ms;
+        self.server
+            .broadcast(Message::GetHeaders(locator, H256::from([0u8; 32])));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StaleTipWatchdog;
+    use crate::blockchain::{Blockchain, GenesisParams};
+    use crate::network::message::Message;
+    use crate::network::server::Handle as ServerHandle;
+    use crate::types::block::generate_random_block;
+    use crate::types::clock::MockClock;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn resync_after_stale_tip() {
+        let clock = Arc::new(MockClock::new(0));
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params_and_clock(
+            GenesisParams::default(),
+            clock.clone(),
+        )));
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let mut watchdog = StaleTipWatchdog::new(&server, &blockchain, 1000);
+
+        clock.set(1000);
+        assert!(!watchdog.check());
+        // a new tip defers the resync
+        let mut _blockchain = blockchain.lock().unwrap();
+        let block = generate_random_block(&_blockchain.tip());
+        _blockchain.insert(&block);
+        drop(_blockchain);
+        clock.set(2000);
+        assert!(!watchdog.check());
+
+        clock.set(2001);
+        assert!(watchdog.check());
+        let locator = blockchain.lock().unwrap().block_locator();
+        if let Some(Message::GetHeaders(sent_locator, _)) = server_receiver.recv() {
+            assert_eq!(sent_locator, locator);
+        } else {
+            panic!();
+        }
+        // not again until another timeout passes
+        assert!(!watchdog.check());
+        clock.advance(1001);
+        assert!(watchdog.check());
+    }
+}
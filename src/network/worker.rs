@@ -1,25 +1,94 @@
-use super::message::Message;
+use super::message::{Message, MAX_MSG_BYTES, PROTOCOL_VERSION};
+use super::metrics::NetworkMetrics;
+use super::orphans::OrphanPool;
 use super::peer;
+use super::peer_selector::{HighestTipSelector, PeerSelector};
+use super::rate_limit::PeerRateLimiter;
+use super::rejections::{RejectionLog, RejectionReason};
+use super::seen_cache::SeenCache;
 use super::server::Handle as ServerHandle;
-use crate::blockchain::Blockchain;
-use crate::types::block::Block;
+use super::sync::SyncTracker;
+use crate::blockchain::{Blockchain, ReorgInfo};
+use crate::mempool::Mempool;
+use crate::types::block::{Block, Content, Header};
+use crate::types::bloom::BloomFilter;
 use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::{SignedTransaction, TxValidationError};
 
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
+use rand::seq::SliceRandom;
 
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 #[cfg(any(test, test_utilities))]
 use super::peer::TestReceiver as PeerTestReceiver;
 #[cfg(any(test, test_utilities))]
 use super::server::TestReceiver as ServerTestReceiver;
+
+/// How long a requested block hash stays in-flight before it may be requested again
+const GET_BLOCKS_TIMEOUT: Duration = Duration::from_secs(5);
+/// Max number of headers carried by one `Headers` message
+pub const MAX_HEADERS_PER_MSG: usize = 2000;
+/// Max number of transactions carried by one `Transactions` message
+pub const MAX_TXS_PER_MSG: usize = 100;
+/// Max serialized size of a transaction relayed from peers, larger ones are dropped
+pub const MAX_TX_BYTES: usize = 1024;
+/// Max number of hashes served from one `GetTransactions` request, the rest are ignored
+pub const MAX_TX_HASHES_PER_REQUEST: usize = 10 * MAX_TXS_PER_MSG;
+/// Number of recently seen txids remembered, so that each transaction is broadcast once
+const SEEN_TXS_CACHE_SIZE: usize = 10000;
+/// Max number of blocks waiting for their missing parent, the earliest one is dropped when full
+const MAX_ORPHAN_BLOCKS: usize = 100;
+/// How long a block waits for its missing parent before it is dropped
+const ORPHAN_BLOCK_TIMEOUT: Duration = Duration::from_secs(600);
+/// Max number of compact blocks waiting for their missing transactions, the next ones are
+/// downloaded in full
+const MAX_PENDING_COMPACT_BLOCKS: usize = 100;
+/// How long a compact block waits for its missing transactions before it is dropped
+const PENDING_COMPACT_BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Max number of messages of a peer kept until its `Version` arrives, the next ones are dropped
+const MAX_PRE_HANDSHAKE_MSGS: usize = 100;
+
+/// Number of latest rejected blocks and transactions kept for debugging
+const REJECTION_LOG_SIZE: usize = 100;
+
+/// Number of `Blocks` and `CompactBlock` messages a peer may send in a burst, excess ones are
+/// dropped
+pub const BLOCK_MSGS_BURST: u32 = 100;
+/// Number of `Blocks` and `CompactBlock` messages per second a peer may send after a burst
+pub const BLOCK_MSGS_PER_SEC: f64 = 50.0;
+
+/// Header and txids of a compact block, with the peer that sent it and when
+type PendingCompactBlock = (Header, Vec<H256>, SocketAddr, Instant);
+
 #[derive(Clone)]
 pub struct Worker {
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    /// block hash -> (time of last GetBlocks, whether it retries one timed out)
+    in_flight_blocks: Arc<Mutex<HashMap<H256, (Instant, bool)>>>,
+    orphan_buffer: Arc<Mutex<OrphanPool>>,
+    pending_compact_blocks: Arc<Mutex<HashMap<H256, PendingCompactBlock>>>, // waiting for some txs
+    sync_tracker: Arc<Mutex<SyncTracker>>,
+    seen_txs: Arc<Mutex<SeenCache<H256>>>,
+    handshaken_peers: Arc<Mutex<HashSet<SocketAddr>>>, // peers with a compatible `Version`
+    pre_handshake_msgs: Arc<Mutex<HashMap<SocketAddr, Vec<Message>>>>, // waiting for `Version`
+    rejected_peers: Arc<Mutex<HashSet<SocketAddr>>>,   // peers with an incompatible `Version`
+    metrics: Arc<NetworkMetrics>,
+    rejections: Arc<RejectionLog>,
+    peer_selector: Arc<dyn PeerSelector>, // which peer to ask for missing blocks
+    block_rate_limiter: Arc<PeerRateLimiter>,
+    relay_fanout: Option<usize>, // number of random peers to announce to, or all of them if None
+    max_msg_bytes: u64,
+    disconnects: smol::channel::Receiver<SocketAddr>, // addresses of peers once they disconnect
 }
 
 impl Worker {
@@ -28,17 +97,88 @@ impl Worker {
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        sync_tracker: &Arc<Mutex<SyncTracker>>,
     ) -> Self {
+        // without any thread, messages would never be processed
+        let num_worker = if num_worker == 0 {
+            warn!("No network worker thread requested, using 1 instead");
+            1
+        } else {
+            num_worker
+        };
         Self {
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
             blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            in_flight_blocks: Arc::new(Mutex::new(HashMap::new())),
+            orphan_buffer: Arc::new(Mutex::new(OrphanPool::new(
+                MAX_ORPHAN_BLOCKS,
+                ORPHAN_BLOCK_TIMEOUT,
+            ))),
+            pending_compact_blocks: Arc::new(Mutex::new(HashMap::new())),
+            sync_tracker: Arc::clone(sync_tracker),
+            seen_txs: Arc::new(Mutex::new(SeenCache::new(SEEN_TXS_CACHE_SIZE))),
+            handshaken_peers: Arc::new(Mutex::new(HashSet::new())),
+            pre_handshake_msgs: Arc::new(Mutex::new(HashMap::new())),
+            rejected_peers: Arc::new(Mutex::new(HashSet::new())),
+            metrics: Arc::new(NetworkMetrics::new()),
+            rejections: Arc::new(RejectionLog::new(REJECTION_LOG_SIZE)),
+            peer_selector: Arc::new(HighestTipSelector::new()),
+            block_rate_limiter: Arc::new(PeerRateLimiter::new(
+                BLOCK_MSGS_BURST,
+                BLOCK_MSGS_PER_SEC,
+            )),
+            relay_fanout: None,
+            max_msg_bytes: MAX_MSG_BYTES,
+            disconnects: server.subscribe_disconnects(),
         }
     }
 
+    /// Get the counters of received messages, which keep counting after the worker starts
+    pub fn metrics(&self) -> Arc<NetworkMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Get the latest blocks and transactions rejected, with their reasons and peers
+    pub fn rejections(&self) -> Arc<RejectionLog> {
+        Arc::clone(&self.rejections)
+    }
+
+    /// Keep the given number of latest rejections instead, forgetting the ones kept so far
+    pub fn set_rejection_log_size(&mut self, size: usize) {
+        self.rejections = Arc::new(RejectionLog::new(size));
+    }
+
+    /// Choose the peer to ask for missing blocks with the given policy, instead of the one
+    /// preferring the highest advertised tip
+    pub fn set_peer_selector(&mut self, peer_selector: Arc<dyn PeerSelector>) {
+        self.peer_selector = peer_selector;
+    }
+
+    /// Let each peer send bursts of `burst` block messages, then `per_sec` of them per second
+    pub fn set_block_rate_limit(&mut self, burst: u32, per_sec: f64) {
+        self.block_rate_limiter = Arc::new(PeerRateLimiter::new(burst, per_sec));
+    }
+
+    /// Announce new blocks and transactions to `fanout` random peers, or to all of them if None,
+    /// which is the default. The peers announced to relay further, so fewer limit the traffic of
+    /// a large network without keeping the announcements from spreading.
+    pub fn set_relay_fanout(&mut self, fanout: Option<usize>) {
+        self.relay_fanout = fanout;
+    }
+
+    /// Reject messages larger than `max_msg_bytes` serialized, instead of `MAX_MSG_BYTES`. The
+    /// server disconnects peers sending more than `MAX_MSG_BYTES` anyway.
+    pub fn set_max_msg_bytes(&mut self, max_msg_bytes: u64) {
+        self.max_msg_bytes = max_msg_bytes;
+    }
+
     pub fn start(self) {
         let num_worker = self.num_worker;
+        info!("Starting {} network worker threads", num_worker);
         for i in 0..num_worker {
             let cloned = self.clone();
             thread::spawn(move || {
@@ -46,6 +186,27 @@ impl Worker {
                 warn!("Worker thread {} exited", i);
             });
         }
+        let cloned = self.clone();
+        thread::spawn(move || cloned.disconnect_loop());
+    }
+
+    /// Forget what is kept about each peer once it disconnects
+    fn disconnect_loop(&self) {
+        while let Ok(addr) = smol::block_on(self.disconnects.recv()) {
+            debug!("Forgetting disconnected peer {}", addr);
+            self.orphan_buffer.lock().unwrap().forget_peer(&addr);
+            self.pending_compact_blocks
+                .lock()
+                .unwrap()
+                .retain(|_, (_, _, peer, _)| *peer != addr);
+            self.peer_selector.on_disconnect(addr);
+            // the lock order of the message handling
+            let mut handshaken_peers = self.handshaken_peers.lock().unwrap();
+            handshaken_peers.remove(&addr);
+            self.pre_handshake_msgs.lock().unwrap().remove(&addr);
+            drop(handshaken_peers);
+            self.rejected_peers.lock().unwrap().remove(&addr);
+        }
     }
 
     fn worker_loop(&self) {
@@ -57,72 +218,753 @@ impl Worker {
             }
             let msg = result.unwrap();
             let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
-            match msg {
-                Message::Ping(nonce) => {
-                    debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
+            // the limit is checked against the lengths declared inside too, e.g. of a vector,
+            // before allocating them. Deserializing from a slice would ignore it.
+            let msg: Message = match bincode::config()
+                .limit(self.max_msg_bytes)
+                .deserialize_from(&msg[..])
+            {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Malformed message from peer {}: {}", peer.addr(), e);
+                    self.metrics.record_malformed();
+                    continue;
+                }
+            };
+            self.metrics.record(&msg);
+            if self.rejected_peers.lock().unwrap().contains(peer.addr()) {
+                debug!("Ignoring message from rejected peer {}", peer.addr());
+                continue;
+            }
+            if let Message::Blocks(_) | Message::CompactBlock { .. } = msg {
+                if !self.block_rate_limiter.allow(*peer.addr()) {
+                    debug!("Dropping blocks from peer {} over its rate", peer.addr());
+                    continue;
+                }
+            }
+            if !matches!(msg, Message::Version { .. }) {
+                // the other messages of a peer wait for its `Version`, which may arrive later
+                // through another worker thread
+                let handshaken_peers = self.handshaken_peers.lock().unwrap();
+                if !handshaken_peers.contains(peer.addr()) {
+                    let mut pre_handshake_msgs = self.pre_handshake_msgs.lock().unwrap();
+                    let msg_vec = pre_handshake_msgs.entry(*peer.addr()).or_default();
+                    if msg_vec.len() < MAX_PRE_HANDSHAKE_MSGS {
+                        msg_vec.push(msg);
+                    } else {
+                        debug!(
+                            "Dropping message from peer {} before handshake",
+                            peer.addr()
+                        );
+                    }
+                    continue;
+                }
+            }
+            self.handle_message(msg, &mut peer);
+        }
+    }
+
+    /// Handle a message from a peer, once the handshake with it is completed
+    fn handle_message(&self, msg: Message, peer: &mut peer::Handle) {
+        match msg {
+            Message::Ping(nonce) => {
+                debug!("Ping: {}", nonce);
+                peer.write(Message::Pong(nonce.to_string()));
+            }
+            Message::Pong(nonce) => {
+                debug!("Pong: {}", nonce);
+            }
+            Message::NewBlockHashes(hash_vec) => {
+                let _blockchain = self.blockchain.lock().unwrap();
+
+                let missed_hash_vec: Vec<H256> = hash_vec
+                    .into_iter()
+                    .filter(|hash| !_blockchain.hash_to_block.contains_key(&hash))
+                    .collect();
+                drop(_blockchain);
+
+                let missed_hash_vec = self.filter_in_flight(missed_hash_vec);
+                if !missed_hash_vec.is_empty() {
+                    self.request_blocks(peer, missed_hash_vec);
+                }
+            }
+            Message::GetBlocks(hash_vec) => {
+                let _blockchain = self.blockchain.lock().unwrap();
+
+                let block_vec: Vec<Block> = hash_vec
+                    .into_iter()
+                    .filter(|hash| _blockchain.hash_to_block.contains_key(&hash))
+                    .map(|hash| Block::clone(&_blockchain.hash_to_block[&hash])) // send a deep copy
+                    .collect();
+                drop(_blockchain);
+
+                // up to `MAX_HEADERS_PER_MSG` blocks may be requested at once, which would not fit
+                // in one message
+                for chunk in Self::split_blocks(block_vec, MAX_MSG_BYTES) {
+                    peer.write(Message::Blocks(chunk));
+                }
+            }
+            Message::Blocks(blocks_vec) => {
+                self.process_blocks(peer, blocks_vec);
+            }
+            Message::CompactBlock { header, txids } => {
+                let block_hash = header.hash();
+                if self
+                    .blockchain
+                    .lock()
+                    .unwrap()
+                    .hash_to_block
+                    .contains_key(&block_hash)
+                {
+                    return;
                 }
-                Message::Pong(nonce) => {
-                    debug!("Pong: {}", nonce);
+                if let Some(block) = self.reconstruct_block(&header, &txids, &[]) {
+                    self.process_blocks(peer, vec![block]);
+                    return;
                 }
-                Message::NewBlockHashes(hash_vec) => {
-                    let _blockchain = self.blockchain.lock().unwrap();
 
-                    let missed_hash_vec: Vec<H256> = hash_vec
-                        .into_iter()
-                        .filter(|hash| !_blockchain.hash_to_block.contains_key(&hash))
-                        .collect();
+                let _mempool = self.mempool.lock().unwrap();
+                let missed_txid_vec: Vec<H256> = txids
+                    .iter()
+                    .filter(|txid| !_mempool.contains(txid))
+                    .cloned()
+                    .collect();
+                drop(_mempool);
+                let now = Instant::now();
+                let mut pending_compact_blocks = self.pending_compact_blocks.lock().unwrap();
+                pending_compact_blocks.retain(|_, (_, _, _, received_at)| {
+                    now.duration_since(*received_at) < PENDING_COMPACT_BLOCK_TIMEOUT
+                });
+                // with every transaction at hand, the merkle root is wrong
+                if missed_txid_vec.is_empty()
+                    || pending_compact_blocks.len() >= MAX_PENDING_COMPACT_BLOCKS
+                {
+                    drop(pending_compact_blocks);
+                    self.request_full_block(peer, block_hash);
+                    return;
+                }
+                pending_compact_blocks.insert(block_hash, (header, txids, *peer.addr(), now));
+                drop(pending_compact_blocks);
+                peer.write(Message::GetBlockTransactions(block_hash, missed_txid_vec));
+            }
+            Message::GetBlockTransactions(block_hash, txids) => {
+                let _blockchain = self.blockchain.lock().unwrap();
+                let block = match _blockchain.hash_to_block.get(&block_hash) {
+                    Some(block) => block,
+                    None => return,
+                };
+                let txid_set: HashSet<H256> = txids.into_iter().collect();
+                let tx_vec: Vec<SignedTransaction> = block
+                    .content()
+                    .data
+                    .iter()
+                    .filter(|tx| txid_set.contains(&tx.txid()))
+                    .cloned()
+                    .collect();
+                drop(_blockchain);
 
-                    if !missed_hash_vec.is_empty() {
-                        peer.write(Message::GetBlocks(missed_hash_vec));
-                    }
+                peer.write(Message::BlockTransactions(block_hash, tx_vec));
+            }
+            Message::BlockTransactions(block_hash, tx_vec) => {
+                let pending = self
+                    .pending_compact_blocks
+                    .lock()
+                    .unwrap()
+                    .remove(&block_hash);
+                let (header, txids, _, _) = match pending {
+                    Some(pending) => pending,
+                    None => return,
+                };
+                match self.reconstruct_block(&header, &txids, &tx_vec) {
+                    Some(block) => self.process_blocks(peer, vec![block]),
+                    None => self.request_full_block(peer, block_hash),
+                }
+            }
+            Message::GetHeaders(locator, stop) => {
+                let _blockchain = self.blockchain.lock().unwrap();
+                let header_vec: Vec<Header> = _blockchain
+                    .branch_after_locator(&locator, &stop, MAX_HEADERS_PER_MSG)
+                    .into_iter()
+                    .filter_map(|hash| _blockchain.get_header(&hash))
+                    .collect();
+                drop(_blockchain);
+
+                if !header_vec.is_empty() {
+                    peer.write(Message::Headers(header_vec));
+                }
+            }
+            Message::Headers(header_vec) => {
+                let _blockchain = self.blockchain.lock().unwrap();
+                // validate the whole header chain before downloading any body
+                if let Err(e) = _blockchain.validate_header_chain(&header_vec) {
+                    warn!("Invalid header chain from peer {}: {:?}", peer.addr(), e);
+                    return;
                 }
-                Message::GetBlocks(hash_vec) => {
-                    let _blockchain = self.blockchain.lock().unwrap();
 
-                    let block_vec: Vec<Block> = hash_vec
-                        .into_iter()
-                        .filter(|hash| _blockchain.hash_to_block.contains_key(&hash))
-                        .map(|hash| _blockchain.hash_to_block[&hash].clone()) // send a deep copy
-                        .collect();
+                let missed_hash_vec: Vec<H256> = header_vec
+                    .iter()
+                    .map(|header| header.hash())
+                    .filter(|hash| !_blockchain.hash_to_block.contains_key(hash))
+                    .collect();
+                drop(_blockchain);
 
-                    if !block_vec.is_empty() {
-                        peer.write(Message::Blocks(block_vec));
-                    }
+                let missed_hash_vec = self.filter_in_flight(missed_hash_vec);
+                if !missed_hash_vec.is_empty() {
+                    peer.write(Message::GetBlocks(missed_hash_vec));
                 }
-                Message::Blocks(blocks_vec) => {
-                    let mut _blockchain = self.blockchain.lock().unwrap();
-                    let mut new_block_vec = vec![];
+            }
+            Message::NewTransactionHashes(hash_vec) => {
+                let _mempool = self.mempool.lock().unwrap();
 
-                    for block_item in blocks_vec {
-                        // TODO: validate block
-                        // TODO: handle block's parent's missing: put to buffer, get blocks from peers
+                let missed_hash_vec: Vec<H256> = hash_vec
+                    .into_iter()
+                    .filter(|hash| !_mempool.contains(hash))
+                    .collect();
 
-                        let item_hash = block_item.hash();
-                        if !_blockchain.hash_to_block.contains_key(&item_hash) {
-                            _blockchain.insert(&block_item);
-                            new_block_vec.push(item_hash);
+                if !missed_hash_vec.is_empty() {
+                    peer.write(Message::GetTransactions(missed_hash_vec));
+                }
+            }
+            Message::GetTransactions(mut hash_vec) => {
+                if hash_vec.len() > MAX_TX_HASHES_PER_REQUEST {
+                    debug!(
+                        "GetTransactions with {} hashes, only serving the first {}",
+                        hash_vec.len(),
+                        MAX_TX_HASHES_PER_REQUEST
+                    );
+                    hash_vec.truncate(MAX_TX_HASHES_PER_REQUEST);
+                }
+                let _mempool = self.mempool.lock().unwrap();
+
+                let tx_vec: Vec<SignedTransaction> = hash_vec
+                    .into_iter()
+                    .filter_map(|hash| _mempool.get(&hash).cloned())
+                    .collect();
+                drop(_mempool);
+
+                // split the reply to keep each frame bounded
+                for tx_chunk in tx_vec.chunks(MAX_TXS_PER_MSG) {
+                    peer.write(Message::Transactions(tx_chunk.to_vec()));
+                }
+            }
+            Message::Transactions(signed_tx_vec) => {
+                let _blockchain = self.blockchain.lock().unwrap();
+                let mut _mempool = self.mempool.lock().unwrap();
+                let mut seen_txs = self.seen_txs.lock().unwrap();
+                let mut new_tx_vec = vec![];
+
+                for tx_item in signed_tx_vec {
+                    // a replayed transaction is neither re-inserted nor re-broadcast. Only those
+                    // accepted or invalid are seen, one waiting for a block may be accepted later.
+                    if seen_txs.contains(&tx_item.txid()) {
+                        continue;
+                    }
+                    // a signature or public key of any length still deserializes
+                    if tx_item.size_bytes() > MAX_TX_BYTES {
+                        warn!(
+                            "Oversized transaction {} of {} bytes from peer {}",
+                            tx_item.txid(),
+                            tx_item.size_bytes(),
+                            peer.addr()
+                        );
+                        self.rejections.record(
+                            tx_item.txid(),
+                            RejectionReason::Transaction(TxValidationError::Oversized),
+                            *peer.addr(),
+                        );
+                        seen_txs.insert(tx_item.txid());
+                        continue;
+                    }
+                    if let Err(e) = tx_item.validate() {
+                        warn!(
+                            "Invalid transaction {} from peer {}: {:?}",
+                            tx_item.txid(),
+                            peer.addr(),
+                            e
+                        );
+                        self.rejections.record(
+                            tx_item.txid(),
+                            RejectionReason::Transaction(e),
+                            *peer.addr(),
+                        );
+                        seen_txs.insert(tx_item.txid());
+                        continue;
+                    }
+                    // same check as for local transactions, except that one of a nonce after
+                    // the pending ones waits as an orphan
+                    let tip_state = _blockchain.tip_state();
+                    let transaction = tx_item.transaction();
+                    let pending = _mempool.pending_before(
+                        &transaction.sender(),
+                        tip_state,
+                        transaction.nonce(),
+                    );
+                    let nonce_ahead = transaction.nonce()
+                        > tip_state.account(&transaction.sender()).nonce + pending.len() as u64;
+                    match _blockchain.would_accept_tx_after(&tx_item, &_blockchain.tip(), &pending)
+                    {
+                        Ok(()) => {}
+                        Err(TxValidationError::BadNonce) if nonce_ahead => {}
+                        Err(e) => {
+                            // not seen, a later block may fund the sender
+                            warn!(
+                                "Transaction {} from peer {} does not apply on the tip: {:?}",
+                                tx_item.txid(),
+                                peer.addr(),
+                                e
+                            );
+                            self.rejections.record(
+                                tx_item.txid(),
+                                RejectionReason::Transaction(e),
+                                *peer.addr(),
+                            );
+                            continue;
                         }
                     }
+                    for txid in _mempool.insert_or_orphan(&tx_item, tip_state) {
+                        seen_txs.insert(txid);
+                        new_tx_vec.push(txid);
+                    }
+                }
+                drop(seen_txs);
+                drop(_mempool);
+                drop(_blockchain);
+
+                if !new_tx_vec.is_empty() {
+                    self.relay(Message::NewTransactionHashes(new_tx_vec));
+                }
+            }
+            Message::TransactionBatches(batch_vec) => {
+                let mut _mempool = self.mempool.lock().unwrap();
+                let mut seen_txs = self.seen_txs.lock().unwrap();
+                let mut new_batch_vec = vec![];
 
-                    if !new_block_vec.is_empty() {
-                        self.server
-                            .broadcast(Message::NewBlockHashes(new_block_vec));
+                for batch in batch_vec {
+                    let batch_hash = batch.hash();
+                    // seen like transactions, once accepted or invalid
+                    if seen_txs.contains(&batch_hash) {
+                        continue;
+                    }
+                    let result = if batch.size_bytes() > MAX_TX_BYTES * batch.transactions().len() {
+                        Err(TxValidationError::Oversized)
+                    } else {
+                        batch.validate()
+                    };
+                    if let Err(e) = result {
+                        warn!(
+                            "Invalid transaction batch {} from peer {}: {:?}",
+                            batch_hash,
+                            peer.addr(),
+                            e
+                        );
+                        self.rejections.record(
+                            batch_hash,
+                            RejectionReason::Transaction(e),
+                            *peer.addr(),
+                        );
+                        seen_txs.insert(batch_hash);
+                        continue;
+                    }
+                    // whether it applies on the state is checked by the miner
+                    if _mempool.insert_batch(&batch) {
+                        seen_txs.insert(batch_hash);
+                        new_batch_vec.push(batch);
                     }
                 }
-                Message::NewTransactionHashes(hash_vec) => {
-                    // TODO:
+                drop(seen_txs);
+                drop(_mempool);
+
+                // batches are not announced by hash, so they are relayed in full
+                if !new_batch_vec.is_empty() {
+                    self.relay(Message::TransactionBatches(new_batch_vec));
                 }
-                Message::GetTransactions(hash_vec) => {
-                    // TODO:
+            }
+            Message::HaveBlocksFilter(filter_bytes) => {
+                let filter = match BloomFilter::from_bytes(&filter_bytes) {
+                    Some(filter) => filter,
+                    None => {
+                        warn!("Malformed blocks filter from peer {}", peer.addr());
+                        return;
+                    }
+                };
+                let _blockchain = self.blockchain.lock().unwrap();
+
+                // the peer may lack a block in its filter only through a false positive
+                let unknown_hash_vec: Vec<H256> = _blockchain
+                    .all_blocks_in_longest_chain()
+                    .into_iter()
+                    .filter(|hash| !filter.contains(hash))
+                    .collect();
+                drop(_blockchain);
+
+                if !unknown_hash_vec.is_empty() {
+                    peer.write(Message::NewBlockHashes(unknown_hash_vec));
+                }
+            }
+            Message::Version {
+                protocol_version,
+                genesis,
+                node_id,
+            } => {
+                let local_genesis = self.blockchain.lock().unwrap().genesis_hash();
+                if protocol_version != PROTOCOL_VERSION || genesis != local_genesis {
+                    warn!(
+                        "Rejecting peer {} with protocol version {} and genesis {}",
+                        peer.addr(),
+                        protocol_version,
+                        genesis
+                    );
+                    self.rejected_peers.lock().unwrap().insert(*peer.addr());
+                    peer.disconnect();
+                    return;
+                }
+                if !self.server.identify_peer(*peer.addr(), node_id) {
+                    warn!(
+                        "Dropping connection to peer {} with node id {}, which is ourselves \
+                         or already connected",
+                        peer.addr(),
+                        node_id
+                    );
+                    peer.disconnect();
+                    return;
                 }
-                Message::Transactions(signed_tx_vec) => {
-                    // TODO:
+                let mut handshaken_peers = self.handshaken_peers.lock().unwrap();
+                let first_version = handshaken_peers.insert(*peer.addr());
+                let msg_vec = self
+                    .pre_handshake_msgs
+                    .lock()
+                    .unwrap()
+                    .remove(peer.addr())
+                    .unwrap_or_default();
+                drop(handshaken_peers);
+                // answer once, so that both sides have checked each other
+                if first_version {
+                    peer.write(Message::Version {
+                        protocol_version: PROTOCOL_VERSION,
+                        genesis: local_genesis,
+                        node_id: self.server.node_id(),
+                    });
+                }
+                for msg in msg_vec {
+                    self.handle_message(msg, peer);
+                }
+            }
+            Message::Tip(tip_hash, tip_height) => {
+                self.sync_tracker
+                    .lock()
+                    .unwrap()
+                    .on_advertised_tip(tip_height);
+                self.peer_selector
+                    .on_advertised_tip(*peer.addr(), tip_height);
+                let _blockchain = self.blockchain.lock().unwrap();
+
+                // catch up through headers first when the peer is ahead
+                if tip_height > _blockchain.tip_height()
+                    && !_blockchain.hash_to_block.contains_key(&tip_hash)
+                {
+                    let locator = _blockchain.block_locator();
+                    drop(_blockchain);
+                    peer.write(Message::GetHeaders(locator, H256::from([0u8; 32])));
                 }
             }
         }
     }
+
+    /// Validate and insert received blocks, buffer those with a missing parent and request the
+    /// parent, then announce the newly inserted blocks
+    fn process_blocks(&self, peer: &mut peer::Handle, blocks_vec: Vec<Block>) {
+        let mut _blockchain = self.blockchain.lock().unwrap();
+        let mut new_block_vec = vec![];
+
+        let mut in_flight_blocks = self.in_flight_blocks.lock().unwrap();
+        for block_item in blocks_vec.iter() {
+            in_flight_blocks.remove(&block_item.hash());
+        }
+        drop(in_flight_blocks);
+
+        let mut _orphan_buffer = self.orphan_buffer.lock().unwrap();
+        let mut missed_parent_vec = vec![];
+        let mut reorg_vec = vec![];
+
+        for block_item in blocks_vec {
+            let item_hash = block_item.hash();
+            if _blockchain.hash_to_block.contains_key(&item_hash) {
+                continue;
+            }
+
+            let parent_hash = block_item.get_parent();
+            if parent_hash == H256::from([0u8; 32]) {
+                // a genesis block other than ours, the branch is from another network
+                warn!("Foreign genesis {} from peer {}", item_hash, peer.addr());
+                _orphan_buffer.discard_branch(&item_hash);
+                continue;
+            }
+            if !_blockchain.hash_to_block.contains_key(&parent_hash) {
+                _orphan_buffer.insert(block_item, *peer.addr(), Instant::now());
+                missed_parent_vec.push(parent_hash);
+                continue;
+            }
+            match _blockchain.submit_block(block_item) {
+                Ok(reorg) => reorg_vec.extend(reorg),
+                Err(e) => {
+                    warn!(
+                        "Invalid block {} from peer {}: {:?}",
+                        item_hash,
+                        peer.addr(),
+                        e
+                    );
+                    self.rejections
+                        .record(item_hash, RejectionReason::Block(e), *peer.addr());
+                    _orphan_buffer.discard_branch(&item_hash);
+                    continue;
+                }
+            }
+
+            new_block_vec.extend(Self::insert_orphans(
+                &mut _blockchain,
+                &mut _orphan_buffer,
+                &self.rejections,
+                item_hash,
+                &mut reorg_vec,
+            ));
+        }
+        drop(_orphan_buffer);
+        let mut promoted_tx_vec = vec![];
+        if !new_block_vec.is_empty() {
+            let mut _mempool = self.mempool.lock().unwrap();
+            // the transactions of orphaned blocks are pending again, unless the new chain has them
+            for reorg in reorg_vec.iter() {
+                let replayed_vec = _mempool.replay_reorg(&_blockchain, reorg);
+                debug!(
+                    "Reorg disconnecting {} blocks returned {} transactions to mempool",
+                    reorg.disconnected.len(),
+                    replayed_vec.len()
+                );
+            }
+            // the transactions of blocks joining the longest chain are not pending anymore
+            let connected_hashes: HashSet<H256> = new_block_vec
+                .iter()
+                .chain(reorg_vec.iter().flat_map(|reorg| reorg.connected.iter()))
+                .filter(|hash| _blockchain.confirmations(hash).is_some())
+                .cloned()
+                .collect();
+            for hash in connected_hashes.iter() {
+                _mempool.remove_confirmed(&_blockchain.hash_to_block[hash]);
+            }
+            // the new blocks may apply the nonces orphan transactions wait for
+            promoted_tx_vec = _mempool.update_orphans(_blockchain.tip_state());
+            let mut seen_txs = self.seen_txs.lock().unwrap();
+            for txid in promoted_tx_vec.iter() {
+                seen_txs.insert(*txid);
+            }
+            drop(seen_txs);
+            drop(_mempool);
+        }
+        drop(_blockchain);
+        // the transactions of an inserted block are not waited for anymore, e.g. once it is
+        // downloaded in full
+        let mut pending_compact_blocks = self.pending_compact_blocks.lock().unwrap();
+        for hash in new_block_vec.iter() {
+            pending_compact_blocks.remove(hash);
+        }
+        drop(pending_compact_blocks);
+
+        let missed_parent_vec = self.filter_in_flight(missed_parent_vec);
+        if !missed_parent_vec.is_empty() {
+            self.request_blocks(peer, missed_parent_vec);
+        }
+        if !new_block_vec.is_empty() {
+            self.relay(Message::NewBlockHashes(new_block_vec));
+        }
+        if !promoted_tx_vec.is_empty() {
+            self.relay(Message::NewTransactionHashes(promoted_tx_vec));
+        }
+    }
+
+    // Send an announcement to the peers chosen by the relay fanout
+    fn relay(&self, msg: Message) {
+        relay(&self.server, self.relay_fanout, msg);
+    }
+
+    /// Rebuild a block from its header and txids, taking each transaction from the given ones or
+    /// the mempool. Return None if any is missing or they do not match the merkle root.
+    fn reconstruct_block(
+        &self,
+        header: &Header,
+        txids: &[H256],
+        tx_vec: &[SignedTransaction],
+    ) -> Option<Block> {
+        let received: HashMap<H256, &SignedTransaction> =
+            tx_vec.iter().map(|tx| (tx.txid(), tx)).collect();
+        let _mempool = self.mempool.lock().unwrap();
+        let data = txids
+            .iter()
+            .map(|txid| {
+                received
+                    .get(txid)
+                    .cloned()
+                    .or_else(|| _mempool.get(txid))
+                    .cloned()
+            })
+            .collect::<Option<Vec<SignedTransaction>>>()?;
+        drop(_mempool);
+
+        // the batches are not announced, so a block with any is fetched in full
+        let block = Block::new(
+            header.clone(),
+            Content {
+                data,
+                batches: vec![],
+            },
+        );
+        if block.merkle_root() != header.merkle_root {
+            return None;
+        }
+        Some(block)
+    }
+
+    /// Ask the peer chosen by the peer selector for missing blocks announced by the sender, which
+    /// may be slower than a peer further ahead. The blocks requested again after a timeout are
+    /// asked from the sender, as the chosen peer may not have them.
+    fn request_blocks(&self, sender: &mut peer::Handle, hash_vec: Vec<H256>) {
+        let in_flight_blocks = self.in_flight_blocks.lock().unwrap();
+        let (retry_vec, hash_vec): (Vec<H256>, Vec<H256>) = hash_vec
+            .into_iter()
+            .partition(|hash| matches!(in_flight_blocks.get(hash), Some((_, true))));
+        drop(in_flight_blocks);
+        if !retry_vec.is_empty() {
+            sender.write(Message::GetBlocks(retry_vec));
+        }
+        if hash_vec.is_empty() {
+            return;
+        }
+
+        let selected = self
+            .peer_selector
+            .select(*sender.addr(), &self.server.peer_addrs());
+        match self.server.peer(&selected) {
+            Some(mut selected_peer) if selected != *sender.addr() => {
+                debug!(
+                    "Requesting blocks announced by peer {} from peer {}",
+                    sender.addr(),
+                    selected
+                );
+                selected_peer.write(Message::GetBlocks(hash_vec));
+            }
+            _ => sender.write(Message::GetBlocks(hash_vec)),
+        }
+    }
+
+    /// Fall back to downloading a whole block when its compact form cannot be rebuilt
+    fn request_full_block(&self, peer: &mut peer::Handle, block_hash: H256) {
+        debug!(
+            "Requesting full block {} from peer {}",
+            block_hash,
+            peer.addr()
+        );
+        if !self.filter_in_flight(vec![block_hash]).is_empty() {
+            peer.write(Message::GetBlocks(vec![block_hash]));
+        }
+    }
+
+    /// Split blocks, in order, into the contents of `Blocks` messages of at most `max_msg_bytes`
+    /// serialized. A larger block still gets a message of its own.
+    fn split_blocks(block_vec: Vec<Block>, max_msg_bytes: u64) -> Vec<Vec<Block>> {
+        let empty_msg_bytes = bincode::serialized_size(&Message::Blocks(vec![])).unwrap();
+        let mut chunks = vec![];
+        let mut chunk = vec![];
+        let mut chunk_bytes = empty_msg_bytes;
+        for block in block_vec {
+            let block_bytes = bincode::serialized_size(&block).unwrap();
+            if !chunk.is_empty() && chunk_bytes + block_bytes > max_msg_bytes {
+                chunks.push(std::mem::take(&mut chunk));
+                chunk_bytes = empty_msg_bytes;
+            }
+            chunk_bytes += block_bytes;
+            chunk.push(block);
+        }
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    /// Submit the orphan blocks waiting for a block just inserted, iteratively, adding the reorgs
+    /// they cause and recording the rejected ones. Return the hashes of the block and of all
+    /// inserted orphans.
+    fn insert_orphans(
+        blockchain: &mut Blockchain,
+        orphan_buffer: &mut OrphanPool,
+        rejections: &RejectionLog,
+        block_hash: H256,
+        reorg_vec: &mut Vec<ReorgInfo>,
+    ) -> Vec<H256> {
+        let mut inserted_hash_vec = vec![block_hash];
+        let mut parent_queue = vec![block_hash];
+
+        while let Some(parent_hash) = parent_queue.pop() {
+            for (orphan, orphan_peer) in orphan_buffer.take_children(&parent_hash) {
+                let orphan_hash = orphan.hash();
+                match blockchain.submit_block(orphan) {
+                    Ok(reorg) => {
+                        reorg_vec.extend(reorg);
+                        inserted_hash_vec.push(orphan_hash);
+                        parent_queue.push(orphan_hash);
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Invalid orphan block {} from peer {}: {:?}",
+                            orphan_hash, orphan_peer, e
+                        );
+                        rejections.record(orphan_hash, RejectionReason::Block(e), orphan_peer);
+                        orphan_buffer.discard_branch(&orphan_hash);
+                    }
+                }
+            }
+        }
+        inserted_hash_vec
+    }
+
+    /// Keep the hashes that are not already requested within `GET_BLOCKS_TIMEOUT`, and mark them
+    /// as requested
+    fn filter_in_flight(&self, hash_vec: Vec<H256>) -> Vec<H256> {
+        let now = Instant::now();
+        let mut in_flight_blocks = self.in_flight_blocks.lock().unwrap();
+
+        hash_vec
+            .into_iter()
+            .filter(|hash| {
+                let retry = match in_flight_blocks.get(hash) {
+                    Some((requested_at, _))
+                        if now.duration_since(*requested_at) < GET_BLOCKS_TIMEOUT =>
+                    {
+                        return false
+                    }
+                    Some(_) => true,
+                    None => false,
+                };
+                in_flight_blocks.insert(*hash, (now, retry));
+                true
+            })
+            .collect()
+    }
+}
+
+/// Send an announcement to `fanout` random peers, or to all of them if None, as the worker
+/// relays new blocks and transactions
+pub fn relay(server: &ServerHandle, fanout: Option<usize>, msg: Message) {
+    let fanout = match fanout {
+        Some(fanout) => fanout,
+        None => return server.broadcast(msg),
+    };
+    let peer_addrs = server.peer_addrs();
+    let chosen = peer_addrs.choose_multiple(&mut rand::thread_rng(), fanout);
+    for addr in chosen {
+        // the peer may disconnect in between
+        if let Some(mut peer) = server.peer(addr) {
+            peer.write(msg.clone());
+        }
+    }
 }
 
 #[cfg(any(test, test_utilities))]
@@ -140,35 +982,95 @@ impl TestMsgSender {
     }
 
     fn send(&self, msg: Message) -> PeerTestReceiver {
-        let bytes = bincode::serialize(&msg).unwrap();
+        self.send_raw(bincode::serialize(&msg).unwrap())
+    }
+
+    fn send_raw(&self, bytes: Vec<u8>) -> PeerTestReceiver {
         let (handle, r) = peer::Handle::test_handle();
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
     }
+
+    /// a peer that has not sent its `Version` yet, unlike the one of `send`
+    fn new_peer() -> (peer::Handle, PeerTestReceiver) {
+        peer::Handle::test_handle_with_addr("127.0.0.1:12320".parse().unwrap())
+    }
+
+    /// send as the peer of the given handle, e.g. one also connected to the server
+    fn send_from(&self, msg: Message, handle: &peer::Handle) {
+        let bytes = bincode::serialize(&msg).unwrap();
+        smol::block_on(self.s.send((bytes, handle.clone()))).unwrap();
+    }
 }
 #[cfg(any(test, test_utilities))]
 /// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
 fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H256>) {
+    let (test_msg_sender, server_receiver, all_hash_vec, _metrics) =
+        generate_test_worker_with_threads_and_start(1);
+    (test_msg_sender, server_receiver, all_hash_vec)
+}
+#[cfg(any(test, test_utilities))]
+/// also returns the message counters of the worker
+fn generate_test_worker_with_threads_and_start(
+    num_worker: usize,
+) -> (
+    TestMsgSender,
+    ServerTestReceiver,
+    Vec<H256>,
+    Arc<NetworkMetrics>,
+) {
+    let (worker, test_msg_sender, server_receiver, all_hash_vec) = generate_test_worker(num_worker);
+    let metrics = worker.metrics();
+    worker.start();
+    (test_msg_sender, server_receiver, all_hash_vec, metrics)
+}
+#[cfg(any(test, test_utilities))]
+/// returns the worker not started yet, e.g. to take its shared state first
+fn generate_test_worker(
+    num_worker: usize,
+) -> (Worker, TestMsgSender, ServerTestReceiver, Vec<H256>) {
     let _blockchain = Blockchain::new();
     let _blockchain = Arc::new(Mutex::new(_blockchain));
     let (server, server_receiver) = ServerHandle::new_for_test();
+    let _mempool = Arc::new(Mutex::new(Mempool::new()));
+    let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
     let (test_msg_sender, msg_chan) = TestMsgSender::new();
-    let worker = Worker::new(1, msg_chan, &server, &_blockchain);
-    worker.start();
+    let worker = Worker::new(
+        num_worker,
+        msg_chan,
+        &server,
+        &_blockchain,
+        &_mempool,
+        &sync_tracker,
+    );
+    // the peer of `TestMsgSender::send` has completed the handshake
+    let (test_peer, _) = peer::Handle::test_handle();
+    worker
+        .handshaken_peers
+        .lock()
+        .unwrap()
+        .insert(*test_peer.addr());
     let all_hash_vec = _blockchain.lock().unwrap().all_blocks_in_longest_chain();
-    (test_msg_sender, server_receiver, all_hash_vec)
+    (worker, test_msg_sender, server_receiver, all_hash_vec)
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
 mod test {
-    use crate::types::block::generate_random_block;
-    use crate::types::hash::Hashable;
+    use crate::types::address::random_address_with_keypair;
+    use crate::types::block::{generate_random_block, Block};
+    use crate::types::bloom::BloomFilter;
+    use crate::types::hash::{Hashable, H256};
+    use crate::types::merkle::MerkleTree;
+    use crate::types::transaction::{sign_transaction, SignedTransaction, Transaction};
     use ntest::timeout;
 
-    use super::super::message::Message;
-    use super::generate_test_worker_and_start;
+    use super::super::message::{Message, PROTOCOL_VERSION};
+    use super::{
+        generate_test_worker, generate_test_worker_and_start,
+        generate_test_worker_with_threads_and_start, TestMsgSender, MAX_TXS_PER_MSG, MAX_TX_BYTES,
+    };
 
     #[test]
     #[timeout(60000)]
@@ -186,6 +1088,32 @@ mod test {
     }
     #[test]
     #[timeout(60000)]
+    fn dedup_in_flight_get_blocks() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let random_block = generate_random_block(v.last().unwrap());
+        let other_block = generate_random_block(v.last().unwrap());
+        let mut peer_receiver =
+            test_msg_sender.send(Message::NewBlockHashes(vec![random_block.hash()]));
+        let reply = peer_receiver.recv();
+        if let Message::GetBlocks(v) = reply {
+            assert_eq!(v, vec![random_block.hash()]);
+        } else {
+            panic!();
+        }
+        // the first hash is still in-flight, so only the second one is requested
+        let mut peer_receiver = test_msg_sender.send(Message::NewBlockHashes(vec![
+            random_block.hash(),
+            other_block.hash(),
+        ]));
+        let reply = peer_receiver.recv();
+        if let Message::GetBlocks(v) = reply {
+            assert_eq!(v, vec![other_block.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
     fn reply_get_blocks() {
         let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
         let h = v.last().unwrap().clone();
@@ -211,6 +1139,952 @@ mod test {
             panic!();
         }
     }
+    #[test]
+    #[timeout(60000)]
+    fn request_missing_parent() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let parent_block = generate_random_block(v.last().unwrap());
+        let child_block = generate_random_block(&parent_block.hash());
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![child_block]));
+        let reply = peer_receiver.recv();
+        if let Message::GetBlocks(v) = reply {
+            assert_eq!(v, vec![parent_block.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn insert_orphan_after_parent() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let parent_block = generate_random_block(v.last().unwrap());
+        let child_block = generate_random_block(&parent_block.hash());
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![child_block.clone()]));
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![parent_block.clone()]));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::NewBlockHashes(v) = reply {
+            assert_eq!(v, vec![parent_block.hash(), child_block.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reject_foreign_genesis_branch() {
+        use std::sync::Arc;
+
+        let (worker, test_msg_sender, _server_receiver, v) = generate_test_worker(1);
+        let orphan_buffer = Arc::clone(&worker.orphan_buffer);
+        worker.start();
+        let foreign_genesis = generate_random_block(&[0u8; 32].into());
+        let foreign_block = generate_random_block(&foreign_genesis.hash());
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![foreign_block.clone()]));
+        let reply = peer_receiver.recv();
+        if let Message::GetBlocks(v) = reply {
+            assert_eq!(v, vec![foreign_genesis.hash()]);
+        } else {
+            panic!();
+        }
+        assert!(orphan_buffer
+            .lock()
+            .unwrap()
+            .contains(&foreign_block.hash()));
+        let mut _peer_receiver =
+            test_msg_sender.send(Message::Blocks(vec![foreign_genesis.clone()]));
+
+        // neither the foreign genesis nor its descendant is available from us
+        let mut peer_receiver = test_msg_sender.send(Message::GetBlocks(vec![
+            foreign_genesis.hash(),
+            foreign_block.hash(),
+            v[0],
+        ]));
+        let reply = peer_receiver.recv();
+        if let Message::Blocks(blocks) = reply {
+            assert_eq!(blocks.len(), 1);
+            assert_eq!(blocks[0].hash(), v[0]);
+        } else {
+            panic!();
+        }
+        assert!(orphan_buffer.lock().unwrap().is_empty());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn forget_orphans_of_disconnected_peer() {
+        use super::super::peer;
+        use std::sync::Arc;
+
+        let (worker, test_msg_sender, _server_receiver, v) = generate_test_worker(1);
+        let orphan_buffer = Arc::clone(&worker.orphan_buffer);
+        let server = worker.server.clone();
+        worker.start();
+        let parent_block = generate_random_block(v.last().unwrap());
+        let child_block = generate_random_block(&parent_block.hash());
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![child_block.clone()]));
+        peer_receiver.recv();
+        assert!(orphan_buffer.lock().unwrap().contains(&child_block.hash()));
+
+        server.remove_test_peer(*peer::Handle::test_handle().0.addr());
+        while !orphan_buffer.lock().unwrap().is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_get_headers_with_missing_branch() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let block_1 = generate_random_block(v.last().unwrap());
+        let block_2 = generate_random_block(&block_1.hash());
+        let mut _peer_receiver =
+            test_msg_sender.send(Message::Blocks(vec![block_1.clone(), block_2.clone()]));
+        server_receiver.recv().unwrap();
+
+        let mut peer_receiver =
+            test_msg_sender.send(Message::GetHeaders(v.clone(), [0u8; 32].into()));
+        let reply = peer_receiver.recv();
+        if let Message::Headers(header_vec) = reply {
+            let hash_vec: Vec<H256> = header_vec.iter().map(|header| header.hash()).collect();
+            assert_eq!(hash_vec, vec![block_1.hash(), block_2.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn request_bodies_after_valid_headers() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let block_1 = generate_random_block(v.last().unwrap());
+        let block_2 = generate_random_block(&block_1.hash());
+        let mut easier_block_2 = block_2.clone();
+        easier_block_2.header.difficulty = [254u8; 32].into();
+
+        // an invalid header chain is dropped, without asking for any body
+        let mut _peer_receiver = test_msg_sender.send(Message::Headers(vec![
+            block_1.header.clone(),
+            easier_block_2.header,
+        ]));
+        let mut peer_receiver = test_msg_sender.send(Message::Headers(vec![
+            block_1.header.clone(),
+            block_2.header.clone(),
+        ]));
+        let reply = peer_receiver.recv();
+        if let Message::GetBlocks(hash_vec) = reply {
+            assert_eq!(hash_vec, vec![block_1.hash(), block_2.hash()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_get_transactions_in_batches() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let tx_vec: Vec<SignedTransaction> = (0..MAX_TXS_PER_MSG + 1)
+            .map(|_| free_transaction())
+            .collect();
+        let hash_vec: Vec<H256> = tx_vec.iter().map(|tx| tx.txid()).collect();
+        let mut _peer_receiver = test_msg_sender.send(Message::Transactions(tx_vec));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::NewTransactionHashes(v) = reply {
+            assert_eq!(v.len(), MAX_TXS_PER_MSG + 1);
+        } else {
+            panic!();
+        }
+
+        let mut peer_receiver = test_msg_sender.send(Message::GetTransactions(hash_vec.clone()));
+        let mut received_hash_vec = vec![];
+        for expected_len in [MAX_TXS_PER_MSG, 1].iter() {
+            if let Message::Transactions(v) = peer_receiver.recv() {
+                assert_eq!(v.len(), *expected_len);
+                received_hash_vec.extend(v.iter().map(|tx| tx.txid()));
+            } else {
+                panic!();
+            }
+        }
+        assert_eq!(received_hash_vec, hash_vec);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_have_blocks_filter() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let filter = BloomFilter::new(1);
+        let mut peer_receiver = test_msg_sender.send(Message::HaveBlocksFilter(filter.to_bytes()));
+        let reply = peer_receiver.recv();
+        if let Message::NewBlockHashes(hash_vec) = reply {
+            assert_eq!(hash_vec, v);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn broadcast_transaction_once() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let signed_tx = free_transaction();
+        let other_signed_tx = free_transaction();
+        let mut _peer_receiver =
+            test_msg_sender.send(Message::Transactions(vec![signed_tx.clone()]));
+        let mut _peer_receiver =
+            test_msg_sender.send(Message::Transactions(vec![signed_tx.clone()]));
+        let mut _peer_receiver =
+            test_msg_sender.send(Message::Transactions(vec![other_signed_tx.clone()]));
+        // the replayed transaction produces no broadcast in between
+        for expected_tx in [signed_tx, other_signed_tx].iter() {
+            let reply = server_receiver.recv().unwrap();
+            if let Message::NewTransactionHashes(hash_vec) = reply {
+                assert_eq!(hash_vec, vec![expected_tx.txid()]);
+            } else {
+                panic!();
+            }
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn accept_transaction_dropped_before() {
+        use std::sync::Arc;
+
+        let (worker, test_msg_sender, server_receiver, _v) = generate_test_worker(1);
+        let mempool = Arc::clone(&worker.mempool);
+        // no room for orphans at first
+        mempool.lock().unwrap().max_orphans = 0;
+        worker.start();
+
+        let (sender, key) = random_address_with_keypair();
+        let tx_0 = sign_transaction(Transaction::new(sender, [2u8; 20].into(), 0, 0, 0), &key);
+        let tx_1 = sign_transaction(Transaction::new(sender, [2u8; 20].into(), 0, 0, 1), &key);
+        let mut _peer_receiver = test_msg_sender.send(Message::Transactions(vec![tx_1.clone()]));
+        let mut peer_receiver = test_msg_sender.send(Message::Ping(String::new()));
+        peer_receiver.recv();
+        mempool.lock().unwrap().max_orphans = 1;
+
+        // the dropped transaction is not taken as seen, so it is kept when relayed again
+        let mut _peer_receiver = test_msg_sender.send(Message::Transactions(vec![tx_1.clone()]));
+        let mut _peer_receiver = test_msg_sender.send(Message::Transactions(vec![tx_0.clone()]));
+        if let Some(Message::NewTransactionHashes(hash_vec)) = server_receiver.recv() {
+            assert_eq!(hash_vec, vec![tx_0.txid(), tx_1.txid()]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reject_peer_with_other_genesis() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let (new_peer, peer_receiver) = TestMsgSender::new_peer();
+        test_msg_sender.send_from(
+            Message::Version {
+                protocol_version: PROTOCOL_VERSION,
+                genesis: H256::from([1u8; 32]),
+                node_id: 1,
+            },
+            &new_peer,
+        );
+        while !peer_receiver.is_disconnected() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn disconnect_connection_to_ourselves() {
+        let (worker, test_msg_sender, _server_receiver, v) = generate_test_worker(1);
+        let node_id = worker.server.node_id();
+        worker.start();
+        let (new_peer, peer_receiver) = TestMsgSender::new_peer();
+        test_msg_sender.send_from(
+            Message::Version {
+                protocol_version: PROTOCOL_VERSION,
+                genesis: v[0],
+                node_id,
+            },
+            &new_peer,
+        );
+        while !peer_receiver.is_disconnected() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn accept_peer_with_same_version() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let (new_peer, mut peer_receiver) = TestMsgSender::new_peer();
+        // a message before the handshake waits for it
+        test_msg_sender.send_from(Message::Ping(String::from("before version")), &new_peer);
+        test_msg_sender.send_from(
+            Message::Version {
+                protocol_version: PROTOCOL_VERSION,
+                genesis: v[0],
+                node_id: 1,
+            },
+            &new_peer,
+        );
+        let reply = peer_receiver.recv();
+        if let Message::Version {
+            protocol_version,
+            genesis,
+            ..
+        } = reply
+        {
+            assert_eq!(protocol_version, PROTOCOL_VERSION);
+            assert_eq!(genesis, v[0]);
+        } else {
+            panic!();
+        }
+        if let Message::Pong(nonce) = peer_receiver.recv() {
+            assert_eq!(nonce, "before version");
+        } else {
+            panic!();
+        }
+        test_msg_sender.send_from(Message::Ping(String::from("after version")), &new_peer);
+        if let Message::Pong(nonce) = peer_receiver.recv() {
+            assert_eq!(nonce, "after version");
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn forget_handshake_of_disconnected_peer() {
+        let (worker, test_msg_sender, _server_receiver, v) = generate_test_worker(1);
+        let handshaken_peers = std::sync::Arc::clone(&worker.handshaken_peers);
+        let server = worker.server.clone();
+        worker.start();
+        let (new_peer, mut peer_receiver) = TestMsgSender::new_peer();
+        test_msg_sender.send_from(
+            Message::Version {
+                protocol_version: PROTOCOL_VERSION,
+                genesis: v[0],
+                node_id: 1,
+            },
+            &new_peer,
+        );
+        peer_receiver.recv();
+        assert!(handshaken_peers.lock().unwrap().contains(new_peer.addr()));
+
+        server.remove_test_peer(*new_peer.addr());
+        while handshaken_peers.lock().unwrap().contains(new_peer.addr()) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn zero_worker_threads_clamped_to_one() {
+        let (test_msg_sender, _server_receiver, _v, _metrics) =
+            generate_test_worker_with_threads_and_start(0);
+        let mut peer_receiver = test_msg_sender.send(Message::Ping(String::from("no threads")));
+        if let Message::Pong(nonce) = peer_receiver.recv() {
+            assert_eq!(nonce, "no threads");
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn count_received_messages() {
+        let (test_msg_sender, _server_receiver, v, metrics) =
+            generate_test_worker_with_threads_and_start(1);
+        let mut _peer_receiver = test_msg_sender.send(Message::Pong(String::from("pong")));
+        let mut _peer_receiver = test_msg_sender.send(Message::GetBlocks(v.clone()));
+        let mut _peer_receiver = test_msg_sender.send(Message::GetBlocks(v.clone()));
+        // the reply to a `Ping` comes after all previous messages are counted
+        let mut peer_receiver = test_msg_sender.send(Message::Ping(String::from("ping")));
+        peer_receiver.recv();
+
+        let counts = metrics.snapshot();
+        assert_eq!(counts["Ping"], 1);
+        assert_eq!(counts["Pong"], 1);
+        assert_eq!(counts["GetBlocks"], 2);
+        assert_eq!(counts["Blocks"], 0);
+        assert_eq!(counts["Total"], 4);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn survive_malformed_message() {
+        let (test_msg_sender, _server_receiver, _v, metrics) =
+            generate_test_worker_with_threads_and_start(1);
+        let mut _peer_receiver = test_msg_sender.send_raw(vec![255u8; 7]);
+        let mut peer_receiver = test_msg_sender.send(Message::Ping(String::from("still alive")));
+        if let Message::Pong(nonce) = peer_receiver.recv() {
+            assert_eq!(nonce, "still alive");
+        } else {
+            panic!();
+        }
+        let counts = metrics.snapshot();
+        assert_eq!(counts["Malformed"], 1);
+        assert_eq!(counts["Ping"], 1);
+    }
+    #[test]
+    #[timeout(60000)]
+    fn request_headers_for_higher_tip() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let random_block = generate_random_block(v.last().unwrap());
+        let mut peer_receiver = test_msg_sender.send(Message::Tip(random_block.hash(), 1));
+        let reply = peer_receiver.recv();
+        if let Message::GetHeaders(locator, _) = reply {
+            assert_eq!(locator, v);
+        } else {
+            panic!();
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn compact_block_from_mempool() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let tx_vec = vec![free_transaction(), free_transaction()];
+        let mut _peer_receiver = test_msg_sender.send(Message::Transactions(tx_vec.clone()));
+        server_receiver.recv().unwrap();
+
+        let block = block_with_transactions(v.last().unwrap(), tx_vec);
+        let mut _peer_receiver = test_msg_sender.send(Message::CompactBlock {
+            header: block.header.clone(),
+            txids: block.content().data.iter().map(|tx| tx.txid()).collect(),
+        });
+        if let Some(Message::NewBlockHashes(hash_vec)) = server_receiver.recv() {
+            assert_eq!(hash_vec, vec![block.hash()]);
+        } else {
+            panic!();
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn compact_block_requests_missing_transaction() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let known_tx = free_transaction();
+        let missed_tx = free_transaction();
+        let mut _peer_receiver =
+            test_msg_sender.send(Message::Transactions(vec![known_tx.clone()]));
+        server_receiver.recv().unwrap();
+
+        let block = block_with_transactions(v.last().unwrap(), vec![known_tx, missed_tx.clone()]);
+        let mut peer_receiver = test_msg_sender.send(Message::CompactBlock {
+            header: block.header.clone(),
+            txids: block.content().data.iter().map(|tx| tx.txid()).collect(),
+        });
+        if let Message::GetBlockTransactions(block_hash, txids) = peer_receiver.recv() {
+            assert_eq!(block_hash, block.hash());
+            assert_eq!(txids, vec![missed_tx.txid()]);
+        } else {
+            panic!();
+        }
+
+        let mut _peer_receiver =
+            test_msg_sender.send(Message::BlockTransactions(block.hash(), vec![missed_tx]));
+        if let Some(Message::NewBlockHashes(hash_vec)) = server_receiver.recv() {
+            assert_eq!(hash_vec, vec![block.hash()]);
+        } else {
+            panic!();
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn forget_compact_block_once_inserted_or_peer_drops() {
+        use super::super::peer;
+        use std::sync::Arc;
+
+        let (worker, test_msg_sender, server_receiver, v) = generate_test_worker(1);
+        let pending_compact_blocks = Arc::clone(&worker.pending_compact_blocks);
+        let server = worker.server.clone();
+        worker.start();
+
+        // the block downloaded in full rather than rebuilt
+        let block = block_with_transactions(v.last().unwrap(), vec![free_transaction()]);
+        let mut peer_receiver = test_msg_sender.send(Message::CompactBlock {
+            header: block.header.clone(),
+            txids: block.content().data.iter().map(|tx| tx.txid()).collect(),
+        });
+        peer_receiver.recv();
+        assert!(pending_compact_blocks
+            .lock()
+            .unwrap()
+            .contains_key(&block.hash()));
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        server_receiver.recv().unwrap();
+        assert!(pending_compact_blocks.lock().unwrap().is_empty());
+
+        let other_block = block_with_transactions(&block.hash(), vec![free_transaction()]);
+        let mut peer_receiver = test_msg_sender.send(Message::CompactBlock {
+            header: other_block.header.clone(),
+            txids: other_block
+                .content()
+                .data
+                .iter()
+                .map(|tx| tx.txid())
+                .collect(),
+        });
+        peer_receiver.recv();
+        assert_eq!(pending_compact_blocks.lock().unwrap().len(), 1);
+        server.remove_test_peer(*peer::Handle::test_handle().0.addr());
+        while !pending_compact_blocks.lock().unwrap().is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn record_rejected_blocks_in_order() {
+        use crate::blockchain::BlockValidationError;
+        use crate::network::rejections::RejectionReason;
+
+        let (worker, test_msg_sender, _server_receiver, v) = generate_test_worker(1);
+        let rejections = worker.rejections();
+        worker.start();
+
+        let mut bad_merkle_block = generate_random_block(v.last().unwrap());
+        bad_merkle_block.header.merkle_root = [1u8; 32].into();
+        let mut bad_difficulty_block = generate_random_block(v.last().unwrap());
+        bad_difficulty_block.header.difficulty = [1u8; 32].into();
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![
+            bad_merkle_block.clone(),
+            bad_difficulty_block.clone(),
+        ]));
+
+        while rejections.entries().len() < 2 {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let entries = rejections.entries();
+        assert_eq!(entries[0].hash, bad_merkle_block.hash());
+        assert_eq!(
+            entries[0].reason,
+            RejectionReason::Block(BlockValidationError::BadMerkleRoot)
+        );
+        assert_eq!(entries[1].hash, bad_difficulty_block.hash());
+        assert_eq!(
+            entries[1].reason,
+            RejectionReason::Block(BlockValidationError::DifficultyMismatch)
+        );
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn record_rejected_orphan_block() {
+        use crate::blockchain::BlockValidationError;
+        use crate::network::rejections::RejectionReason;
+
+        let (worker, test_msg_sender, server_receiver, v) = generate_test_worker(1);
+        let rejections = worker.rejections();
+        worker.start();
+
+        let parent_block = generate_random_block(v.last().unwrap());
+        let mut bad_orphan = generate_random_block(&parent_block.hash());
+        bad_orphan.header.merkle_root = [1u8; 32].into();
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![bad_orphan.clone()]));
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![parent_block.clone()]));
+        if let Some(Message::NewBlockHashes(hash_vec)) = server_receiver.recv() {
+            assert_eq!(hash_vec, vec![parent_block.hash()]);
+        } else {
+            panic!();
+        }
+
+        let entries = rejections.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].hash, bad_orphan.hash());
+        assert_eq!(
+            entries[0].reason,
+            RejectionReason::Block(BlockValidationError::BadMerkleRoot)
+        );
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn drop_oversized_transaction() {
+        use crate::network::rejections::RejectionReason;
+        use crate::types::transaction::TxValidationError;
+
+        let (worker, test_msg_sender, server_receiver, _v) = generate_test_worker(1);
+        let rejections = worker.rejections();
+        worker.start();
+
+        // a peer may pad the signature, which is only checked after deserializing
+        let tx = free_transaction();
+        let mut bytes = bincode::serialize(tx.transaction()).unwrap();
+        bytes.extend(bincode::serialize(&vec![0u8; MAX_TX_BYTES]).unwrap());
+        bytes.extend(bincode::serialize(&Vec::<u8>::new()).unwrap());
+        let oversized_tx: SignedTransaction = bincode::deserialize(&bytes).unwrap();
+        assert!(oversized_tx.size_bytes() > MAX_TX_BYTES);
+
+        let normal_tx = free_transaction();
+        let mut _peer_receiver = test_msg_sender.send(Message::Transactions(vec![
+            oversized_tx.clone(),
+            normal_tx.clone(),
+        ]));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::NewTransactionHashes(v) = reply {
+            assert_eq!(v, vec![normal_tx.txid()]);
+        } else {
+            panic!();
+        }
+        let entries = rejections.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].hash, oversized_tx.txid());
+        assert_eq!(
+            entries[0].reason,
+            RejectionReason::Transaction(TxValidationError::Oversized)
+        );
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn drop_overdrawing_transaction() {
+        use crate::network::rejections::RejectionReason;
+        use crate::types::transaction::TxValidationError;
+
+        let (worker, test_msg_sender, server_receiver, _v) = generate_test_worker(1);
+        let rejections = worker.rejections();
+        worker.start();
+
+        let (sender, key) = random_address_with_keypair();
+        let overdrawing_tx =
+            sign_transaction(Transaction::new(sender, [2u8; 20].into(), 10, 0, 0), &key);
+        let normal_tx = free_transaction();
+        let mut _peer_receiver = test_msg_sender.send(Message::Transactions(vec![
+            overdrawing_tx.clone(),
+            normal_tx.clone(),
+        ]));
+        if let Some(Message::NewTransactionHashes(v)) = server_receiver.recv() {
+            assert_eq!(v, vec![normal_tx.txid()]);
+        } else {
+            panic!();
+        }
+        let entries = rejections.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].hash, overdrawing_tx.txid());
+        assert_eq!(
+            entries[0].reason,
+            RejectionReason::Transaction(TxValidationError::InsufficientBalance)
+        );
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn request_blocks_from_highest_tip_peer() {
+        use super::super::peer;
+        use super::GET_BLOCKS_TIMEOUT;
+        use std::sync::Arc;
+        use std::time::Instant;
+
+        let (worker, test_msg_sender, _server_receiver, v) = generate_test_worker(1);
+        let (slow_peer, mut slow_receiver) =
+            peer::Handle::test_handle_with_addr("127.0.0.1:12322".parse().unwrap());
+        let (ahead_peer, mut ahead_receiver) =
+            peer::Handle::test_handle_with_addr("127.0.0.1:12323".parse().unwrap());
+        worker.server.insert_test_peer(slow_peer.clone());
+        worker.server.insert_test_peer(ahead_peer.clone());
+        let mut handshaken_peers = worker.handshaken_peers.lock().unwrap();
+        handshaken_peers.insert(*slow_peer.addr());
+        handshaken_peers.insert(*ahead_peer.addr());
+        drop(handshaken_peers);
+        let in_flight_blocks = Arc::clone(&worker.in_flight_blocks);
+        worker.start();
+
+        // the peer ahead is asked for its headers first
+        test_msg_sender.send_from(Message::Tip([9u8; 32].into(), 10), &ahead_peer);
+        if let Message::GetHeaders(..) = ahead_receiver.recv() {
+        } else {
+            panic!();
+        }
+
+        let block = generate_random_block(v.last().unwrap());
+        test_msg_sender.send_from(Message::NewBlockHashes(vec![block.hash()]), &slow_peer);
+        if let Message::GetBlocks(hash_vec) = ahead_receiver.recv() {
+            assert_eq!(hash_vec, vec![block.hash()]);
+        } else {
+            panic!();
+        }
+        assert_eq!(slow_peer.queue_depth(), 0);
+
+        // the peer ahead does not answer in time, then the announcing peer is asked
+        let timed_out = Instant::now().checked_sub(GET_BLOCKS_TIMEOUT).unwrap();
+        in_flight_blocks
+            .lock()
+            .unwrap()
+            .insert(block.hash(), (timed_out, false));
+        test_msg_sender.send_from(Message::NewBlockHashes(vec![block.hash()]), &slow_peer);
+        if let Message::GetBlocks(hash_vec) = slow_receiver.recv() {
+            assert_eq!(hash_vec, vec![block.hash()]);
+        } else {
+            panic!();
+        }
+        assert_eq!(ahead_peer.queue_depth(), 0);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn throttle_burst_of_blocks() {
+        use std::sync::Arc;
+
+        let (mut worker, test_msg_sender, server_receiver, v) = generate_test_worker(1);
+        // a burst of 2 blocks, then hardly any
+        worker.set_block_rate_limit(2, 0.001);
+        let blockchain = Arc::clone(&worker.blockchain);
+        worker.start();
+
+        let mut parent = *v.last().unwrap();
+        let mut blocks = vec![];
+        for _ in 0..3 {
+            let block = generate_random_block(&parent);
+            parent = block.hash();
+            blocks.push(block);
+        }
+        for block in blocks.iter() {
+            let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        }
+        // messages are processed in order by the only thread
+        let mut peer_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        peer_receiver.recv();
+
+        for block in blocks[..2].iter() {
+            if let Some(Message::NewBlockHashes(hash_vec)) = server_receiver.recv() {
+                assert_eq!(hash_vec, vec![block.hash()]);
+            } else {
+                panic!();
+            }
+        }
+        let _blockchain = blockchain.lock().unwrap();
+        assert_eq!(_blockchain.tip(), blocks[1].hash());
+        assert!(!_blockchain.hash_to_block.contains_key(&blocks[2].hash()));
+    }
+
+    // a transaction moving nothing, which is valid from any new account
+    fn free_transaction() -> SignedTransaction {
+        let (sender, key) = random_address_with_keypair();
+        sign_transaction(Transaction::new(sender, [2u8; 20].into(), 0, 0, 0), &key)
+    }
+
+    fn block_with_transactions(parent: &H256, tx_vec: Vec<SignedTransaction>) -> Block {
+        let mut block = generate_random_block(parent);
+        block.header.merkle_root = MerkleTree::new(&tx_vec).root();
+        block.content_mut().data = tx_vec;
+        block
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn promote_orphan_transaction_after_block() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let (sender, key) = random_address_with_keypair();
+        let tx_0 = sign_transaction(Transaction::new(sender, [2u8; 20].into(), 0, 0, 0), &key);
+        let tx_1 = sign_transaction(Transaction::new(sender, [2u8; 20].into(), 0, 0, 1), &key);
+        // nonce 1 waits for nonce 0, which only arrives in a block
+        let mut _peer_receiver = test_msg_sender.send(Message::Transactions(vec![tx_1.clone()]));
+        let block = block_with_transactions(v.last().unwrap(), vec![tx_0]);
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        if let Some(Message::NewBlockHashes(hash_vec)) = server_receiver.recv() {
+            assert_eq!(hash_vec, vec![block.hash()]);
+        } else {
+            panic!();
+        }
+        if let Some(Message::NewTransactionHashes(hash_vec)) = server_receiver.recv() {
+            assert_eq!(hash_vec, vec![tx_1.txid()]);
+        } else {
+            panic!();
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn remove_transaction_of_connected_block() {
+        use std::sync::Arc;
+
+        let (worker, test_msg_sender, server_receiver, v) = generate_test_worker(1);
+        let mempool = Arc::clone(&worker.mempool);
+        worker.start();
+
+        let tx = free_transaction();
+        let mut _peer_receiver = test_msg_sender.send(Message::Transactions(vec![tx.clone()]));
+        if let Some(Message::NewTransactionHashes(hash_vec)) = server_receiver.recv() {
+            assert_eq!(hash_vec, vec![tx.txid()]);
+        } else {
+            panic!();
+        }
+        assert!(mempool.lock().unwrap().contains(&tx.txid()));
+
+        let block = block_with_transactions(v.last().unwrap(), vec![tx.clone()]);
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        if let Some(Message::NewBlockHashes(hash_vec)) = server_receiver.recv() {
+            assert_eq!(hash_vec, vec![block.hash()]);
+        } else {
+            panic!();
+        }
+        assert!(!mempool.lock().unwrap().contains(&tx.txid()));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn relay_valid_batches_and_reject_block_with_bad_batch() {
+        use crate::blockchain::BlockValidationError;
+        use crate::network::rejections::RejectionReason;
+        use crate::types::transaction::{sign_batch, TxValidationError};
+        use std::sync::Arc;
+
+        let (worker, test_msg_sender, server_receiver, v) = generate_test_worker(1);
+        let mempool = Arc::clone(&worker.mempool);
+        let rejections = worker.rejections();
+        worker.start();
+
+        let (sender, key) = random_address_with_keypair();
+        let good_batch = sign_batch(
+            vec![
+                Transaction::new(sender, [2u8; 20].into(), 0, 0, 0),
+                Transaction::new(sender, [2u8; 20].into(), 0, 0, 1),
+            ],
+            &key,
+        );
+        // one transaction is not from the signer of the batch
+        let bad_batch = sign_batch(
+            vec![
+                Transaction::new(sender, [2u8; 20].into(), 0, 0, 2),
+                Transaction::new([3u8; 20].into(), [2u8; 20].into(), 0, 0, 0),
+            ],
+            &key,
+        );
+        let mut _peer_receiver = test_msg_sender.send(Message::TransactionBatches(vec![
+            good_batch.clone(),
+            bad_batch.clone(),
+        ]));
+        if let Some(Message::TransactionBatches(batch_vec)) = server_receiver.recv() {
+            assert_eq!(batch_vec.len(), 1);
+            assert_eq!(batch_vec[0].hash(), good_batch.hash());
+        } else {
+            panic!();
+        }
+        assert!(mempool.lock().unwrap().contains_batch(&good_batch.hash()));
+        assert!(!mempool.lock().unwrap().contains_batch(&bad_batch.hash()));
+
+        let mut bad_block = generate_random_block(v.last().unwrap());
+        bad_block.content_mut().batches = vec![bad_batch.clone()];
+        bad_block.header.merkle_root = bad_block.content().merkle_root();
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![bad_block.clone()]));
+        while rejections.entries().len() < 2 {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let entries = rejections.entries();
+        assert_eq!(entries[0].hash, bad_batch.hash());
+        assert_eq!(
+            entries[0].reason,
+            RejectionReason::Transaction(TxValidationError::WrongSender)
+        );
+        assert_eq!(entries[1].hash, bad_block.hash());
+        assert_eq!(
+            entries[1].reason,
+            RejectionReason::Block(BlockValidationError::InvalidTransaction(
+                TxValidationError::WrongSender
+            ))
+        );
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn relay_to_fanout_peers() {
+        use super::super::peer;
+
+        let (mut worker, test_msg_sender, _server_receiver, _v) = generate_test_worker(1);
+        worker.set_relay_fanout(Some(2));
+        let peers: Vec<_> = (0..5)
+            .map(|i| {
+                let addr = format!("127.0.0.1:{}", 12330 + i).parse().unwrap();
+                let (peer, receiver) = peer::Handle::test_handle_with_addr(addr);
+                worker.server.insert_test_peer(peer.clone());
+                (peer, receiver)
+            })
+            .collect();
+        worker.start();
+
+        let signed_tx = free_transaction();
+        let mut _peer_receiver = test_msg_sender.send(Message::Transactions(vec![signed_tx]));
+        // messages are processed in order by the only thread
+        let mut peer_receiver = test_msg_sender.send(Message::Ping("done".to_string()));
+        peer_receiver.recv();
+
+        let announced: Vec<_> = peers
+            .into_iter()
+            .filter(|(peer, _)| peer.queue_depth() == 1)
+            .collect();
+        assert_eq!(announced.len(), 2);
+        for (_, mut receiver) in announced {
+            if let Message::NewTransactionHashes(..) = receiver.recv() {
+            } else {
+                panic!();
+            }
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn announce_echoed_block_once() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let block = generate_random_block(v.last().unwrap());
+        let other_block = generate_random_block(v.last().unwrap());
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![other_block.clone()]));
+        // the echoed block is already in the blockchain, so it produces no announcement in between
+        for expected_block in [block, other_block].iter() {
+            if let Some(Message::NewBlockHashes(hash_vec)) = server_receiver.recv() {
+                assert_eq!(hash_vec, vec![expected_block.hash()]);
+            } else {
+                panic!();
+            }
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reject_message_over_size_limit() {
+        let (mut worker, test_msg_sender, _server_receiver, _v) = generate_test_worker(1);
+        worker.set_max_msg_bytes(1024);
+        let metrics = worker.metrics();
+        worker.start();
+
+        // a NewBlockHashes declaring almost 2^64 hashes, in a few bytes
+        let mut bytes = 2u32.to_le_bytes().to_vec();
+        bytes.extend(u64::MAX.to_le_bytes().iter());
+        bytes.extend([0u8; 32].iter());
+        let mut _peer_receiver = test_msg_sender.send_raw(bytes);
+        // a well-formed message over the limit
+        let hashes = vec![H256::from([1u8; 32]); 100];
+        let mut _peer_receiver = test_msg_sender.send(Message::NewBlockHashes(hashes));
+
+        let mut peer_receiver = test_msg_sender.send(Message::Ping(String::from("still alive")));
+        if let Message::Pong(nonce) = peer_receiver.recv() {
+            assert_eq!(nonce, "still alive");
+        } else {
+            panic!();
+        }
+        let counts = metrics.snapshot();
+        assert_eq!(counts["Malformed"], 2);
+        assert_eq!(counts["NewBlockHashes"], 0);
+    }
+
+    #[test]
+    fn split_blocks_under_message_limit() {
+        use super::Worker;
+
+        let mut block_vec = vec![];
+        let mut parent = [0u8; 32].into();
+        for _ in 0..5 {
+            let block = block_with_transactions(&parent, vec![free_transaction()]);
+            parent = block.hash();
+            block_vec.push(block);
+        }
+        // room for two blocks in each message
+        let max_msg_bytes =
+            bincode::serialized_size(&Message::Blocks(block_vec[..2].to_vec())).unwrap();
+
+        let chunks = Worker::split_blocks(block_vec.clone(), max_msg_bytes);
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+        for chunk in chunks.iter() {
+            let msg = Message::Blocks(chunk.clone());
+            assert!(bincode::serialized_size(&msg).unwrap() <= max_msg_bytes);
+        }
+        let hash_vec: Vec<H256> = chunks.into_iter().flatten().map(|b| b.hash()).collect();
+        assert_eq!(
+            hash_vec,
+            block_vec.iter().map(|b| b.hash()).collect::<Vec<_>>()
+        );
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,503 @@
+pub mod peer_maintainer;
+
+use crate::blockchain::{Blockchain, GenesisParams};
+use crate::mempool::{self, Mempool};
+use crate::miner;
+use crate::network;
+use crate::network::message::{Message, MAX_MSG_BYTES, PROTOCOL_VERSION};
+use crate::network::metrics::NetworkMetrics;
+use crate::network::sync::SyncTracker;
+use crate::network::watchdog::{StaleTipWatchdog, STALE_TIP_TIMEOUT_MS};
+use crate::network::worker::{BLOCK_MSGS_BURST, BLOCK_MSGS_PER_SEC, MAX_TX_BYTES};
+use crate::types::transaction::{SignedTransaction, TxValidationError};
+use peer_maintainer::PeerMaintainer;
+
+#[cfg(any(test, test_utilities))]
+use crate::types::hash::H256;
+#[cfg(any(test, test_utilities))]
+use std::collections::HashSet;
+
+use log::{error, info};
+use smol::channel;
+use std::io;
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+/// Settings of a node started by `Node::start`
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub listen_addr: net::SocketAddr, // of the P2P server
+    pub seed_peers: Vec<net::SocketAddr>,
+    pub miner_lambda: Option<u64>, // mine continuously with this lambda, or leave the miner paused
+    pub p2p_workers: usize,
+    pub min_peers: usize, // dial the known peers, the seed ones at first, while fewer are connected
+    pub block_msgs_burst: u32, // block messages a peer may send in a burst
+    pub block_msgs_per_sec: f64, // block messages per second a peer may send after a burst
+    pub relay_fanout: Option<usize>, // random peers to announce to, or all of them if None
+    pub max_msg_bytes: u64, // larger messages from peers are rejected
+    pub genesis: GenesisParams,
+    pub evict_interval_ms: u64, // between two evictions of expired transactions from mempool
+    pub stale_tip_timeout_ms: u128, // ask peers for newer blocks once the tip is unchanged this long
+    pub tip_reannounce_ms: Option<u64>, // re-announce the tip at this interval, or never if None
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            listen_addr: "127.0.0.1:6000".parse().unwrap(),
+            seed_peers: vec![],
+            miner_lambda: None,
+            p2p_workers: 4,
+            min_peers: 0,
+            block_msgs_burst: BLOCK_MSGS_BURST,
+            block_msgs_per_sec: BLOCK_MSGS_PER_SEC,
+            relay_fanout: None,
+            max_msg_bytes: MAX_MSG_BYTES,
+            genesis: GenesisParams::default(),
+            evict_interval_ms: mempool::EVICT_INTERVAL_MS,
+            stale_tip_timeout_ms: STALE_TIP_TIMEOUT_MS,
+            tip_reannounce_ms: None,
+        }
+    }
+}
+
+/// A running node: its blockchain, mempool, P2P server, network worker, miner, and the threads
+/// maintaining them
+pub struct Node {
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub sync_tracker: Arc<Mutex<SyncTracker>>,
+    pub server: network::server::Handle,
+    pub miner: miner::Handle,
+    pub network_metrics: Arc<NetworkMetrics>,
+    pub mining_latency: Arc<miner::MiningLatency>,
+    pub peer_maintainer: PeerMaintainer,
+    relay_fanout: Option<usize>, // of the transactions submitted, as of those the worker relays
+}
+
+impl Node {
+    /// Wire the components of a node together, start them, and connect to the seed peers in the
+    /// background
+    pub fn start(config: NodeConfig) -> io::Result<Node> {
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(config.genesis.clone())));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let sync_tracker = Arc::new(Mutex::new(SyncTracker::new()));
+
+        // create channels between server and worker
+        let (msg_tx, msg_rx) = channel::bounded(10000);
+
+        // start the p2p server
+        let (server_ctx, server) = network::server::new(config.listen_addr, msg_tx)?;
+        server_ctx.start()?;
+
+        // start the worker
+        let mut worker_ctx = network::worker::Worker::new(
+            config.p2p_workers,
+            msg_rx,
+            &server,
+            &blockchain,
+            &mempool,
+            &sync_tracker,
+        );
+        worker_ctx.set_block_rate_limit(config.block_msgs_burst, config.block_msgs_per_sec);
+        worker_ctx.set_relay_fanout(config.relay_fanout);
+        worker_ctx.set_max_msg_bytes(config.max_msg_bytes);
+        let network_metrics = worker_ctx.metrics();
+        worker_ctx.start();
+
+        // start the miner
+        let (miner_ctx, miner, finished_block_chan) =
+            miner::new(&blockchain, &mempool, &sync_tracker);
+        let mut miner_worker_ctx =
+            miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &mempool);
+        let mining_latency = miner_ctx.latency();
+        miner_worker_ctx.set_latency(&mining_latency);
+        miner_ctx.start();
+        miner_worker_ctx.start();
+        if let Some(lambda) = config.miner_lambda {
+            miner.start(lambda);
+        }
+
+        let peer_maintainer =
+            PeerMaintainer::new(&server, &blockchain, config.min_peers, &config.seed_peers);
+        if config.min_peers > 0 {
+            peer_maintainer.start();
+        }
+
+        start_mempool_eviction(&mempool, config.evict_interval_ms);
+        // ask peers for newer blocks when the tip stays unchanged for too long
+        let watchdog = StaleTipWatchdog::new(&server, &blockchain, config.stale_tip_timeout_ms);
+        watchdog.start();
+        if let Some(interval_ms) = config.tip_reannounce_ms {
+            let announcer = network::announcer::TipAnnouncer::new(
+                &server,
+                &blockchain,
+                time::Duration::from_millis(interval_ms),
+            );
+            announcer.start();
+        }
+        connect_to_seeds(config.seed_peers, &server, &blockchain);
+
+        Ok(Node {
+            blockchain,
+            mempool,
+            sync_tracker,
+            server,
+            miner,
+            network_metrics,
+            mining_latency,
+            peer_maintainer,
+            relay_fanout: config.relay_fanout,
+        })
+    }
+
+    /// Submit a transaction from a wallet: check its size, its signature and that it applies on
+    /// the tip's state after the sender's pending transactions of lower nonces, then insert it
+    /// into mempool and announce it to the peers of the relay fanout. A transaction already
+    /// pending is accepted without another announcement.
+    pub fn submit_transaction(&self, tx: SignedTransaction) -> Result<(), TxValidationError> {
+        if tx.size_bytes() > MAX_TX_BYTES {
+            return Err(TxValidationError::Oversized);
+        }
+        let _blockchain = self.blockchain.lock().unwrap();
+        let mut _mempool = self.mempool.lock().unwrap();
+        let tip_state = _blockchain.tip_state();
+        let pending = _mempool.pending_before(
+            &tx.transaction().sender(),
+            tip_state,
+            tx.transaction().nonce(),
+        );
+        _blockchain.would_accept_tx_after(&tx, &_blockchain.tip(), &pending)?;
+        let new_tx_vec = _mempool.insert_or_orphan(&tx, tip_state);
+        drop(_mempool);
+        drop(_blockchain);
+
+        if !new_tx_vec.is_empty() {
+            network::worker::relay(
+                &self.server,
+                self.relay_fanout,
+                Message::NewTransactionHashes(new_tx_vec),
+            );
+        }
+        Ok(())
+    }
+}
+
+// Evict expired transactions from mempool in a thread, every `interval_ms`
+fn start_mempool_eviction(mempool: &Arc<Mutex<Mempool>>, interval_ms: u64) {
+    let mempool = Arc::clone(mempool);
+    thread::spawn(move || loop {
+        thread::sleep(time::Duration::from_millis(interval_ms));
+        let now_ms = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let evicted = mempool
+            .lock()
+            .unwrap()
+            .evict_expired(now_ms, mempool::TX_TTL_MS);
+        if evicted > 0 {
+            info!("Evicted {} expired transactions from mempool", evicted);
+        }
+    });
+}
+
+// Connect to each seed peer in a thread, retrying every second until connected, and start the
+// handshake
+fn connect_to_seeds(
+    seed_peers: Vec<net::SocketAddr>,
+    server: &network::server::Handle,
+    blockchain: &Arc<Mutex<Blockchain>>,
+) {
+    if seed_peers.is_empty() {
+        return;
+    }
+    let server = server.clone();
+    let blockchain = Arc::clone(blockchain);
+    thread::spawn(move || {
+        for addr in seed_peers {
+            loop {
+                match server.connect(addr) {
+                    Ok(mut peer_handle) => {
+                        info!("Connected to outgoing peer {}", &addr);
+                        handshake(&mut peer_handle, &server, &blockchain);
+                        break;
+                    }
+                    // retrying would not help with ourselves or a peer already connected
+                    Err(e)
+                        if e.kind() == io::ErrorKind::InvalidInput
+                            || e.kind() == io::ErrorKind::AlreadyExists =>
+                    {
+                        error!("Not connecting to peer {}: {}", addr, e);
+                        break;
+                    }
+                    Err(e) => {
+                        error!(
+                            "Error connecting to peer {}, retrying in one second: {}",
+                            addr, e
+                        );
+                        thread::sleep(time::Duration::from_millis(1000));
+                        continue;
+                    }
+                }
+            }
+        }
+    });
+}
+
+// Start the handshake with an outgoing peer
+fn handshake(
+    peer_handle: &mut network::peer::Handle,
+    server: &network::server::Handle,
+    blockchain: &Arc<Mutex<Blockchain>>,
+) {
+    let _blockchain = blockchain.lock().unwrap();
+    // the peer rejects us if we are on another network
+    let version = Message::Version {
+        protocol_version: PROTOCOL_VERSION,
+        genesis: _blockchain.genesis_hash(),
+        node_id: server.node_id(),
+    };
+    let filter = _blockchain.known_blocks_filter();
+    let tip = Message::Tip(_blockchain.tip(), _blockchain.tip_height());
+    drop(_blockchain);
+    peer_handle.write(version);
+    // let the peer announce the blocks we are missing
+    peer_handle.write(Message::HaveBlocksFilter(filter.to_bytes()));
+    // let the peer know how far ahead we are
+    peer_handle.write(tip);
+}
+
+#[cfg(any(test, test_utilities))]
+/// In-process nodes which can be connected and partitioned, to test convergence
+pub struct TestNetwork {
+    pub nodes: Vec<Node>,
+    addrs: Vec<net::SocketAddr>,
+}
+
+#[cfg(any(test, test_utilities))]
+impl TestNetwork {
+    /// Start nodes listening at the given addresses, not connected to each other
+    pub fn start(addrs: &[net::SocketAddr]) -> Self {
+        Self::start_with_config(addrs, &NodeConfig::default())
+    }
+
+    /// Same as `start`, with the settings other than the listen address taken from `config`
+    pub fn start_with_config(addrs: &[net::SocketAddr], config: &NodeConfig) -> Self {
+        let nodes = addrs
+            .iter()
+            .map(|addr| {
+                Node::start(NodeConfig {
+                    listen_addr: *addr,
+                    ..config.clone()
+                })
+                .unwrap()
+            })
+            .collect();
+        TestNetwork {
+            nodes,
+            addrs: addrs.to_vec(),
+        }
+    }
+
+    /// Connect node `i` to node `j`, and wait until both see the connection
+    pub fn connect(&self, i: usize, j: usize) {
+        let (peer_count_i, peer_count_j) = (self.peer_count(i), self.peer_count(j));
+        let node = &self.nodes[i];
+        connect_to_seeds(vec![self.addrs[j]], &node.server, &node.blockchain);
+        while self.peer_count(i) == peer_count_i || self.peer_count(j) == peer_count_j {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    /// Close all connections, and wait until no node sees any
+    pub fn partition(&self) {
+        for node in self.nodes.iter() {
+            for addr in node.server.peer_addrs() {
+                node.server.disconnect_peer(addr);
+            }
+        }
+        while (0..self.nodes.len()).any(|i| self.peer_count(i) > 0) {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    /// Wait until all nodes have the same tip, and return it
+    pub fn wait_converged(&self) -> H256 {
+        loop {
+            let tips: HashSet<H256> = self
+                .nodes
+                .iter()
+                .map(|node| node.blockchain.lock().unwrap().tip())
+                .collect();
+            if tips.len() == 1 {
+                return *tips.iter().next().unwrap();
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    fn peer_count(&self, i: usize) -> usize {
+        self.nodes[i].server.peer_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Node, NodeConfig, TestNetwork};
+    use crate::blockchain::GenesisParams;
+    use crate::types::address::address_from_seed;
+    use crate::types::transaction::{sign_transaction, Transaction, TxValidationError};
+    use ntest::timeout;
+    use std::thread;
+    use std::time;
+
+    #[test]
+    #[timeout(60000)]
+    fn two_nodes_sync_a_block() {
+        let node_1 = Node::start(NodeConfig {
+            listen_addr: "127.0.0.1:16180".parse().unwrap(),
+            ..Default::default()
+        })
+        .unwrap();
+        node_1.miner.mine_n(1);
+        while node_1.blockchain.lock().unwrap().tip_height() < 1 {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let mined_tip = node_1.blockchain.lock().unwrap().tip();
+
+        let node_2 = Node::start(NodeConfig {
+            listen_addr: "127.0.0.1:16181".parse().unwrap(),
+            seed_peers: vec!["127.0.0.1:16180".parse().unwrap()],
+            ..Default::default()
+        })
+        .unwrap();
+        while node_2.blockchain.lock().unwrap().tip() != mined_tip {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert_eq!(node_1.server.peer_count(), 1);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn converge_after_partition() {
+        let network = TestNetwork::start(&[
+            "127.0.0.1:16182".parse().unwrap(),
+            "127.0.0.1:16183".parse().unwrap(),
+        ]);
+        network.connect(1, 0);
+        network.partition();
+
+        // each side mines its own fork, the second one with more work
+        let mine = |i: usize, n: u128| {
+            let node = &network.nodes[i];
+            for height in 1..=n {
+                node.miner.mine_n(1);
+                while node.blockchain.lock().unwrap().tip_height() < height {
+                    thread::sleep(time::Duration::from_millis(10));
+                }
+            }
+        };
+        mine(0, 2);
+        mine(1, 3);
+        let heavier_tip = network.nodes[1].blockchain.lock().unwrap().tip();
+        assert_ne!(
+            network.nodes[0].blockchain.lock().unwrap().tip(),
+            heavier_tip
+        );
+
+        network.connect(1, 0);
+        assert_eq!(network.wait_converged(), heavier_tip);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn submit_transaction_to_peers() {
+        let (alice, alice_key) = address_from_seed(1);
+        let bob = address_from_seed(2).0;
+        // the peer checks the balance of relayed transactions too
+        let config = NodeConfig {
+            genesis: GenesisParams {
+                premine: vec![(alice, 100)],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let network = TestNetwork::start_with_config(
+            &[
+                "127.0.0.1:16184".parse().unwrap(),
+                "127.0.0.1:16185".parse().unwrap(),
+            ],
+            &config,
+        );
+        network.connect(1, 0);
+
+        let node = &network.nodes[0];
+        let overspending_tx = sign_transaction(Transaction::new(alice, bob, 200, 0, 0), &alice_key);
+        assert_eq!(
+            node.submit_transaction(overspending_tx),
+            Err(TxValidationError::InsufficientBalance)
+        );
+        let tx = sign_transaction(Transaction::new(alice, bob, 30, 1, 0), &alice_key);
+        assert_eq!(node.submit_transaction(tx.clone()), Ok(()));
+        assert!(node.mempool.lock().unwrap().contains(&tx.txid()));
+        // the next nonce of the wallet applies after the pending transaction, up to its balance
+        let next_tx = sign_transaction(Transaction::new(alice, bob, 30, 1, 1), &alice_key);
+        assert_eq!(node.submit_transaction(next_tx.clone()), Ok(()));
+        assert!(node.mempool.lock().unwrap().contains(&next_tx.txid()));
+        let overspending_tx = sign_transaction(Transaction::new(alice, bob, 50, 0, 2), &alice_key);
+        assert_eq!(
+            node.submit_transaction(overspending_tx),
+            Err(TxValidationError::InsufficientBalance)
+        );
+
+        // the peer fetches the announced transaction
+        while !network.nodes[1]
+            .mempool
+            .lock()
+            .unwrap()
+            .contains(&tx.txid())
+        {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn submit_transaction_to_fanout_peers() {
+        use crate::network::peer;
+
+        let (alice, alice_key) = address_from_seed(1);
+        let node = Node::start(NodeConfig {
+            listen_addr: "127.0.0.1:16188".parse().unwrap(),
+            relay_fanout: Some(2),
+            genesis: GenesisParams {
+                premine: vec![(alice, 100)],
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let peers: Vec<_> = (0..5)
+            .map(|i| {
+                let addr = format!("127.0.0.1:{}", 12350 + i).parse().unwrap();
+                let (peer, receiver) = peer::Handle::test_handle_with_addr(addr);
+                node.server.insert_test_peer(peer.clone());
+                (peer, receiver)
+            })
+            .collect();
+
+        let tx = sign_transaction(
+            Transaction::new(alice, [2u8; 20].into(), 30, 0, 0),
+            &alice_key,
+        );
+        assert_eq!(node.submit_transaction(tx), Ok(()));
+        let announced = peers
+            .iter()
+            .filter(|(peer, _)| peer.queue_depth() == 1)
+            .count();
+        assert_eq!(announced, 2);
+    }
+}
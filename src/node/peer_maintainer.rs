@@ -0,0 +1,133 @@
+use super::handshake;
+use crate::blockchain::Blockchain;
+use crate::network::server::Handle as ServerHandle;
+
+use log::{info, warn};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Interval between two checks of the number of connected peers
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Dials known peers while fewer than `min_peers` are connected, e.g. after some of them
+/// disconnect
+#[derive(Clone)]
+pub struct PeerMaintainer {
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    min_peers: usize,
+    known_peers: Arc<Mutex<Vec<SocketAddr>>>, // in the order they become known
+}
+
+impl PeerMaintainer {
+    pub fn new(
+        server: &ServerHandle,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        min_peers: usize,
+        known_peers: &[SocketAddr],
+    ) -> Self {
+        let maintainer = Self {
+            server: server.clone(),
+            blockchain: Arc::clone(blockchain),
+            min_peers,
+            known_peers: Arc::new(Mutex::new(vec![])),
+        };
+        for addr in known_peers {
+            maintainer.add_known_peer(*addr);
+        }
+        maintainer
+    }
+
+    pub fn start(&self) {
+        let maintainer = self.clone();
+        thread::Builder::new()
+            .name("peer-maintainer".to_string())
+            .spawn(move || loop {
+                thread::sleep(CHECK_INTERVAL);
+                maintainer.check();
+            })
+            .unwrap();
+        info!("Peer maintainer started, keeping {} peers", self.min_peers);
+    }
+
+    /// Get the addresses which may be dialed, in the order they become known
+    pub fn known_peers(&self) -> Vec<SocketAddr> {
+        self.known_peers.lock().unwrap().clone()
+    }
+
+    pub fn add_known_peer(&self, addr: SocketAddr) {
+        let mut known_peers = self.known_peers.lock().unwrap();
+        if !known_peers.contains(&addr) {
+            known_peers.push(addr);
+        }
+    }
+
+    /// Dial the known peers not connected, as many as missing to reach `min_peers`, and start the
+    /// handshake with the ones answering. Return the number of dial attempts.
+    pub fn check(&self) -> usize {
+        let peer_addrs = self.server.peer_addrs();
+        if peer_addrs.len() >= self.min_peers {
+            return 0;
+        }
+        let missing = self.min_peers - peer_addrs.len();
+        let candidates: Vec<SocketAddr> = self
+            .known_peers()
+            .into_iter()
+            .filter(|addr| !peer_addrs.contains(addr))
+            .take(missing)
+            .collect();
+
+        for addr in candidates.iter() {
+            match self.server.connect(*addr) {
+                Ok(mut peer_handle) => {
+                    info!(
+                        "Connected to known peer {} to keep {} peers",
+                        addr, self.min_peers
+                    );
+                    handshake(&mut peer_handle, &self.server, &self.blockchain);
+                }
+                // e.g. a peer connected to us from another address, tried again at the next check
+                Err(e) => warn!("Error dialing known peer {}: {}", addr, e),
+            }
+        }
+        candidates.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerMaintainer;
+    use crate::node::TestNetwork;
+    use ntest::timeout;
+    use std::thread;
+    use std::time;
+
+    #[test]
+    #[timeout(60000)]
+    fn dial_known_peer_below_min_peers() {
+        let network = TestNetwork::start(&[
+            "127.0.0.1:16186".parse().unwrap(),
+            "127.0.0.1:16187".parse().unwrap(),
+        ]);
+        let node = &network.nodes[0];
+        let known_addr = "127.0.0.1:16187".parse().unwrap();
+        let maintainer = PeerMaintainer::new(&node.server, &node.blockchain, 1, &[known_addr]);
+        assert_eq!(maintainer.known_peers(), vec![known_addr]);
+
+        assert_eq!(maintainer.check(), 1);
+        assert_eq!(node.server.peer_addrs(), vec![known_addr]);
+        // enough peers, nothing to dial
+        assert_eq!(maintainer.check(), 0);
+
+        // dialed again once the peer disconnects, until it accepts as the periodic checks do
+        network.partition();
+        assert_eq!(maintainer.check(), 1);
+        while node.server.peer_count() == 0 {
+            thread::sleep(time::Duration::from_millis(10));
+            maintainer.check();
+        }
+        assert_eq!(node.server.peer_addrs(), vec![known_addr]);
+    }
+}
@@ -56,11 +56,33 @@ impl Address {
         return Address::from(&addr_bytes_buffer);
     }
 }
+
+#[cfg(any(test, test_utilities))]
+/// Generate a random key pair and the address it controls
+pub fn random_address_with_keypair() -> (Address, ring::signature::Ed25519KeyPair) {
+    use ring::signature::KeyPair;
+
+    let key = super::key_pair::random();
+    let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+    (addr, key)
+}
+
+#[cfg(any(test, test_utilities))]
+/// Derive the same key pair and address from a seed on every run, so that tests can refer to
+/// consistent participants. Anyone knowing the seed has the key, so never use it in production.
+pub fn address_from_seed(seed: u64) -> (Address, ring::signature::Ed25519KeyPair) {
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    let key_seed = digest::digest(&digest::SHA256, &seed.to_be_bytes());
+    let key = Ed25519KeyPair::from_seed_unchecked(key_seed.as_ref()).unwrap();
+    let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+    (addr, key)
+}
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
 mod test {
-    use super::Address;
+    use super::{address_from_seed, random_address_with_keypair, Address};
 
     #[test]
     fn from_a_test_key() {
@@ -72,6 +94,34 @@ mod test {
         // "0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d"
         // take the last 20 bytes, we get "1851a0eae0060a132cf0f64a0ffaea248de6cba0"
     }
+
+    #[test]
+    fn random_address_matches_key() {
+        use ring::signature::KeyPair;
+
+        let (addr, key) = random_address_with_keypair();
+        assert_eq!(
+            addr,
+            Address::from_public_key_bytes(key.public_key().as_ref())
+        );
+        let (other_addr, _) = random_address_with_keypair();
+        assert_ne!(addr, other_addr);
+    }
+
+    #[test]
+    fn same_address_from_same_seed() {
+        use ring::signature::KeyPair;
+
+        let (addr, key) = address_from_seed(42);
+        let (same_addr, same_key) = address_from_seed(42);
+        assert_eq!(addr, same_addr);
+        assert_eq!(key.public_key().as_ref(), same_key.public_key().as_ref());
+        assert_eq!(
+            addr,
+            Address::from_public_key_bytes(key.public_key().as_ref())
+        );
+        assert_ne!(addr, address_from_seed(43).0);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,13 +1,25 @@
-use crate::types::hash::{Hashable, H256};
-use ring::digest::{digest, SHA256};
-use serde::{Deserialize, Serialize};
+use crate::types::hash::{Hashable, Hasher, Sha256Hasher, H256};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::OnceLock;
 
-use super::transaction::SignedTransaction;
+use super::merkle::MerkleTree;
+use super::transaction::{SignedTransaction, SignedTransactionBatch, Transaction};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Version of the block layout written by this node
+///
+/// A block is serialized as its version, then a payload of its fields. Migration policy: a new
+/// version only appends fields to the payload, and bumps this number. A node decodes the fields
+/// it knows and ignores the rest, so that it still reads blocks of newer versions, while a field
+/// missing from the payload of an older version is to be decoded as its default value.
+///
+/// Version 1 appends the transaction batches of the content.
+pub const BLOCK_VERSION: u16 = 1;
+
+#[derive(Debug, Clone)]
 pub struct Block {
     pub header: Header,
-    pub content: Content,
+    content: Content, // only modified through `content_mut`, which resets the merkle root
+    merkle_root: OnceLock<H256>, // of the content, computed on first use and never serialized
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -20,24 +32,107 @@ pub struct Header {
 }
 
 impl Hashable for Header {
-    fn hash(&self) -> H256 {
+    fn hash_with<Hs: Hasher>(&self) -> H256 {
         let serialized_header = bincode::serialize(self).unwrap();
-        digest(&SHA256, &serialized_header).into()
+        Hs::digest(&serialized_header)
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Content {
     pub data: Vec<SignedTransaction>,
+    pub batches: Vec<SignedTransactionBatch>, // applied after `data`, none before version 1
+}
+
+impl Content {
+    /// Get the merkle root of the transactions, combined with the one of the batches if any, so
+    /// that the root of a content without batches is the same as before version 1
+    pub fn merkle_root(&self) -> H256 {
+        let tx_root = MerkleTree::new(&self.data).root();
+        if self.batches.is_empty() {
+            return tx_root;
+        }
+        let batch_root = MerkleTree::new(&self.batches).root();
+        Sha256Hasher::digest(&[tx_root.as_ref(), batch_root.as_ref()].concat())
+    }
+
+    /// Iterate over all transactions, those of the batches after the others
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.data
+            .iter()
+            .map(|signed_tx| signed_tx.transaction())
+            .chain(
+                self.batches
+                    .iter()
+                    .flat_map(|batch| batch.transactions().iter()),
+            )
+    }
+
+    /// Get the number of transactions, including those in batches
+    pub fn tx_count(&self) -> usize {
+        self.data.len()
+            + self
+                .batches
+                .iter()
+                .map(|batch| batch.transactions().len())
+                .sum::<usize>()
+    }
 }
 
 impl Hashable for Block {
-    fn hash(&self) -> H256 {
-        self.header.hash()
+    fn hash_with<Hs: Hasher>(&self) -> H256 {
+        self.header.hash_with::<Hs>()
+    }
+}
+
+impl Serialize for Block {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let payload = bincode::serialize(&(&self.header, &self.content))
+            .map_err(serde::ser::Error::custom)?;
+        (BLOCK_VERSION, payload).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Block {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (version, payload): (u16, Vec<u8>) = Deserialize::deserialize(deserializer)?;
+        // each version starts with the fields of the ones before, the trailing ones are skipped
+        let mut reader = &payload[..];
+        let (header, data): (Header, Vec<SignedTransaction>) =
+            bincode::deserialize_from(&mut reader).map_err(serde::de::Error::custom)?;
+        let batches = if version >= 1 {
+            bincode::deserialize_from(&mut reader).map_err(serde::de::Error::custom)?
+        } else {
+            vec![]
+        };
+        Ok(Block::new(header, Content { data, batches }))
     }
 }
 
 impl Block {
+    pub fn new(header: Header, content: Content) -> Self {
+        Block {
+            header,
+            content,
+            merkle_root: OnceLock::new(),
+        }
+    }
+
+    pub fn content(&self) -> &Content {
+        &self.content
+    }
+
+    /// Get the content to modify, the merkle root is then computed again on next use
+    pub fn content_mut(&mut self) -> &mut Content {
+        self.merkle_root = OnceLock::new();
+        &mut self.content
+    }
+
+    /// Get the merkle root of the content, computed once until the content is modified
+    pub fn merkle_root(&self) -> H256 {
+        *self.merkle_root.get_or_init(|| self.content.merkle_root())
+    }
+
     pub fn get_parent(&self) -> H256 {
         self.header.parent
     }
@@ -50,25 +145,121 @@ impl Block {
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_block(parent: &H256) -> Block {
     use rand::Rng;
+    use std::sync::atomic::{AtomicU64, Ordering};
     use std::time::{SystemTime, UNIX_EPOCH};
 
-    use crate::types::merkle::MerkleTree;
+    use crate::blockchain::GENESIS_DIFFICULTY;
+
+    // strictly increasing across calls, so that chained random blocks pass the timestamp check
+    static LAST_TIME_MS: AtomicU64 = AtomicU64::new(0);
 
     let mut rng = rand::thread_rng();
-    let time_ms = SystemTime::now()
+    let now_ms = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
-        .as_millis();
+        .as_millis() as u64;
+    let last_time_ms = LAST_TIME_MS
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(now_ms.max(last + 1))
+        })
+        .unwrap();
+    let time_ms = now_ms.max(last_time_ms + 1) as u128;
     let data: Vec<SignedTransaction> = Vec::new();
 
-    Block {
-        header: Header {
+    Block::new(
+        Header {
             parent: *parent,
             nonce: rng.gen(),
-            difficulty: H256::from([1u8; 32]),
+            difficulty: H256::from(GENESIS_DIFFICULTY),
             timestamp: time_ms,
             merkle_root: MerkleTree::new(&data).root(),
         },
-        content: Content { data },
+        Content {
+            data,
+            batches: vec![],
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_random_block, Block};
+    use crate::types::address::random_address_with_keypair;
+    use crate::types::hash::Hashable;
+    use crate::types::transaction::{generate_random_signed_transaction, sign_batch, Transaction};
+
+    #[test]
+    fn decode_version_0_block() {
+        let mut block = generate_random_block(&[1u8; 32].into());
+        block.content_mut().data = vec![generate_random_signed_transaction()];
+        let payload = bincode::serialize(&(&block.header, &block.content().data)).unwrap();
+        let bytes = bincode::serialize(&(0u16, payload)).unwrap();
+
+        let decoded: Block = bincode::deserialize(&bytes).unwrap();
+        assert!(decoded.content().batches.is_empty());
+        assert_eq!(decoded.hash(), block.hash());
+        assert_eq!(
+            decoded.content().data[0].txid(),
+            block.content().data[0].txid()
+        );
+    }
+
+    #[test]
+    fn decode_version_1_block_with_batches() {
+        let (sender, key) = random_address_with_keypair();
+        let mut block = generate_random_block(&[1u8; 32].into());
+        let tx_root = block.merkle_root();
+        block.content_mut().batches = vec![sign_batch(
+            vec![Transaction::new(sender, [2u8; 20].into(), 1, 0, 0)],
+            &key,
+        )];
+        // the batches are part of the merkle root
+        assert_ne!(block.merkle_root(), tx_root);
+        let payload = bincode::serialize(&(&block.header, &block.content())).unwrap();
+        let bytes = bincode::serialize(&(1u16, payload)).unwrap();
+        assert_eq!(bytes, bincode::serialize(&block).unwrap());
+
+        let decoded: Block = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.content().tx_count(), 1);
+        assert_eq!(decoded.merkle_root(), block.merkle_root());
+    }
+
+    #[test]
+    fn decode_newer_block_with_extra_field() {
+        let block = generate_random_block(&[1u8; 32].into());
+        // a later version appending a field unknown to this node
+        let payload = bincode::serialize(&(&block.header, &block.content(), 42u64)).unwrap();
+        let bytes = bincode::serialize(&(2u16, payload)).unwrap();
+
+        let decoded: Block = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.hash(), block.hash());
+        let round_tripped: Block =
+            bincode::deserialize(&bincode::serialize(&decoded).unwrap()).unwrap();
+        assert_eq!(round_tripped.hash(), block.hash());
+        assert!(round_tripped.content().data.is_empty());
+    }
+
+    #[test]
+    fn cache_merkle_root() {
+        use crate::types::merkle::MerkleTree;
+
+        let mut block = generate_random_block(&[1u8; 32].into());
+        block.content_mut().data = vec![
+            generate_random_signed_transaction(),
+            generate_random_signed_transaction(),
+        ];
+        let root = MerkleTree::new(&block.content().data).root();
+        assert_eq!(block.merkle_root(), root);
+        assert_eq!(block.merkle_root(), root);
+
+        // not serialized, so computed again
+        let decoded: Block = bincode::deserialize(&bincode::serialize(&block).unwrap()).unwrap();
+        assert_eq!(decoded.merkle_root(), root);
+        // reset once the content is modified, also on a clone
+        let mut cloned = block.clone();
+        cloned.content_mut().data.pop();
+        assert_ne!(cloned.merkle_root(), root);
+        block.content_mut().data.pop();
+        assert_eq!(block.merkle_root(), cloned.merkle_root());
     }
 }
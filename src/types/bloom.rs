@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+use super::hash::H256;
+
+/// Number of filter bits reserved per expected item
+const BITS_PER_ITEM: usize = 10;
+/// Number of bit positions set per item, optimal for `BITS_PER_ITEM` is about `10 * ln 2`
+const NUM_HASHES: u32 = 7;
+/// Upper bound on the hash count accepted from a peer, to bound the cost of a query
+const MAX_NUM_HASHES: u32 = 32;
+
+/// A Bloom filter summarizing a set of hashes, e.g. the blocks known by a node.
+///
+/// `contains` never returns false for an inserted hash (no false negative), but may return true
+/// for a hash that was never inserted. With `n` items, `m` bits and `k` hashes, the false positive
+/// rate is about `(1 - e^(-k * n / m))^k`, which is below 1% for the default 10 bits per item and 7
+/// hashes. A false positive means the holder of the filter is assumed to know a block it does not
+/// have, so it can still fetch that block through the usual `NewBlockHashes` gossip.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Create an empty filter sized for `expected_items` items
+    pub fn new(expected_items: usize) -> Self {
+        let num_bytes = (expected_items.max(1) * BITS_PER_ITEM).div_ceil(8);
+        BloomFilter {
+            bits: vec![0u8; num_bytes],
+            num_hashes: NUM_HASHES,
+        }
+    }
+
+    pub fn insert(&mut self, hash: &H256) {
+        for bit_idx in self.bit_indices(hash) {
+            self.bits[bit_idx / 8] |= 1 << (bit_idx % 8);
+        }
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.bit_indices(hash)
+            .into_iter()
+            .all(|bit_idx| self.bits[bit_idx / 8] & (1 << (bit_idx % 8)) != 0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Parse a filter received from a peer, return None if it is malformed
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let filter: BloomFilter = bincode::deserialize(bytes).ok()?;
+        if filter.bits.is_empty() || filter.num_hashes == 0 || filter.num_hashes > MAX_NUM_HASHES {
+            return None;
+        }
+        Some(filter)
+    }
+
+    // The hash is already uniformly distributed, so derive the k positions by double hashing
+    // from two 64-bit words of it instead of rehashing.
+    fn bit_indices(&self, hash: &H256) -> Vec<usize> {
+        let bytes = hash.as_ref();
+        let h1 = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+        let h2 = u64::from_be_bytes(bytes[8..16].try_into().unwrap());
+        let num_bits = (self.bits.len() * 8) as u64;
+
+        (0..self.num_hashes as u64)
+            .map(|i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomFilter;
+    use crate::types::hash::{generate_random_hash, H256};
+
+    #[test]
+    fn no_false_negative() {
+        let hashes: Vec<H256> = (0..1000).map(|_| generate_random_hash()).collect();
+        let mut filter = BloomFilter::new(hashes.len());
+        for hash in hashes.iter() {
+            filter.insert(hash);
+        }
+        assert!(hashes.iter().all(|hash| filter.contains(hash)));
+
+        let filter = BloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+        assert!(hashes.iter().all(|hash| filter.contains(hash)));
+    }
+
+    #[test]
+    fn reject_malformed_filter() {
+        assert!(BloomFilter::from_bytes(&[1, 2, 3]).is_none());
+        let empty = BloomFilter {
+            bits: vec![],
+            num_hashes: 7,
+        };
+        assert!(BloomFilter::from_bytes(&empty.to_bytes()).is_none());
+    }
+}
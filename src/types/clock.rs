@@ -0,0 +1,49 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the current unix time in millis
+pub trait Clock: Send + Sync {
+    fn now_ms(&self) -> u128;
+}
+
+/// The system's wall clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+/// A clock which only moves when told to, for deterministic tests
+pub struct MockClock {
+    now_ms: std::sync::Mutex<u128>,
+}
+
+#[cfg(any(test, test_utilities))]
+impl MockClock {
+    pub fn new(now_ms: u128) -> Self {
+        MockClock {
+            now_ms: std::sync::Mutex::new(now_ms),
+        }
+    }
+
+    pub fn set(&self, now_ms: u128) {
+        *self.now_ms.lock().unwrap() = now_ms;
+    }
+
+    pub fn advance(&self, delta_ms: u128) {
+        *self.now_ms.lock().unwrap() += delta_ms;
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+impl Clock for MockClock {
+    fn now_ms(&self) -> u128 {
+        *self.now_ms.lock().unwrap()
+    }
+}
@@ -5,8 +5,27 @@ use rand::Rng;
 
 /// An object that can be meaningfully hashed.
 pub trait Hashable {
+    /// Hash the object using the given hash function.
+    fn hash_with<Hs: Hasher>(&self) -> H256;
+
     /// Hash the object using SHA256.
-    fn hash(&self) -> H256;
+    fn hash(&self) -> H256 {
+        self.hash_with::<Sha256Hasher>()
+    }
+}
+
+/// A hash function producing 256-bit digests.
+pub trait Hasher {
+    fn digest(data: &[u8]) -> H256;
+}
+
+/// SHA256, the default hash function.
+pub struct Sha256Hasher;
+
+impl Hasher for Sha256Hasher {
+    fn digest(data: &[u8]) -> H256 {
+        ring::digest::digest(&ring::digest::SHA256, data).into()
+    }
 }
 
 /// A SHA256 hash.
@@ -14,8 +33,8 @@ pub trait Hashable {
 pub struct H256([u8; 32]); // big endian u256
 
 impl Hashable for H256 {
-    fn hash(&self) -> H256 {
-        ring::digest::digest(&ring::digest::SHA256, &self.0).into()
+    fn hash_with<Hs: Hasher>(&self) -> H256 {
+        Hs::digest(&self.0)
     }
 }
 
@@ -89,6 +108,8 @@ impl std::convert::From<ring::digest::Digest> for H256 {
     }
 }
 
+/// Hashes compare as 256-bit big-endian integers, the first byte being the most significant. A
+/// block meets its difficulty when its hash is `<=` the target, so this order is what PoW means.
 impl Ord for H256 {
     fn cmp(&self, other: &H256) -> std::cmp::Ordering {
         let self_higher = u128::from_be_bytes(self.0[0..16].try_into().unwrap());
@@ -109,6 +130,74 @@ impl PartialOrd for H256 {
     }
 }
 
+/// Mask of the bit which Bitcoin reads as the sign of the mantissa.
+const COMPACT_SIGN_BIT: u32 = 0x0080_0000;
+
+impl H256 {
+    /// Encode a target in 32 bits, as Bitcoin's nBits. The highest byte is the exponent, the number
+    /// of significant bytes of the target, and the lower 3 bytes are the mantissa, the 3 most
+    /// significant bytes of the target. The sign bit of the mantissa is kept clear by taking one
+    /// more exponent byte. Lossy: the bytes below the mantissa are dropped.
+    pub fn to_compact(&self) -> u32 {
+        let first_nonzero = match self.0.iter().position(|byte| *byte != 0) {
+            Some(idx) => idx,
+            None => return 0,
+        };
+        let mut mantissa_bytes = [0u8; 4]; // big endian, the first byte is left for the exponent
+        for k in 0..3 {
+            if first_nonzero + k < 32 {
+                mantissa_bytes[1 + k] = self.0[first_nonzero + k];
+            }
+        }
+        let mut mantissa = u32::from_be_bytes(mantissa_bytes);
+        let mut size = (32 - first_nonzero) as u32;
+        if mantissa & COMPACT_SIGN_BIT != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        (size << 24) | mantissa
+    }
+
+    /// Decode a target from `to_compact`. A negative mantissa decodes to zero, an exponent too
+    /// large for 256 bits saturates to the max target, and the bytes below the lowest one are
+    /// dropped.
+    pub fn from_compact(compact: u32) -> H256 {
+        if compact & COMPACT_SIGN_BIT != 0 {
+            return H256::default();
+        }
+        let size = (compact >> 24) as isize;
+        let mantissa_bytes = compact.to_be_bytes();
+        let mut raw_bytes = [0u8; 32];
+        for k in 0..3 {
+            let byte = mantissa_bytes[1 + k];
+            // index of this byte in the big endian target
+            let idx = 32 - size + k as isize;
+            if idx < 0 {
+                if byte != 0 {
+                    return H256([255u8; 32]);
+                }
+            } else if idx < 32 {
+                raw_bytes[idx as usize] = byte;
+            }
+        }
+        H256(raw_bytes)
+    }
+
+    /// Get how many times harder this target is than the genesis one, e.g. 2.0 for half of it.
+    /// A zero target can never be met, so it is infinitely hard.
+    pub fn difficulty_ratio(&self, genesis_target: &H256) -> f64 {
+        if *self == H256::default() {
+            return f64::INFINITY;
+        }
+        genesis_target.approx_value() / self.approx_value()
+    }
+
+    // approximate value as a 256-bit big endian integer
+    fn approx_value(&self) -> f64 {
+        self.0.iter().fold(0f64, |value, byte| value * 256f64 + *byte as f64)
+    }
+}
+
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_hash() -> H256 {
     let mut rng = rand::thread_rng();
@@ -116,4 +205,82 @@ pub fn generate_random_hash() -> H256 {
     let mut raw_bytes = [0; 32];
     raw_bytes.copy_from_slice(&random_bytes);
     (&raw_bytes).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::H256;
+
+    #[test]
+    fn compact_round_trip() {
+        // bitcoin's genesis target is exact
+        let mut raw_bytes = [0u8; 32];
+        raw_bytes[4] = 0xff;
+        raw_bytes[5] = 0xff;
+        let target = H256::from(raw_bytes);
+        assert_eq!(target.to_compact(), 0x1d00ffff);
+        assert_eq!(H256::from_compact(0x1d00ffff), target);
+
+        let mut raw_bytes = [0u8; 32];
+        raw_bytes[2..5].copy_from_slice(&[0x12, 0x34, 0x56]);
+        let target = H256::from(raw_bytes);
+        assert_eq!(H256::from_compact(target.to_compact()), target);
+
+        let mut raw_bytes = [0u8; 32];
+        raw_bytes[31] = 1;
+        let target = H256::from(raw_bytes);
+        assert_eq!(target.to_compact(), 0x01010000);
+        assert_eq!(H256::from_compact(target.to_compact()), target);
+
+        assert_eq!(H256::default().to_compact(), 0);
+        assert_eq!(H256::from_compact(0), H256::default());
+    }
+
+    #[test]
+    fn compact_lossy_and_out_of_range() {
+        // the max target keeps only its 2 highest bytes, the sign bit taking the third one
+        let max_target = H256::from([255u8; 32]);
+        assert_eq!(max_target.to_compact(), 0x2100ffff);
+        let mut raw_bytes = [0u8; 32];
+        raw_bytes[0] = 0xff;
+        raw_bytes[1] = 0xff;
+        assert_eq!(H256::from_compact(max_target.to_compact()), H256::from(raw_bytes));
+
+        // overflow saturates, a negative mantissa is zero
+        assert_eq!(H256::from_compact(0x22010000), max_target);
+        assert_eq!(H256::from_compact(0x1d80ffff), H256::default());
+    }
+
+    #[test]
+    fn difficulty_ratio_of_targets() {
+        let genesis_target = H256::from([255u8; 32]);
+        let mut raw_bytes = [255u8; 32];
+        raw_bytes[0] = 0x7f;
+        let half_target = H256::from(raw_bytes);
+        assert!((half_target.difficulty_ratio(&genesis_target) - 2.0).abs() < 1e-9);
+
+        assert_eq!(genesis_target.difficulty_ratio(&genesis_target), 1.0);
+        assert_eq!(H256::default().difficulty_ratio(&genesis_target), f64::INFINITY);
+    }
+
+    #[test]
+    fn order_by_most_significant_byte_first() {
+        let mut low_bytes = [0u8; 32];
+        low_bytes[0] = 0x01;
+        let mut high_bytes = [0u8; 32];
+        high_bytes[0] = 0x02;
+        assert!(H256::from(low_bytes) < H256::from(high_bytes));
+
+        // the less significant bytes only order hashes with the same first bytes
+        for byte in low_bytes[1..].iter_mut() {
+            *byte = 0xff;
+        }
+        assert!(H256::from(low_bytes) < H256::from(high_bytes));
+        let mut byte_15 = [0u8; 32];
+        byte_15[15] = 0x01;
+        let mut byte_16 = [0u8; 32];
+        byte_16[16] = 0xff;
+        assert!(H256::from(byte_16) < H256::from(byte_15));
+        assert_eq!(H256::from(byte_15).cmp(&H256::from(byte_15)), std::cmp::Ordering::Equal);
+    }
 }
\ No newline at end of file
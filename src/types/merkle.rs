@@ -1,8 +1,9 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 
-use ring::digest;
+use super::hash::{Hashable, Hasher, Sha256Hasher, H256};
 
-use super::hash::{Hashable, H256};
+/// Root of the tree of no data, e.g. of a block without transactions
+pub const EMPTY_MERKLE_ROOT: [u8; 32] = [0u8; 32];
 
 #[derive(Debug, Default)]
 pub struct MerkleNode {
@@ -20,14 +21,9 @@ impl MerkleNode {
         }
     }
 
-    // create a new node with separate copies of children
-    pub fn new_from_children(left: &MerkleNode, right: &MerkleNode) -> Self {
-        let left_val_bytes = <[u8; 32]>::from(left.value);
-        let right_val_bytes = <[u8; 32]>::from(right.value);
-        let mut buffer: [u8; 64] = [0; 64];
-        buffer[..32].copy_from_slice(&left_val_bytes);
-        buffer[32..].copy_from_slice(&right_val_bytes);
-        let cat_hash = digest::digest(&digest::SHA256, &buffer).into();
+    // create a new node with separate copies of children, hashing their concatenation with `Hs`
+    pub fn new_from_children<Hs: Hasher>(left: &MerkleNode, right: &MerkleNode) -> Self {
+        let cat_hash = hash_concat::<Hs>(&left.value, &right.value);
 
         MerkleNode {
             left: Some(Box::new(left.clone())),
@@ -63,6 +59,15 @@ impl MerkleTree {
     pub fn new<T>(data: &[T]) -> Self
     where
         T: Hashable,
+    {
+        Self::new_with_hasher::<T, Sha256Hasher>(data)
+    }
+
+    /// Build the tree with `Hs` hashing both the leaves and the interior nodes
+    pub fn new_with_hasher<T, Hs>(data: &[T]) -> Self
+    where
+        T: Hashable,
+        Hs: Hasher,
     {
         let mut level_to_nodes = HashMap::new();
         let mut cur_height: usize = 0;
@@ -73,7 +78,7 @@ impl MerkleTree {
                 root: MerkleNode {
                     left: None,
                     right: None,
-                    value: Default::default(),
+                    value: EMPTY_MERKLE_ROOT.into(),
                 },
                 level_to_nodes,
                 leaf_level_size,
@@ -83,7 +88,7 @@ impl MerkleTree {
 
         let mut cur_level_nodes: VecDeque<MerkleNode> = VecDeque::new(); // queue of nodes on current level
         data.into_iter().for_each(|item| {
-            let temp_node = MerkleNode::new_from_val(item.hash());
+            let temp_node = MerkleNode::new_from_val(item.hash_with::<Hs>());
             cur_level_nodes.push_back(temp_node);
         }); // ownership of nodes
 
@@ -98,7 +103,7 @@ impl MerkleTree {
             for i in (0..cur_level_nodes.len()).step_by(2) {
                 let left = cur_level_nodes.get(i).unwrap();
                 let right = cur_level_nodes.get(i + 1).unwrap_or(left);
-                let parent = MerkleNode::new_from_children(left, right);
+                let parent = MerkleNode::new_from_children::<Hs>(left, right);
                 parent_nodes.push_back(parent);
             }
             level_to_nodes.insert(cur_height, cur_level_nodes);
@@ -119,6 +124,15 @@ impl MerkleTree {
         self.root.value
     }
 
+    /// Get the node values of each level, from the leaves to the root and from left to right in
+    /// each level, e.g. for debugging proofs. A tree of no data has no level.
+    pub fn to_levels(&self) -> Vec<Vec<H256>> {
+        (0..=self.height)
+            .filter_map(|height| self.level_to_nodes.get(&height))
+            .map(|nodes| nodes.iter().map(|node| node.value).collect())
+            .collect()
+    }
+
     /// Returns the Merkle Proof of data at index i
     pub fn proof(&self, index: usize) -> Vec<H256> {
         let mut proof: Vec<H256> = vec![];
@@ -142,11 +156,72 @@ impl MerkleTree {
 
         proof
     }
+
+    /// Returns one Merkle Proof for the data at all given indices: the sibling hashes needed to
+    /// verify all of them, without the ones derivable from other given data. Siblings are ordered
+    /// by level from the leaves up, then by index. The proof is empty if any index is out of
+    /// range or repeated.
+    pub fn multiproof(&self, indices: &[usize]) -> Vec<H256> {
+        let mut proof: Vec<H256> = vec![];
+        let mut known_indices: BTreeSet<usize> = indices.iter().cloned().collect();
+        if known_indices.len() != indices.len()
+            || known_indices.iter().any(|idx| *idx >= self.leaf_level_size)
+        {
+            return proof;
+        }
+        let mut cur_level_size = self.leaf_level_size;
+
+        for cur_height in 0..self.height {
+            let cur_level_nodes = self.level_to_nodes.get(&cur_height).unwrap();
+            for idx in known_indices.iter() {
+                let sibling_idx = idx ^ 1;
+                if sibling_idx < cur_level_size && !known_indices.contains(&sibling_idx) {
+                    proof.push(cur_level_nodes[sibling_idx].value);
+                }
+            }
+            known_indices = known_indices.iter().map(|idx| idx / 2).collect();
+            cur_level_size = cur_level_size.div_ceil(2);
+        }
+
+        proof
+    }
 }
 
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
 /// index of datum and `leaf_size`, the total number of leaves.
 pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
+    verify_with_hasher::<Sha256Hasher>(root, datum, proof, index, leaf_size)
+}
+
+/// Same as `verify`, for a tree built with `MerkleTree::new_with_hasher::<_, Hs>`.
+pub fn verify_with_hasher<Hs: Hasher>(
+    root: &H256,
+    datum: &H256,
+    proof: &[H256],
+    index: usize,
+    leaf_size: usize,
+) -> bool {
+    compute_root_from_proof_with_hasher::<Hs>(datum, proof, index, leaf_size) == *root
+}
+
+/// Reconstruct the Merkle root from the datum hash and its proof, which `verify` compares to the
+/// expected root, e.g. to log both on a mismatch
+pub fn compute_root_from_proof(
+    datum: &H256,
+    proof: &[H256],
+    index: usize,
+    leaf_size: usize,
+) -> H256 {
+    compute_root_from_proof_with_hasher::<Sha256Hasher>(datum, proof, index, leaf_size)
+}
+
+/// Same as `compute_root_from_proof`, for a tree built with `MerkleTree::new_with_hasher::<_, Hs>`.
+pub fn compute_root_from_proof_with_hasher<Hs: Hasher>(
+    datum: &H256,
+    proof: &[H256],
+    index: usize,
+    leaf_size: usize,
+) -> H256 {
     let mut cur_hash = *datum;
     let mut cur_idx = index;
     let mut cur_level_size = leaf_size;
@@ -155,26 +230,76 @@ pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size
 
     // reconstruct root hash according to proof slice
     while cur_level_size > 1 {
-        let mut ctx = digest::Context::new(&digest::SHA256);
         if cur_idx % 2 == 1 {
             let even_hash = proof_vec.pop().unwrap();
-            ctx.update(even_hash.as_ref());
-            ctx.update(cur_hash.as_ref());
+            cur_hash = hash_concat::<Hs>(&even_hash, &cur_hash);
         } else if cur_idx == cur_level_size - 1 {
-            ctx.update(cur_hash.as_ref());
-            ctx.update(cur_hash.as_ref()); // duplicate itself
+            cur_hash = hash_concat::<Hs>(&cur_hash, &cur_hash); // duplicate itself
         } else {
             let snd_hash = proof_vec.pop().unwrap();
-            ctx.update(cur_hash.as_ref());
-            ctx.update(snd_hash.as_ref());
+            cur_hash = hash_concat::<Hs>(&cur_hash, &snd_hash);
         }
-        cur_hash = ctx.finish().into();
         cur_idx /= 2;
         cur_level_size = (cur_level_size + 1) / 2;
     }
 
-    cur_hash == *root
+    cur_hash
+}
+/// Verify that the data hashes at the given indices, with a proof from `MerkleTree::multiproof`,
+/// will produce the Merkle root. `leaf_size` is the total number of leaves. Indices out of range
+/// or repeated are rejected.
+pub fn verify_multiproof(
+    root: &H256,
+    indexed_data: &[(usize, H256)],
+    proof: &[H256],
+    leaf_size: usize,
+) -> bool {
+    let mut known_hashes: BTreeMap<usize, H256> = indexed_data.iter().cloned().collect();
+    if known_hashes.is_empty()
+        || known_hashes.len() != indexed_data.len()
+        || known_hashes.keys().any(|idx| *idx >= leaf_size)
+    {
+        return false;
+    }
+    let mut proof_iter = proof.iter();
+    let mut cur_level_size = leaf_size;
+
+    // reconstruct root hash level by level, taking siblings from the proof in the same order
+    while cur_level_size > 1 {
+        let mut parent_hashes: BTreeMap<usize, H256> = BTreeMap::new();
+        for (idx, hash) in known_hashes.iter() {
+            let sibling_idx = idx ^ 1;
+            let sibling_hash = if sibling_idx >= cur_level_size {
+                *hash // duplicate itself
+            } else if let Some(sibling_hash) = known_hashes.get(&sibling_idx) {
+                *sibling_hash
+            } else {
+                match proof_iter.next() {
+                    Some(sibling_hash) => *sibling_hash,
+                    None => return false,
+                }
+            };
+            let parent_hash = if idx % 2 == 1 {
+                hash_concat::<Sha256Hasher>(&sibling_hash, hash)
+            } else {
+                hash_concat::<Sha256Hasher>(hash, &sibling_hash)
+            };
+            parent_hashes.insert(idx / 2, parent_hash);
+        }
+        known_hashes = parent_hashes;
+        cur_level_size = cur_level_size.div_ceil(2);
+    }
+
+    proof_iter.next().is_none() && known_hashes.get(&0) == Some(root)
+}
+
+fn hash_concat<Hs: Hasher>(left: &H256, right: &H256) -> H256 {
+    let mut buffer: [u8; 64] = [0; 64];
+    buffer[..32].copy_from_slice(left.as_ref());
+    buffer[32..].copy_from_slice(right.as_ref());
+    Hs::digest(&buffer)
 }
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
@@ -235,6 +360,179 @@ mod tests {
             input_data.len()
         ));
     }
+
+    #[test]
+    fn merkle_multiproof() {
+        let input_data: Vec<H256> = (0..8u8).map(|i| H256::from([i; 32])).collect();
+        let merkle_tree = MerkleTree::new(&input_data);
+        let indices = [1, 6];
+        let multiproof = merkle_tree.multiproof(&indices);
+        let naive_proof_len: usize = indices.iter().map(|i| merkle_tree.proof(*i).len()).sum();
+        assert_eq!(naive_proof_len, 6);
+        assert_eq!(multiproof.len(), 4);
+
+        let indexed_data: Vec<(usize, H256)> = indices
+            .iter()
+            .map(|i| (*i, input_data[*i].hash()))
+            .collect();
+        assert!(verify_multiproof(
+            &merkle_tree.root(),
+            &indexed_data,
+            &multiproof,
+            input_data.len()
+        ));
+
+        // a tampered datum or a truncated proof
+        let mut tampered_data = indexed_data.clone();
+        tampered_data[1].1 = input_data[5].hash();
+        assert!(!verify_multiproof(
+            &merkle_tree.root(),
+            &tampered_data,
+            &multiproof,
+            input_data.len()
+        ));
+        assert!(!verify_multiproof(
+            &merkle_tree.root(),
+            &indexed_data,
+            &multiproof[1..],
+            input_data.len()
+        ));
+    }
+
+    #[test]
+    fn merkle_multiproof_odd_leaves() {
+        let input_data: Vec<H256> = (0..5u8).map(|i| H256::from([i; 32])).collect();
+        let merkle_tree = MerkleTree::new(&input_data);
+        let indices = [0, 1, 4];
+        let multiproof = merkle_tree.multiproof(&indices);
+        let indexed_data: Vec<(usize, H256)> = indices
+            .iter()
+            .map(|i| (*i, input_data[*i].hash()))
+            .collect();
+        assert!(verify_multiproof(
+            &merkle_tree.root(),
+            &indexed_data,
+            &multiproof,
+            input_data.len()
+        ));
+    }
+
+    #[test]
+    fn reject_multiproof_of_bad_indices() {
+        let input_data: Vec<H256> = (0..5u8).map(|i| H256::from([i; 32])).collect();
+        let merkle_tree = MerkleTree::new(&input_data);
+        assert!(merkle_tree.multiproof(&[1, 5]).is_empty());
+        assert!(merkle_tree.multiproof(&[1, 1]).is_empty());
+
+        let multiproof = merkle_tree.multiproof(&[1]);
+        let datum = (1, input_data[1].hash());
+        assert!(verify_multiproof(
+            &merkle_tree.root(),
+            &[datum],
+            &multiproof,
+            input_data.len()
+        ));
+        // the same datum given twice, or at an index past the leaves
+        assert!(!verify_multiproof(
+            &merkle_tree.root(),
+            &[datum, datum],
+            &multiproof,
+            input_data.len()
+        ));
+        assert!(!verify_multiproof(
+            &merkle_tree.root(),
+            &[datum, (5, input_data[4].hash())],
+            &multiproof,
+            input_data.len()
+        ));
+    }
+
+    // XOR-folds the input into 32 bytes, only for telling hashers apart
+    struct XorHasher;
+
+    impl Hasher for XorHasher {
+        fn digest(data: &[u8]) -> H256 {
+            let mut buffer = [0u8; 32];
+            for (i, byte) in data.iter().enumerate() {
+                buffer[i % 32] ^= byte;
+            }
+            buffer.into()
+        }
+    }
+
+    #[test]
+    fn merkle_with_mock_hasher() {
+        let input_data: Vec<H256> = gen_merkle_tree_data!();
+        let sha_tree = MerkleTree::new(&input_data);
+        let mock_tree = MerkleTree::new_with_hasher::<H256, XorHasher>(&input_data);
+        assert_ne!(mock_tree.root(), sha_tree.root());
+        assert_eq!(
+            mock_tree.root(),
+            XorHasher::digest(
+                &[
+                    input_data[0].hash_with::<XorHasher>().as_ref(),
+                    input_data[1].hash_with::<XorHasher>().as_ref()
+                ]
+                .concat()
+            )
+        );
+
+        let proof = mock_tree.proof(1);
+        assert!(verify_with_hasher::<XorHasher>(
+            &mock_tree.root(),
+            &input_data[1].hash_with::<XorHasher>(),
+            &proof,
+            1,
+            input_data.len()
+        ));
+        assert!(!verify(
+            &mock_tree.root(),
+            &input_data[1].hash_with::<XorHasher>(),
+            &proof,
+            1,
+            input_data.len()
+        ));
+    }
+
+    #[test]
+    fn levels_of_four_leaves() {
+        let input_data: Vec<H256> = (1..=4u8).map(|i| [i; 32].into()).collect();
+        let merkle_tree = MerkleTree::new(&input_data);
+        let levels = merkle_tree.to_levels();
+
+        let leaves: Vec<H256> = input_data.iter().map(|datum| datum.hash()).collect();
+        let left = hash_concat::<Sha256Hasher>(&leaves[0], &leaves[1]);
+        let right = hash_concat::<Sha256Hasher>(&leaves[2], &leaves[3]);
+        assert_eq!(levels.len(), 3);
+        assert_eq!(levels[0], leaves);
+        assert_eq!(levels[1], vec![left, right]);
+        assert_eq!(levels[2], vec![hash_concat::<Sha256Hasher>(&left, &right)]);
+        assert_eq!(levels[2][0], merkle_tree.root());
+        assert!(MerkleTree::new::<H256>(&[]).to_levels().is_empty());
+    }
+
+    #[test]
+    fn compute_root_of_tampered_proof() {
+        let input_data: Vec<H256> = (0..5u8).map(|i| H256::from([i; 32])).collect();
+        let merkle_tree = MerkleTree::new(&input_data);
+        let mut proof = merkle_tree.proof(2);
+        let datum = input_data[2].hash();
+        assert_eq!(
+            compute_root_from_proof(&datum, &proof, 2, input_data.len()),
+            merkle_tree.root()
+        );
+
+        proof[0] = [9u8; 32].into();
+        let computed_root = compute_root_from_proof(&datum, &proof, 2, input_data.len());
+        assert_ne!(computed_root, merkle_tree.root());
+        assert!(!verify(
+            &merkle_tree.root(),
+            &datum,
+            &proof,
+            2,
+            input_data.len()
+        ));
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
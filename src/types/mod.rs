@@ -1,6 +1,9 @@
 pub mod address;
 pub mod block;
+pub mod bloom;
+pub mod clock;
 pub mod hash;
 pub mod merkle;
 pub mod key_pair;
+pub mod state;
 pub mod transaction;
\ No newline at end of file
@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+
+use super::address::Address;
+use super::transaction::{SignedTransactionBatch, Transaction, TxValidationError};
+
+/// Number of blocks following the block of a coinbase reward before it can be spent, as a reorg
+/// may still drop the block
+pub const COINBASE_MATURITY: u128 = 100;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Account {
+    pub nonce: u64, // number of transactions sent by this account
+    pub balance: u64,
+}
+
+/// Account-based ledger, mapping each address to its account
+#[derive(Debug, Default, Clone)]
+pub struct State {
+    accounts: HashMap<Address, Account>,
+    immature_coinbases: HashMap<Address, Vec<(u128, u64)>>, // creation height, and reward
+    height: u128,                                           // of the block the state is after
+}
+
+impl State {
+    /// Create an empty state, where every account has zero nonce and balance
+    pub fn new() -> Self {
+        State {
+            accounts: HashMap::new(),
+            immature_coinbases: HashMap::new(),
+            height: 0,
+        }
+    }
+
+    pub fn height(&self) -> u128 {
+        self.height
+    }
+
+    /// Move to the height of the next block to apply, forgetting the coinbase rewards which mature
+    pub fn set_height(&mut self, height: u128) {
+        self.height = height;
+        for coinbases in self.immature_coinbases.values_mut() {
+            coinbases.retain(|(created, _)| created + COINBASE_MATURITY > height);
+        }
+        self.immature_coinbases
+            .retain(|_, coinbases| !coinbases.is_empty());
+    }
+
+    /// Sum the balances of all accounts, which may exceed a u64 over many accounts
+    pub fn total_balance(&self) -> u128 {
+        self.accounts
+            .values()
+            .map(|account| account.balance as u128)
+            .sum()
+    }
+
+    /// Count the accounts with a nonzero balance
+    pub fn funded_account_count(&self) -> usize {
+        self.accounts
+            .values()
+            .filter(|account| account.balance > 0)
+            .count()
+    }
+
+    pub fn account(&self, addr: &Address) -> Account {
+        self.accounts.get(addr).cloned().unwrap_or_default()
+    }
+
+    /// Add `value` to an account's balance, e.g. for initial coin offering. The balance saturates
+    /// rather than overflows, transactions are checked against overflowing before.
+    pub fn credit(&mut self, addr: &Address, value: u64) {
+        let account = self.accounts.entry(*addr).or_default();
+        account.balance = account.balance.saturating_add(value);
+    }
+
+    /// Add a coinbase reward at the current height, which is not spendable before maturity
+    pub fn credit_coinbase(&mut self, addr: &Address, value: u64) {
+        self.credit(addr, value);
+        self.immature_coinbases
+            .entry(*addr)
+            .or_default()
+            .push((self.height, value));
+    }
+
+    /// Get the balance of an account minus its coinbase rewards not matured yet
+    pub fn spendable_balance(&self, addr: &Address) -> u64 {
+        let immature: u64 = self
+            .immature_coinbases
+            .get(addr)
+            .map(|coinbases| {
+                coinbases
+                    .iter()
+                    .filter(|(created, _)| created + COINBASE_MATURITY > self.height)
+                    .fold(0u64, |sum, (_, value)| sum.saturating_add(*value))
+            })
+            .unwrap_or(0);
+        self.account(addr).balance.saturating_sub(immature)
+    }
+
+    /// Apply a transaction, return an error and leave the state untouched if its nonce is not the
+    /// sender's current nonce, its value is negative, the sender's balance is not enough for the
+    /// value and the fee, or the receiver's balance would overflow
+    // Assumption: the signature is already verified
+    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), TxValidationError> {
+        let sender = self.account(&tx.sender());
+        if tx.nonce() != sender.nonce {
+            return Err(TxValidationError::BadNonce);
+        }
+        if tx.value() < 0 {
+            return Err(TxValidationError::NegativeValue);
+        }
+        let value = tx.value() as u64;
+        let cost = match value.checked_add(tx.fee()) {
+            Some(cost) if cost <= sender.balance => cost,
+            _ => return Err(TxValidationError::InsufficientBalance),
+        };
+        if cost > self.spendable_balance(&tx.sender()) {
+            return Err(TxValidationError::ImmatureCoinbase);
+        }
+
+        // a payment to oneself is credited after the cost is debited
+        let receiver_balance = if tx.receiver() == tx.sender() {
+            sender.balance - cost
+        } else {
+            self.account(&tx.receiver()).balance
+        };
+        if receiver_balance.checked_add(value).is_none() {
+            return Err(TxValidationError::BalanceOverflow);
+        }
+
+        // the fee leaves the sender, it is not credited to anyone yet
+        let sender_account = self.accounts.entry(tx.sender()).or_default();
+        sender_account.balance -= cost;
+        sender_account.nonce += 1;
+        self.credit(&tx.receiver(), value);
+        Ok(())
+    }
+
+    /// Apply all transactions of a batch in order, or none of them if any fails
+    // Assumption: the batch signature is already verified
+    pub fn apply_batch(&mut self, batch: &SignedTransactionBatch) -> Result<(), TxValidationError> {
+        let mut new_state = self.clone();
+        for tx in batch.transactions() {
+            new_state.apply_transaction(tx)?;
+        }
+        *self = new_state;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::State;
+    use crate::types::address::Address;
+    use crate::types::transaction::{Transaction, TxValidationError};
+
+    #[test]
+    fn accept_correct_nonce() {
+        let alice: Address = [1u8; 20].into();
+        let bob: Address = [2u8; 20].into();
+        let mut state = State::new();
+        state.credit(&alice, 100);
+
+        assert_eq!(
+            state.apply_transaction(&Transaction::new(alice, bob, 30, 0, 0)),
+            Ok(())
+        );
+        assert_eq!(
+            state.apply_transaction(&Transaction::new(alice, bob, 20, 0, 1)),
+            Ok(())
+        );
+        assert_eq!(state.account(&alice).nonce, 2);
+        assert_eq!(state.account(&alice).balance, 50);
+        assert_eq!(state.account(&bob).balance, 50);
+        assert_eq!(state.account(&bob).nonce, 0);
+    }
+
+    #[test]
+    fn reject_stale_nonce() {
+        let alice: Address = [1u8; 20].into();
+        let bob: Address = [2u8; 20].into();
+        let mut state = State::new();
+        state.credit(&alice, 100);
+
+        let tx = Transaction::new(alice, bob, 30, 0, 0);
+        assert_eq!(state.apply_transaction(&tx), Ok(()));
+        // replaying the same transaction
+        assert_eq!(
+            state.apply_transaction(&tx),
+            Err(TxValidationError::BadNonce)
+        );
+        // a nonce from the future
+        assert_eq!(
+            state.apply_transaction(&Transaction::new(alice, bob, 30, 0, 2)),
+            Err(TxValidationError::BadNonce)
+        );
+        assert_eq!(state.account(&alice).nonce, 1);
+        assert_eq!(state.account(&alice).balance, 70);
+    }
+
+    #[test]
+    fn reject_insufficient_balance() {
+        let alice: Address = [1u8; 20].into();
+        let bob: Address = [2u8; 20].into();
+        let mut state = State::new();
+        state.credit(&alice, 10);
+
+        assert_eq!(
+            state.apply_transaction(&Transaction::new(alice, bob, 11, 0, 0)),
+            Err(TxValidationError::InsufficientBalance)
+        );
+        // the fee is paid on top of the value
+        assert_eq!(
+            state.apply_transaction(&Transaction::new(alice, bob, 10, 1, 0)),
+            Err(TxValidationError::InsufficientBalance)
+        );
+        assert_eq!(
+            state.apply_transaction(&Transaction::new(alice, bob, -1, 0, 0)),
+            Err(TxValidationError::NegativeValue)
+        );
+        assert_eq!(state.account(&alice).nonce, 0);
+        assert_eq!(state.account(&bob).balance, 0);
+    }
+
+    #[test]
+    fn apply_batch_all_or_nothing() {
+        use crate::types::address::random_address_with_keypair;
+        use crate::types::transaction::sign_batch;
+
+        let (alice, key) = random_address_with_keypair();
+        let bob: Address = [2u8; 20].into();
+        let mut state = State::new();
+        state.credit(&alice, 100);
+
+        let batch = sign_batch(
+            vec![
+                Transaction::new(alice, bob, 30, 0, 0),
+                Transaction::new(alice, bob, 20, 0, 1),
+            ],
+            &key,
+        );
+        assert_eq!(state.apply_batch(&batch), Ok(()));
+        assert_eq!(state.account(&alice).balance, 50);
+        assert_eq!(state.account(&alice).nonce, 2);
+
+        // the second transaction overdraws, so the first one is not applied either
+        let batch = sign_batch(
+            vec![
+                Transaction::new(alice, bob, 30, 0, 2),
+                Transaction::new(alice, bob, 30, 0, 3),
+            ],
+            &key,
+        );
+        assert_eq!(
+            state.apply_batch(&batch),
+            Err(TxValidationError::InsufficientBalance)
+        );
+        assert_eq!(state.account(&alice).balance, 50);
+        assert_eq!(state.account(&bob).balance, 50);
+    }
+
+    #[test]
+    fn spend_coinbase_after_maturity() {
+        use super::COINBASE_MATURITY;
+
+        let alice: Address = [1u8; 20].into();
+        let bob: Address = [2u8; 20].into();
+        let mut state = State::new();
+        state.set_height(1);
+        state.credit_coinbase(&alice, 50);
+        state.credit(&alice, 5);
+
+        state.set_height(COINBASE_MATURITY);
+        assert_eq!(state.spendable_balance(&alice), 5);
+        assert_eq!(
+            state.apply_transaction(&Transaction::new(alice, bob, 10, 0, 0)),
+            Err(TxValidationError::ImmatureCoinbase)
+        );
+        // the balance not from the coinbase is spendable
+        assert_eq!(
+            state.apply_transaction(&Transaction::new(alice, bob, 5, 0, 0)),
+            Ok(())
+        );
+
+        state.set_height(COINBASE_MATURITY + 1);
+        assert_eq!(state.spendable_balance(&alice), 50);
+        assert_eq!(
+            state.apply_transaction(&Transaction::new(alice, bob, 10, 0, 1)),
+            Ok(())
+        );
+        assert_eq!(state.account(&alice).balance, 40);
+    }
+
+    #[test]
+    fn reject_overflow_and_negative_value() {
+        let alice: Address = [1u8; 20].into();
+        let bob: Address = [2u8; 20].into();
+        let mut state = State::new();
+        state.credit(&alice, 100);
+        state.credit(&bob, u64::MAX - 10);
+
+        assert_eq!(
+            state.apply_transaction(&Transaction::new(alice, bob, 11, 0, 0)),
+            Err(TxValidationError::BalanceOverflow)
+        );
+        assert_eq!(
+            state.apply_transaction(&Transaction::new(alice, bob, i64::MIN, 0, 0)),
+            Err(TxValidationError::NegativeValue)
+        );
+        assert_eq!(state.account(&alice).balance, 100);
+        assert_eq!(state.account(&alice).nonce, 0);
+        assert_eq!(state.account(&bob).balance, u64::MAX - 10);
+
+        assert_eq!(
+            state.apply_transaction(&Transaction::new(alice, bob, 10, 0, 0)),
+            Ok(())
+        );
+        assert_eq!(state.account(&bob).balance, u64::MAX);
+        // the total over accounts goes beyond a u64, and crediting more saturates
+        assert_eq!(state.total_balance(), u64::MAX as u128 + 90);
+        state.credit_coinbase(&bob, 1);
+        assert_eq!(state.account(&bob).balance, u64::MAX);
+    }
+}
@@ -1,19 +1,49 @@
-use ring::{
-    digest,
-    signature::{self, Ed25519KeyPair, Signature},
-};
+use ring::signature::{self, Ed25519KeyPair, KeyPair, Signature};
 use serde::{Deserialize, Serialize};
 
 use super::{
     address::Address,
-    hash::{Hashable, H256},
+    hash::{Hashable, Hasher, H256},
 };
 
+/// A digital signature scheme, signing with a key pair and verifying with the public key only.
+pub trait SignatureScheme {
+    type KeyPair;
+    type Signature: AsRef<[u8]>;
+
+    fn sign(key: &Self::KeyPair, message: &[u8]) -> Self::Signature;
+    fn public_key(key: &Self::KeyPair) -> Vec<u8>;
+    fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool;
+}
+
+/// ED25519, the default signature scheme.
+pub struct Ed25519;
+
+impl SignatureScheme for Ed25519 {
+    type KeyPair = Ed25519KeyPair;
+    type Signature = Signature;
+
+    fn sign(key: &Ed25519KeyPair, message: &[u8]) -> Signature {
+        key.sign(message)
+    }
+
+    fn public_key(key: &Ed25519KeyPair) -> Vec<u8> {
+        key.public_key().as_ref().to_vec()
+    }
+
+    fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        let peer_public_key = signature::UnparsedPublicKey::new(&signature::ED25519, public_key);
+        peer_public_key.verify(message, signature).is_ok()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
     sender: Address,
     receiver: Address,
     value: i64,
+    fee: u64,   // paid by the sender on top of the value
+    nonce: u64, // must equal the sender's account nonce when applied
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -23,29 +53,234 @@ pub struct SignedTransaction {
     public_key: Vec<u8>,
 }
 
+/// Transactions of one sender authorized by a single signature over their concatenated
+/// serialization. The txid of each is still the hash of the transaction alone, as if unbatched.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SignedTransactionBatch {
+    transactions: Vec<Transaction>,
+    signature: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+/// Reasons for rejecting a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxValidationError {
+    BadSignature,
+    InsufficientBalance,
+    BadNonce,
+    WrongSender, // the sender is not the address of the signing key
+    NegativeValue,
+    ImmatureCoinbase, // the balance is enough only with coinbase rewards not spendable yet
+    BalanceOverflow,  // the receiver's balance would exceed u64
+    Oversized,        // too large to be relayed
+    UnknownBlock,     // the block to check it against is not in the blockchain
+}
+
+impl Hashable for Transaction {
+    fn hash_with<Hs: Hasher>(&self) -> H256 {
+        let serialized_tx = bincode::serialize(self).unwrap();
+        Hs::digest(&serialized_tx)
+    }
+}
+
 impl Hashable for SignedTransaction {
-    fn hash(&self) -> H256 {
+    fn hash_with<Hs: Hasher>(&self) -> H256 {
         let serialized_signed_tx = bincode::serialize(self).unwrap();
-        digest::digest(&digest::SHA256, &serialized_signed_tx).into()
+        Hs::digest(&serialized_signed_tx)
+    }
+}
+
+impl Hashable for SignedTransactionBatch {
+    fn hash_with<Hs: Hasher>(&self) -> H256 {
+        let serialized_batch = bincode::serialize(self).unwrap();
+        Hs::digest(&serialized_batch)
+    }
+}
+
+impl Transaction {
+    pub fn new(sender: Address, receiver: Address, value: i64, fee: u64, nonce: u64) -> Self {
+        Transaction {
+            sender,
+            receiver,
+            value,
+            fee,
+            nonce,
+        }
+    }
+
+    pub fn sender(&self) -> Address {
+        self.sender
+    }
+
+    pub fn receiver(&self) -> Address {
+        self.receiver
+    }
+
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+
+    pub fn fee(&self) -> u64 {
+        self.fee
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+}
+
+impl SignedTransaction {
+    pub fn transaction(&self) -> &Transaction {
+        &self.transaction
+    }
+
+    /// Get the transaction ID, which is the hash of the inner transaction only, so that it does not
+    /// depend on the signature bytes
+    pub fn txid(&self) -> H256 {
+        self.transaction.hash()
+    }
+
+    /// Verify the signature of the inner transaction against the carried public key
+    pub fn verify(&self) -> bool {
+        self.verify_with_scheme::<Ed25519>()
+    }
+
+    /// Same as `verify`, for a transaction signed with `sign_transaction_with_scheme::<S>`.
+    pub fn verify_with_scheme<S: SignatureScheme>(&self) -> bool {
+        verify_with_scheme::<S>(&self.transaction, &self.public_key, &self.signature)
+    }
+
+    /// Check that the transaction is signed by its sender, which needs no blockchain state
+    pub fn validate(&self) -> Result<(), TxValidationError> {
+        if !self.verify() {
+            return Err(TxValidationError::BadSignature);
+        }
+        if Address::from_public_key_bytes(&self.public_key) != self.transaction.sender {
+            return Err(TxValidationError::WrongSender);
+        }
+        Ok(())
+    }
+
+    /// Check if this is a coinbase transaction, which has the zero address as sender and no
+    /// signature
+    pub fn is_coinbase(&self) -> bool {
+        self.transaction.sender == Address::default() && self.signature.is_empty()
+    }
+
+    /// Get the number of bytes the transaction takes in a serialized block
+    pub fn size_bytes(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+}
+
+impl SignedTransactionBatch {
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    pub fn txids(&self) -> Vec<H256> {
+        self.transactions.iter().map(|t| t.hash()).collect()
+    }
+
+    /// Get the number of bytes the batch takes in a serialized block
+    pub fn size_bytes(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    /// Verify the signature over all transactions against the carried public key
+    pub fn verify(&self) -> bool {
+        Ed25519::verify(
+            &self.public_key,
+            &serialize_batch(&self.transactions),
+            &self.signature,
+        )
+    }
+
+    /// Check that the batch is signed by the sender of every transaction
+    pub fn validate(&self) -> Result<(), TxValidationError> {
+        if !self.verify() {
+            return Err(TxValidationError::BadSignature);
+        }
+        let signer = Address::from_public_key_bytes(&self.public_key);
+        if self.transactions.iter().any(|t| t.sender != signer) {
+            return Err(TxValidationError::WrongSender);
+        }
+        Ok(())
+    }
+}
+
+// concatenation of the transactions' serializations
+fn serialize_batch(transactions: &[Transaction]) -> Vec<u8> {
+    transactions
+        .iter()
+        .flat_map(|t| bincode::serialize(t).unwrap())
+        .collect()
+}
+
+/// Sign transactions with one signature and package them as a batch
+pub fn sign_batch(transactions: Vec<Transaction>, key: &Ed25519KeyPair) -> SignedTransactionBatch {
+    let signature = Ed25519::sign(key, &serialize_batch(&transactions));
+    SignedTransactionBatch {
+        transactions,
+        signature: signature.as_ref().to_vec(),
+        public_key: Ed25519::public_key(key),
     }
 }
 
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
+    sign_with_scheme::<Ed25519>(t, key)
+}
+
+/// Same as `sign`, with the signature scheme `S`.
+pub fn sign_with_scheme<S: SignatureScheme>(t: &Transaction, key: &S::KeyPair) -> S::Signature {
     // Ignore hashing message according to ECSDA
     let serialized_tx = bincode::serialize(t).unwrap();
-    return key.sign(&serialized_tx);
+    S::sign(key, &serialized_tx)
+}
+
+/// Sign a transaction and package it with the signature and the public key
+pub fn sign_transaction(t: Transaction, key: &Ed25519KeyPair) -> SignedTransaction {
+    sign_transaction_with_scheme::<Ed25519>(t, key)
+}
+
+/// Same as `sign_transaction`, with the signature scheme `S`.
+pub fn sign_transaction_with_scheme<S: SignatureScheme>(
+    t: Transaction,
+    key: &S::KeyPair,
+) -> SignedTransaction {
+    let signature = sign_with_scheme::<S>(&t, key);
+    SignedTransaction {
+        transaction: t,
+        signature: signature.as_ref().to_vec(),
+        public_key: S::public_key(key),
+    }
+}
+
+/// Create the coinbase transaction paying a block reward. The height is taken as nonce, so that
+/// coinbases of different blocks have different txids.
+pub fn coinbase_transaction(receiver: Address, value: u64, height: u128) -> SignedTransaction {
+    SignedTransaction {
+        transaction: Transaction::new(Address::default(), receiver, value as i64, 0, height as u64),
+        signature: vec![],
+        public_key: vec![],
+    }
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
 pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
+    verify_with_scheme::<Ed25519>(t, public_key, signature)
+}
+
+/// Same as `verify`, for a signature of the signature scheme `S`.
+pub fn verify_with_scheme<S: SignatureScheme>(
+    t: &Transaction,
+    public_key: &[u8],
+    signature: &[u8],
+) -> bool {
     // Ignore hashing message according to ECSDA
     let serialized_tx = bincode::serialize(t).unwrap();
-    let peer_public_key = signature::UnparsedPublicKey::new(&signature::ED25519, public_key);
-    return match peer_public_key.verify(&serialized_tx, signature) {
-        Ok(_) => true,
-        _ => false,
-    };
+    S::verify(public_key, &serialized_tx, signature)
 }
 
 #[cfg(any(test, test_utilities))]
@@ -61,16 +296,39 @@ pub fn generate_random_transaction() -> Transaction {
         sender,
         receiver,
         value: rng.gen(),
+        fee: rng.gen(),
+        nonce: rng.gen(),
     }
 }
 
+#[cfg(any(test, test_utilities))]
+/// the sender is the address of the signing key, and the nonce is the first one of an account
+pub fn generate_random_signed_transaction() -> SignedTransaction {
+    use super::address::random_address_with_keypair;
+
+    let (sender, key) = random_address_with_keypair();
+    let mut transaction = generate_random_transaction();
+    transaction.sender = sender;
+    transaction.nonce = 0;
+    sign_transaction(transaction, &key)
+}
+
+#[cfg(any(test, test_utilities))]
+/// Sign the given transaction with a random key, which is not the sender's
+pub fn generate_signed_transaction(transaction: Transaction) -> SignedTransaction {
+    use super::key_pair;
+
+    sign_transaction(transaction, &key_pair::random())
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::address::random_address_with_keypair;
+    use crate::types::hash::Sha256Hasher;
     use crate::types::key_pair;
-    use ring::signature::KeyPair;
 
     #[test]
     fn sign_verify() {
@@ -89,6 +347,129 @@ mod tests {
         assert!(!verify(&t_2, key.public_key().as_ref(), signature.as_ref()));
         assert!(!verify(&t, key_2.public_key().as_ref(), signature.as_ref()));
     }
+    #[test]
+    fn sign_covers_nonce() {
+        let t = generate_random_transaction();
+        let key = key_pair::random();
+        let signature = sign(&t, &key);
+        let mut t_2 = t.clone();
+        t_2.nonce = t.nonce.wrapping_add(1);
+        assert!(verify(&t, key.public_key().as_ref(), signature.as_ref()));
+        assert!(!verify(&t_2, key.public_key().as_ref(), signature.as_ref()));
+
+        let serialized_tx = bincode::serialize(&t).unwrap();
+        let deserialized_tx: Transaction = bincode::deserialize(&serialized_tx).unwrap();
+        assert_eq!(deserialized_tx.nonce(), t.nonce());
+        assert!(verify(
+            &deserialized_tx,
+            key.public_key().as_ref(),
+            signature.as_ref()
+        ));
+    }
+    #[test]
+    fn txid_ignores_signature() {
+        let t = generate_random_transaction();
+        let key = key_pair::random();
+        let key_2 = key_pair::random();
+        let signed_tx = SignedTransaction {
+            transaction: t.clone(),
+            signature: sign(&t, &key).as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+        };
+        let signed_tx_2 = SignedTransaction {
+            transaction: t.clone(),
+            signature: sign(&t, &key_2).as_ref().to_vec(),
+            public_key: key_2.public_key().as_ref().to_vec(),
+        };
+        assert_ne!(signed_tx.signature, signed_tx_2.signature);
+        assert_ne!(signed_tx.hash(), signed_tx_2.hash());
+        assert_eq!(signed_tx.txid(), signed_tx_2.txid());
+        assert_eq!(signed_tx.txid(), t.hash());
+    }
+    #[test]
+    fn sign_transaction_verify() {
+        let key = key_pair::random();
+        let signed_tx = sign_transaction(generate_random_transaction(), &key);
+        assert!(signed_tx.verify());
+        assert_eq!(signed_tx.public_key, key.public_key().as_ref().to_vec());
+
+        let mut tampered_tx = signed_tx.clone();
+        tampered_tx.transaction.value = signed_tx.transaction.value.wrapping_add(1);
+        assert!(!tampered_tx.verify());
+    }
+    #[test]
+    fn sign_verify_batch() {
+        let (sender, key) = random_address_with_keypair();
+        let receiver: Address = [2u8; 20].into();
+        let transactions: Vec<Transaction> = (0..3)
+            .map(|nonce| Transaction::new(sender, receiver, 10, 1, nonce))
+            .collect();
+        let batch = sign_batch(transactions.clone(), &key);
+        assert!(batch.verify());
+        assert_eq!(batch.validate(), Ok(()));
+        // batching does not change the txids
+        let txids: Vec<H256> = transactions.iter().map(|t| t.hash()).collect();
+        assert_eq!(batch.txids(), txids);
+
+        let mut tampered_batch = batch.clone();
+        tampered_batch.transactions[1].value = 11;
+        assert!(!tampered_batch.verify());
+        assert_eq!(
+            tampered_batch.validate(),
+            Err(TxValidationError::BadSignature)
+        );
+    }
+    #[test]
+    fn validate_signed_transaction() {
+        let signed_tx = generate_random_signed_transaction();
+        assert_eq!(signed_tx.validate(), Ok(()));
+
+        let mut tampered_tx = signed_tx.clone();
+        tampered_tx.transaction.nonce = signed_tx.transaction.nonce.wrapping_add(1);
+        assert_eq!(tampered_tx.validate(), Err(TxValidationError::BadSignature));
+
+        // validly signed, but by another key than the sender's
+        let foreign_tx = generate_signed_transaction(signed_tx.transaction.clone());
+        assert!(foreign_tx.verify());
+        assert_eq!(foreign_tx.validate(), Err(TxValidationError::WrongSender));
+    }
+    // insecure scheme where the key pair is the public key, and a signature the hash of the public
+    // key and the message
+    struct ToyScheme;
+
+    impl SignatureScheme for ToyScheme {
+        type KeyPair = Vec<u8>;
+        type Signature = H256;
+
+        fn sign(key: &Vec<u8>, message: &[u8]) -> H256 {
+            Sha256Hasher::digest(&[key.as_slice(), message].concat())
+        }
+
+        fn public_key(key: &Vec<u8>) -> Vec<u8> {
+            key.clone()
+        }
+
+        fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+            Self::sign(&public_key.to_vec(), message).as_ref() == signature
+        }
+    }
+
+    #[test]
+    fn verify_through_signature_scheme() {
+        let t = generate_random_transaction();
+        let key = key_pair::random();
+        let signature = sign_with_scheme::<Ed25519>(&t, &key);
+        assert!(verify_with_scheme::<Ed25519>(
+            &t,
+            key.public_key().as_ref(),
+            signature.as_ref()
+        ));
+        assert!(sign_transaction(t.clone(), &key).verify_with_scheme::<Ed25519>());
+
+        let toy_signed_tx = sign_transaction_with_scheme::<ToyScheme>(t, &vec![1u8; 32]);
+        assert!(toy_signed_tx.verify_with_scheme::<ToyScheme>());
+        assert!(!toy_signed_tx.verify());
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST